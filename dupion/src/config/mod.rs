@@ -0,0 +1,147 @@
+use super::*;
+use std::{collections::BTreeMap, ffi::OsString, path::{Path, PathBuf}};
+use serde_derive::Deserialize;
+use clap::{Arg, ArgAction, Command};
+use util::xdg_dir;
+
+pub const SYSTEM_CONFIG: &str = "/etc/dupion/config.toml";
+
+/// A TOML config file. The keys are the long command line options, e.g. `read-buffer = 4`, or `dirs` for the directories to scan
+#[derive(Deserialize,Default)]
+pub struct ConfigFile {
+    /// `[profile.<name>]` tables, selected with --profile
+    #[serde(default)]
+    pub profile: BTreeMap<String,BTreeMap<String,ConfigValue>>,
+    #[serde(flatten)]
+    pub settings: BTreeMap<String,ConfigValue>,
+}
+
+#[derive(Deserialize,Clone,PartialEq,Debug)]
+#[serde(untagged)]
+pub enum ConfigValue {
+    /// a flag, false leaves it unset
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    /// an option given multiple times
+    Array(Vec<ConfigValue>),
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> AnyhowResult<Self> {
+        let s = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("{}: {}",path.display(),e) )?;
+        toml::from_str(&s)
+            .map_err(|e| anyhow::anyhow!("{}: {}",path.display(),e) )
+    }
+
+    /// Check that the keys of the settings and the profiles are arguments of `command`, and that the values fit them
+    pub fn check(&self, command: &Command) -> AnyhowResult<()> {
+        for (key,value) in std::iter::once(&self.settings).chain(self.profile.values()).flatten() {
+            check_setting(command, key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// $XDG_CONFIG_HOME/dupion/config.toml
+pub fn user_config_path() -> Option<PathBuf> {
+    Some(xdg_dir("XDG_CONFIG_HOME", ".config")?.join("dupion/config.toml"))
+}
+
+/// The system and user config files which exist, then `explicit`, in the order they are applied
+pub fn config_paths(explicit: Option<&Path>) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = [Some(PathBuf::from(SYSTEM_CONFIG)), user_config_path()].into_iter()
+        .flatten()
+        .filter(|p| p.is_file() )
+        .collect();
+    paths.extend(explicit.map(Path::to_owned));
+    paths
+}
+
+/// The merged settings of the config files, checked against the arguments of `command`
+pub fn config_settings(command: &Command, explicit: Option<&Path>, profile: Option<&str>) -> AnyhowResult<BTreeMap<String,ConfigValue>> {
+    let files = config_paths(explicit).iter()
+        .map(|p| {
+            let f = ConfigFile::load(p)?;
+            f.check(command).map_err(|e| anyhow::anyhow!("{}: {}",p.display(),e) )?;
+            Ok(f)
+        })
+        .collect::<AnyhowResult<Vec<_>>>()?;
+
+    merge_settings(&files, profile)
+}
+
+/// The top level settings of the files in order, then the ones of the profile. A later value replaces an earlier one, e.g. `false` turns off a flag of a previous file
+pub fn merge_settings(files: &[ConfigFile], profile: Option<&str>) -> AnyhowResult<BTreeMap<String,ConfigValue>> {
+    let mut merged = BTreeMap::new();
+
+    for f in files {
+        merged.extend(f.settings.clone());
+    }
+
+    if let Some(profile) = profile {
+        let mut found = false;
+        for f in files {
+            if let Some(settings) = f.profile.get(profile) {
+                merged.extend(settings.clone());
+                found = true;
+            }
+        }
+        ensure!(found, "Profile {} not found in the config files", profile);
+    }
+
+    Ok(merged)
+}
+
+/// The settings as command line arguments, to be parsed in front of the actual ones, which override them
+pub fn settings_args(command: &Command, settings: &BTreeMap<String,ConfigValue>) -> Vec<OsString> {
+    let mut args = Vec::new();
+    for (key,value) in settings {
+        let positional = find_arg(command, key).is_some_and(Arg::is_positional);
+        push_arg(key, positional, value, &mut args);
+    }
+    args
+}
+
+/// The argument set by a key: the long option, or the id of a positional argument, e.g. `dirs`
+fn find_arg<'a>(command: &'a Command, key: &str) -> Option<&'a Arg> {
+    command.get_arguments().find(|a| a.get_long() == Some(key) || (a.is_positional() && a.get_id() == key) )
+}
+
+fn check_setting(command: &Command, key: &str, value: &ConfigValue) -> AnyhowResult<()> {
+    ensure!(!matches!(key, "config" | "profile" | "print-config"), "{} can't be set in a config file", key);
+
+    let arg = find_arg(command, key).ok_or_else(|| anyhow::anyhow!("unknown option {}",key) )?;
+    let flag = matches!(arg.get_action(), ArgAction::SetTrue);
+
+    match value {
+        ConfigValue::Bool(_) => ensure!(flag, "{} needs a value", key),
+        ConfigValue::Array(values) => {
+            ensure!(matches!(arg.get_action(), ArgAction::Append), "{} can't be given multiple times", key);
+            ensure!(values.iter().all(|v| !matches!(v, ConfigValue::Array(_) | ConfigValue::Bool(_)) ), "{} must be an array of values", key);
+        },
+        _ => ensure!(!flag, "{} is a flag, set it to true or false", key),
+    }
+    Ok(())
+}
+
+fn push_arg(key: &str, positional: bool, value: &ConfigValue, dest: &mut Vec<OsString>) {
+    let arg = |v: String| -> OsString {
+        if positional {v.into()} else {format!("--{}={}",key,v).into()}
+    };
+
+    match value {
+        ConfigValue::Bool(true) => dest.push(format!("--{}",key).into()),
+        ConfigValue::Bool(false) => {},
+        ConfigValue::Int(v) => dest.push(arg(v.to_string())),
+        ConfigValue::Float(v) => dest.push(arg(v.to_string())),
+        ConfigValue::String(v) => dest.push(arg(v.clone())),
+        ConfigValue::Array(values) => {
+            for v in values {
+                push_arg(key, positional, v, dest);
+            }
+        },
+    }
+}
@@ -1,15 +1,30 @@
 use super::*;
 use std::{collections::VecDeque, fs::{Metadata, File}, sync::atomic::Ordering};
-use util::{DISP_PROCESSED_BYTES, DISP_PROCESSED_FILES};
-use ::btrfs::{DedupeRange, DedupeRangeDestInfo, DedupeRangeStatus, deduplicate_range};
-use std::os::unix::io::FromRawFd;
-use size_format::SizeFormatterBinary;
+use ::btrfs::{DedupeRange, DedupeRangeDestInfo, DedupeRangeStatus, deduplicate_range, clone_range, defragment_range, get_file_extent_map_noloop, CompressionType};
+use std::os::unix::{fs::MetadataExt, io::FromRawFd};
+use size::SizeDisp;
 use fd::FileDescriptor;
+use self::fs::{Filesystems, DedupMechanism, fs_key};
+use journal::{Journal, JournalAction, JournalEntry};
+use errors::ScanErrorKind;
 
-pub struct BtrfsDedup;
+pub struct BtrfsDedup {
+    pub fs: Filesystems,
+    pub journal: Option<Journal>,
+}
+
+impl BtrfsDedup {
+    /// `mechanism`: None to probe every filesystem for the supported mechanism
+    pub fn new(mechanism: Option<DedupMechanism>, journal: Option<Journal>) -> Self {
+        Self {
+            fs: Filesystems::new(mechanism),
+            journal,
+        }
+    }
+}
 
 impl Deduper for BtrfsDedup {
-    fn dedup_groups(&mut self, groups: Vec<DedupGroup>, state: &'static RwLock<State>, opts: &'static Opts) -> AnyhowResult<()> {
+    fn dedup_groups(&mut self, groups: Vec<DedupGroup>, state: &RwLock<State>, opts: &Opts) -> AnyhowResult<()> {
         // The dedups are split in batches to fit the os cache for readahead
         // the available file cache is estimated by the unused os memory
         // all files in batch will be opened 
@@ -26,90 +41,36 @@ impl Deduper for BtrfsDedup {
 
         let mut s = state.write();
 
+        self.fs.probe_roots(opts);
+
         let mut submit_buf = Vec::<(DedupGroup,bool)>::new();
 
         let mut groups = VecDeque::from(groups);
 
         while !groups.is_empty() || !submit_buf.is_empty() {
-            // This inner "loop" is kinda like a checklist, if we have to change something on the bufs, we "start over" with continue; or break if we're good
-            while !groups.is_empty() || !submit_buf.is_empty() {
-                // Current submit usage
-                let mut submit_sum = 0;
-                let mut submit_usage = 0;
-                
-                for (f,_) in &submit_buf {
-                    submit_usage += f.usage();
-                    submit_sum += f.sum();
-                }
-
-                // Populate from input
-                if let Some(g) = groups.front() {
-                    // No empty groups
-                    if g.dups.is_empty() {
-                        groups.pop_front();
-                        continue;
-                    }
-                    // We always need one on the submit buf
-                    if submit_buf.is_empty() {
-                        submit_buf.push((groups.pop_front().unwrap(),true));
-                        continue;
-                    }
-                    // Fit more in if we can
-                    if submit_sum + g.sum() <= max_dups_per_group && submit_usage + g.usage() <= cache_max {
-                        submit_buf.push((groups.pop_front().unwrap(),true));
-                        continue;
-                    }
-                }
-
-                // If all fits, we can submit now
-                if submit_sum <= max_dups_per_group && submit_usage <= cache_max {
-                    break;
-                }
+            let too_big = fill_batch(&mut groups, &mut submit_buf, cache_max, max_dups_per_group);
 
-                if submit_buf.is_empty() {
-                    break;
-                }
-                assert!(submit_buf.len() == 1);
-
-                // If too many files, split end and put back
-                let max_group_files = (cache_max / submit_buf[0].0.range_len()).min(max_dups_per_group).max(2);
-                if max_group_files < submit_sum {
-                    let end = submit_buf[0].0.split_off_end_at_candidate_n(max_group_files as usize - 1);
-                    groups.push_front(end);
-                    continue;
-                }
-
-                assert!(max_group_files == 2 && submit_buf[0].0.sum() == 2);
-
-                // Now we have to range-split that single dedup. This will only be done on a single group with only two files
-                if allow_range_split {
-                    let mut g = submit_buf.remove(0).0;
+            // Now we have to range-split that single dedup
+            if too_big && allow_range_split {
+                let mut g = submit_buf.remove(0).0;
 
-                    let max_range_size = cache_max / g.sum(); // group.sum == 2 at this point
-                    let max_range_size = max_range_size/file_split_round*file_split_round;
-                    let max_range_size = max_range_size.min(g.range_len());
-                    assert!(max_range_size*g.sum() <= cache_max);
-                    assert!(max_range_size*g.sum() < g.usage());
-                    let max_range_size = max_range_size.max(file_split_round);
+                let max_range_size = cache_max / (g.sum() + g.defrag as u64);
+                let max_range_size = max_range_size/file_split_round*file_split_round;
+                let max_range_size = max_range_size.min(g.range_len());
+                let max_range_size = max_range_size.max(file_split_round);
 
-                    while !g.range.is_empty() {
-                        let first_half = g.split_off_start_range(max_range_size);
+                while !g.range.is_empty() {
+                    let first_half = g.split_off_start_range(max_range_size);
 
-                        let first_half_usage = first_half.usage();
-                        let is_last_part = g.range.is_empty();
+                    let first_half_usage = first_half.usage();
+                    let is_last_part = g.range.is_empty();
 
-                        dedup_group_batch(&[(first_half,is_last_part)], &mut s, opts, first_half_usage)?;
-                    }
-
-                    break;
+                    dedup_group_batch(&[(first_half,is_last_part)], &mut s, &mut self.fs, &mut self.journal, opts, first_half_usage)?;
                 }
-
-                // We couldn't do anything, so let's just commit the single thing
-                break;
             }
 
             if !submit_buf.is_empty() {
-                dedup_group_batch(&*submit_buf, &mut s, opts, submit_buf.iter().map(|v| v.0.usage()).sum())?;
+                dedup_group_batch(&*submit_buf, &mut s, &mut self.fs, &mut self.journal, opts, submit_buf.iter().map(|v| v.0.usage()).sum())?;
                 submit_buf.clear();
             }
 
@@ -120,17 +81,79 @@ impl Deduper for BtrfsDedup {
     }
 }
 
-pub fn dedup_group_batch(current: &[(DedupGroup,bool)], state: &mut State, opts: &'static Opts, batch_size: u64) -> AnyhowResult<()> {
+/// Move the next batch from `groups` into the empty `submit_buf`, fitting `max_dups_per_group` and `cache_max`
+///
+/// A single group which is too big gets its dups split off to the front of `groups`.
+/// Returns true if the batch still exceeds `cache_max`, which happens if a group can't be split further
+fn fill_batch(groups: &mut VecDeque<DedupGroup>, submit_buf: &mut Vec<(DedupGroup,bool)>, cache_max: u64, max_dups_per_group: u64) -> bool {
+    // This "loop" is kinda like a checklist, if we have to change something on the bufs, we "start over" with continue; or break if we're good
+    while !groups.is_empty() || !submit_buf.is_empty() {
+        // Current submit usage
+        let mut submit_sum = 0;
+        let mut submit_usage = 0;
+        
+        for (f,_) in &*submit_buf {
+            submit_usage += f.usage();
+            submit_sum += f.sum();
+        }
+
+        // Populate from input
+        if let Some(g) = groups.front() {
+            // No empty groups
+            if g.dups.is_empty() {
+                groups.pop_front();
+                continue;
+            }
+            // We always need one on the submit buf
+            if submit_buf.is_empty() {
+                submit_buf.push((groups.pop_front().unwrap(),true));
+                continue;
+            }
+            // Fit more in if we can
+            if submit_sum + g.sum() <= max_dups_per_group && submit_usage + g.usage() <= cache_max {
+                submit_buf.push((groups.pop_front().unwrap(),true));
+                continue;
+            }
+        }
+
+        // If all fits, we can submit now
+        if submit_sum <= max_dups_per_group && submit_usage <= cache_max {
+            return false;
+        }
+
+        if submit_buf.len() != 1 {
+            break;
+        }
+
+        // If too many files, split end and put back. The defrag of the senpai takes one file more of the cache
+        let g = &mut submit_buf[0].0;
+        let max_group_files = (cache_max / g.range_len()).saturating_sub(g.defrag as u64).min(max_dups_per_group).max(2);
+        if max_group_files < submit_sum {
+            let end = g.split_off_end_at_candidate_n(max_group_files as usize - 1);
+            groups.push_front(end);
+            continue;
+        }
+
+        // We couldn't do anything, it's up to the caller to range-split or commit the single thing
+        return true;
+    }
+    false
+}
+
+pub fn dedup_group_batch(current: &[(DedupGroup,bool)], state: &mut State, fs: &mut Filesystems, journal: &mut Option<Journal>, opts: &Opts, batch_size: u64) -> AnyhowResult<()> {
+    let progress = state.progress.clone();
+    progress.check_interrupted()?;
+
     let real = !opts.dedup_simulate;
 
     if opts.verbose {
         dprintln!(
-            "Batch {} groups with {} dups, {}B",
+            "Batch {} groups with {} dups, {}",
             current.len(),
             current.iter()
                 .map(|(g,_)| g.dups.len() )
                 .sum::<usize>(),
-            SizeFormatterBinary::new(batch_size)
+            SizeDisp(batch_size)
         );
     }
 
@@ -142,35 +165,36 @@ pub fn dedup_group_batch(current: &[(DedupGroup,bool)], state: &mut State, opts:
 
             if opts.verbose {
                 dprintln!(
-                    "\tGroup {}B..{}B -> {} ({})",
-                    SizeFormatterBinary::new(group.range.start),
-                    SizeFormatterBinary::new(group.range.end),
+                    "\tGroup {}..{} -> {} ({})",
+                    SizeDisp(group.range.start),
+                    SizeDisp(group.range.end),
                     opts.path_disp(&state.tree[group.senpai].path),
                     group.dups.len()+1,
                 );
             }
 
-            DISP_PROCESSED_BYTES.fetch_add(group.dups.len() as u64 * (group.range.end - group.range.start),Ordering::Relaxed);
+            progress.processed_bytes.fetch_add((group.dups.len() as u64 + group.defrag as u64) * (group.range.end - group.range.start),Ordering::Relaxed);
             if *last_part {
-                DISP_PROCESSED_FILES.fetch_add(group.dups.len() as u64,Ordering::Relaxed);
+                progress.processed_files.fetch_add(group.dups.len() as u64,Ordering::Relaxed);
             }
         }
         return Ok(());
     }
             
-    let mut opened: Vec<(DedupGroup,FileDescriptor,Vec<FileDescriptor>,bool)> = Vec::with_capacity(current.len());
+    let mut opened: Vec<(DedupGroup,FileDescriptor,Vec<FileDescriptor>,bool,DedupMechanism)> = Vec::with_capacity(current.len());
 
     let mut batch_file_sum = 0;
 
-    let open_dup = |group: &DedupGroup,id: VfsId| {
+    // strict: also check ctime, as the data won't be compared by the kernel
+    let open_dup = |group: &DedupGroup,id: VfsId,flags: libc::c_int,strict: bool| {
         let path = &state.tree[id].path;
         let fd = match FileDescriptor::open(
             path,
-            libc::O_RDONLY,
+            flags,
         ) {
             Ok(v) => v,
             Err(e) => {
-                dprintln!("\tError opening for dedup: {} ({})",e,opts.path_disp(path));
+                state.errors.push(opts,ScanErrorKind::Open,&**path,e);
                 return Err(());
             }
         };
@@ -178,15 +202,15 @@ pub fn dedup_group_batch(current: &[(DedupGroup,bool)], state: &mut State, opts:
         let meta = match fd_metadata(fd.get_value()) {
             Ok(m) => m,
             Err(e) => {
-                dprintln!("\tError reading metadata for dedup: {} ({})",e,opts.path_disp(path));
+                state.errors.push(opts,ScanErrorKind::Metadata,&**path,e);
                 return Err(());
             }
         };
 
-        if meta.len() == group.actual_file_size {
+        if meta.len() == group.actual_file_size && (!strict || state.tree[id].ctime == Some(meta.ctime())) {
             Ok(fd)
         }else{
-            dprintln!("\tComodified file, skip group: {}",opts.path_disp(path));
+            state.errors.push(opts,ScanErrorKind::Comodified,&**path,"skipped group");
             Err(())
         }
     };
@@ -199,26 +223,53 @@ pub fn dedup_group_batch(current: &[(DedupGroup,bool)], state: &mut State, opts:
 
         if opts.verbose {
             dprintln!(
-                "\tGroup {}B..{}B -> {} ({})",
-                SizeFormatterBinary::new(group.range.start),
-                SizeFormatterBinary::new(group.range.end),
+                "\tGroup {}..{} -> {} ({})",
+                SizeDisp(group.range.start),
+                SizeDisp(group.range.end),
                 opts.path_disp(&state.tree[group.senpai].path),
                 group.dups.len()+1,
             );
         }
 
-        let senpai_fd = match open_dup(&group,group.senpai) {
+        let senpai_fd = match open_dup(group,group.senpai,libc::O_RDONLY,false) {
             Ok(v) => v,
             Err(_) => continue 'g,
         };
 
+        let senpai_path = &state.tree[group.senpai].path;
+
+        let (senpai_fs,mechanism,is_btrfs) = match fs.info(&senpai_fd, senpai_path.parent().unwrap_or(senpai_path)) {
+            Ok((key,info)) => (key,info.mechanism,info.is_btrfs()),
+            Err(e) => {
+                state.errors.push(opts,ScanErrorKind::Dedup,&**senpai_path,format!("probing the filesystem failed, skipped group: {}",e));
+                continue 'g;
+            }
+        };
+
+        if mechanism == DedupMechanism::Unsupported {
+            dprintln!("\tDedup unsupported on filesystem, skip group: {}",opts.path_disp(senpai_path));
+            continue 'g;
+        }
+
+        let clone = mechanism == DedupMechanism::Clone;
+
+        // nothing compares the files before cloning
+        if clone && !opts.verify {
+            dprintln!("\tClone without --verify, skip group: {}",opts.path_disp(senpai_path));
+            continue 'g;
+        }
+
         let mut group = group.clone();
 
+        // defrag is a btrfs ioctl
+        group.defrag &= is_btrfs;
+
         let mut dups_fd = Vec::with_capacity(group.dups.len());
 
         let mut i = 0;
         while i < group.dups.len() {
-            if let Ok(fd) = open_dup(&group,group.dups[i]) {
+            // ioctl_ficlonerange requires the dest to be writable
+            if let Ok(fd) = open_dup(&group,group.dups[i],if clone {libc::O_RDWR} else {libc::O_RDONLY},clone) {
                 dups_fd.push(fd);
                 i += 1;
             } else {
@@ -228,15 +279,29 @@ pub fn dedup_group_batch(current: &[(DedupGroup,bool)], state: &mut State, opts:
 
         assert_eq!(dups_fd.len(),group.dups.len());
 
+        for (fd,&id) in dups_fd.iter().zip(group.dups.iter()) {
+            match fs_key(fd) {
+                Ok((key,_)) if key == senpai_fs => {},
+                _ => {
+                    dprintln!(
+                        "\tGroup spans multiple filesystems, skip group: {} <-> {}",
+                        opts.path_disp(senpai_path),
+                        opts.path_disp(&state.tree[id].path),
+                    );
+                    continue 'g;
+                }
+            }
+        }
+
         batch_file_sum += group.dups.len()+1;
 
-        opened.push((group,senpai_fd,dups_fd,*last_part));
+        opened.push((group,senpai_fd,dups_fd,*last_part,mechanism));
     }
 
     let mut readahead: Vec<(u64,&FileDescriptor,&Range<u64>)> = Vec::with_capacity(batch_file_sum);
 
     // for readahead, retrieve and sort by the physical pos, then fadvise
-    for (group,senpai_fd,dups_fd,_) in &opened {
+    for (group,senpai_fd,dups_fd,..) in &opened {
         readahead.push((
             state.tree[group.senpai].phys.unwrap(),
             senpai_fd,
@@ -278,21 +343,30 @@ pub fn dedup_group_batch(current: &[(DedupGroup,bool)], state: &mut State, opts:
     }
 
     // issue dedup_range ioctl for the dup ranges
-    for (group,senpai_fd,dups_fd,last_part) in opened {
+    for (group,senpai_fd,dups_fd,last_part,mechanism) in opened {
+        // the journal is still flushed below
+        if progress.is_interrupted() {break;}
+
         assert_eq!(dups_fd.len(),group.dups.len());
+
+        if group.defrag {
+            defrag_senpai(&group, &senpai_fd, state, opts);
+        }
+
         let senpai_path = &state.tree[group.senpai].path;
 
         if opts.verbose {
             dprintln!(
-                "\tDedup {}B..{}B -> {} ({})",
-                SizeFormatterBinary::new(group.range.start),
-                SizeFormatterBinary::new(group.range.end),
+                "\tDedup {}..{} -> {} ({})",
+                SizeDisp(group.range.start),
+                SizeDisp(group.range.end),
                 opts.path_disp(senpai_path),
                 dups_fd.len()+1,
             );
         }
 
-        fn dedup(group_range: &Range<u64>, senpai_fd: &FileDescriptor, dups_fd: &[FileDescriptor], real: bool) -> (Result<(),String>,DedupeRange) {
+        /// the errors of the single dups go to `dup_errors`
+        fn dedup(group_range: &Range<u64>, senpai_fd: &FileDescriptor, dups_fd: &[FileDescriptor], real: bool, mechanism: DedupMechanism, dup_errors: &mut [Option<String>]) -> (Result<(),String>,DedupeRange) {
             let dest_infos: Vec<_> = dups_fd.iter()
             .map(|fd| DedupeRangeDestInfo {
                 dest_fd: fd.get_value() as i64,
@@ -308,64 +382,211 @@ pub fn dedup_group_batch(current: &[(DedupGroup,bool)], state: &mut State, opts:
                 dest_infos,
             };
 
-            if real {
-                let result = deduplicate_range(
-                    senpai_fd.get_value(),
-                    &mut dedup_range,
-                );
-    
-                (result,dedup_range)
-            } else {
-                (Ok(()),dedup_range)
+            if !real {
+                return (Ok(()),dedup_range);
             }
-        }
 
-        let (result,dedup_range) = dedup(&group.range,&senpai_fd,&dups_fd,real);
-
-        let mut deduped = 0;
+            match mechanism {
+                DedupMechanism::Dedupe => {
+                    let result = deduplicate_range(
+                        senpai_fd.get_value(),
+                        &mut dedup_range,
+                    );
+
+                    (result,dedup_range)
+                },
+                DedupMechanism::Clone => {
+                    // cloned one by one, so a failure only affects the single dup
+                    for (info,error) in dedup_range.dest_infos.iter_mut().zip(dup_errors) {
+                        match clone_range(senpai_fd.get_value(), dedup_range.src_offset, dedup_range.src_length, info.dest_fd as i32, info.dest_offset) {
+                            Ok(()) => info.bytes_deduped = dedup_range.src_length,
+                            Err(e) => {
+                                *error = Some(format!("cloning failed: {}",e));
+                                info.status = DedupeRangeStatus::Differs;
+                            }
+                        }
+                    }
 
-        if let Err(e) = result {
-            dprintln!("\tError deduplicating: {}",e);
+                    (Ok(()),dedup_range)
+                },
+                DedupMechanism::Unsupported => unreachable!(),
+            }
+        }
 
-            for (f,&id) in dups_fd.iter().zip(group.dups.iter()) {
-                let (result,dedup_range) = dedup(&group.range,&senpai_fd,std::slice::from_ref(f),real);
+        // the errors of the dups which failed on their own
+        let mut dup_errors = vec![None;group.dups.len()];
 
-                deduped += dedup_range.dest_infos[0].bytes_deduped;
+        let (result,dedup_range) = dedup(&group.range,&senpai_fd,&dups_fd,real,mechanism,&mut dup_errors);
 
-                if dedup_range.dest_infos[0].status == DedupeRangeStatus::Differs {
-                    let path = &state.tree[id].path;
-                    dprintln!("\t\tNot deduped {}",opts.path_disp(path));
-                } else {
-                    state.tree[id].dedup_state = Some(true);
+        let dest_infos = match result {
+            Ok(()) => dedup_range.dest_infos,
+            Err(e) => {
+                if opts.verbose {
+                    dprintln!("\tError deduplicating, retry one by one: {}",e);
                 }
+
+                // retry one by one, so that only the failing dups are skipped
+                dups_fd.iter().zip(dup_errors.iter_mut())
+                    .map(|(f,error)| {
+                        let (result,mut dedup_range) = dedup(&group.range,&senpai_fd,std::slice::from_ref(f),real,mechanism,std::slice::from_mut(error));
+                        let mut info = dedup_range.dest_infos.remove(0);
+                        if let Err(e) = result {
+                            *error = Some(e);
+                            info.status = DedupeRangeStatus::Differs;
+                        }
+                        info
+                    })
+                    .collect()
             }
-        }
+        };
 
-        DISP_PROCESSED_BYTES.fetch_add(group.dups.len() as u64 * (group.range.end - group.range.start),Ordering::Relaxed);
+        progress.processed_bytes.fetch_add(group.dups.len() as u64 * (group.range.end - group.range.start),Ordering::Relaxed);
         if last_part {
-            DISP_PROCESSED_FILES.fetch_add(group.dups.len() as u64,Ordering::Relaxed);
+            progress.processed_files.fetch_add(group.dups.len() as u64,Ordering::Relaxed);
         }
 
-        for (i,&id) in dedup_range.dest_infos.iter().zip(group.dups.iter()) {
+        let mut deduped = 0;
+
+        for ((i,&id),error) in dest_infos.iter().zip(group.dups.iter()).zip(dup_errors) {
             deduped += i.bytes_deduped;
             
             if i.status == DedupeRangeStatus::Differs {
-                let path = &state.tree[id].path;
-                dprintln!("\t\tNot deduped {}",opts.path_disp(path));
+                let error = error.unwrap_or_else(|| "not deduped, the data differs".to_owned() );
+                state.errors.push(opts,ScanErrorKind::Dedup,&*state.tree[id].path,error);
             } else {
+                if let Some(journal) = journal.as_mut() {
+                    let action = match mechanism {
+                        DedupMechanism::Clone => JournalAction::Clone,
+                        _ => JournalAction::Dedup,
+                    };
+                    let mut entry = JournalEntry::new(action, id, state);
+                    entry.source = Some(state.tree[group.senpai].path.to_path_buf());
+                    entry.range_start = group.range.start;
+                    entry.range_end = group.range.end;
+                    entry.bytes = i.bytes_deduped;
+                    journal.append(&entry).unwrap_or_else(|e| dprintln!("\tError writing journal: {}",e) );
+                }
+
                 state.tree[id].dedup_state = Some(true);
             }
         }
 
-        DISP_DEDUPED_BYTES.fetch_add(deduped,Ordering::Relaxed);
+        progress.deduped_bytes.fetch_add(deduped,Ordering::Relaxed);
+    }
+
+    if let Some(journal) = journal.as_mut() {
+        journal.flush().unwrap_or_else(|e| dprintln!("\tError writing journal: {}",e) );
+    }
+
+    progress.check_interrupted()
+}
+
+/// rewrite the senpai range contiguously, so that the dups will share the defragmented extents
+fn defrag_senpai(group: &DedupGroup, senpai_fd: &FileDescriptor, state: &mut State, opts: &Opts) {
+    let path = &state.tree[group.senpai].path;
+
+    if opts.verbose {
+        dprintln!(
+            "\tDefrag {}..{} {} ({} extents)",
+            SizeDisp(group.range.start),
+            SizeDisp(group.range.end),
+            opts.path_disp(path),
+            state.tree[group.senpai].n_extends.unwrap_or(0),
+        );
+    }
+
+    if let Err(e) = defragment_range(
+        senpai_fd.get_value(),
+        group.range.start,
+        group.range_len(),
+        DEFRAG_EXTENT_THRESH,
+        CompressionType::None,
+        true,
+    ) {
+        state.errors.push(opts,ScanErrorKind::Dedup,&**path,format!("defragmenting failed: {}",e));
     }
 
-    Ok(())
+    state.progress.processed_bytes.fetch_add(group.range_len(),Ordering::Relaxed);
+
+    // the extents moved, so the senpai prioritization data of the entry is outdated
+    match get_file_extent_map_noloop(senpai_fd.get_value()) {
+        Ok(extents) => {
+            let e = &mut state.tree[group.senpai];
+            e.phys = Some(extents.first().map_or(0, |e| e.physical ));
+            e.n_extends = Some(extents.len());
+        },
+        Err(e) => {
+            state.errors.push(opts,ScanErrorKind::Metadata,&**path,format!("reading the extents after the defrag failed: {}",e));
+        }
+    }
 }
 
+/// Extents smaller than this will be rewritten by the defrag (btrfs max extent size)
+const DEFRAG_EXTENT_THRESH: u32 = 128*1024*1024;
+
 pub fn fd_metadata(fd: i32) -> std::io::Result<Metadata> {
     let file = unsafe{File::from_raw_fd(fd)};
     let meta = file.metadata();
     std::mem::forget(file);
     meta
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const R: u64 = 1024*1024;
+
+    fn group(files: usize, defrag: bool) -> DedupGroup {
+        DedupGroup {
+            senpai: VfsId::ROOT,
+            dups: (1..files).map(|evil_inner| VfsId{evil_inner} ).collect(),
+            range: 0..R,
+            avg_phys: 0,
+            actual_file_size: R,
+            defrag,
+        }
+    }
+
+    #[test]
+    fn defrag_group_near_budget_is_split() {
+        for cache_max in [3*R, 4*R-1] {
+            let mut groups = VecDeque::from(vec![group(3,true)]);
+            let mut submit_buf = Vec::new();
+
+            assert!(!fill_batch(&mut groups, &mut submit_buf, cache_max, 127));
+            assert_eq!(submit_buf.len(), 1);
+            assert_eq!(submit_buf[0].0.sum(), 2);
+            assert!(submit_buf[0].0.defrag);
+            assert!(submit_buf[0].0.usage() <= cache_max);
+
+            submit_buf.clear();
+            assert!(!fill_batch(&mut groups, &mut submit_buf, cache_max, 127));
+            assert_eq!(submit_buf.len(), 1);
+            assert_eq!(submit_buf[0].0.sum(), 2);
+            assert!(!submit_buf[0].0.defrag);
+            assert!(groups.is_empty());
+        }
+    }
+
+    #[test]
+    fn unsplittable_group_is_reported() {
+        let mut groups = VecDeque::from(vec![group(2,true)]);
+        let mut submit_buf = Vec::new();
+
+        assert!(fill_batch(&mut groups, &mut submit_buf, 2*R, 127));
+        assert_eq!(submit_buf.len(), 1);
+        assert_eq!(submit_buf[0].0.sum(), 2);
+        assert!(groups.is_empty());
+    }
+
+    #[test]
+    fn groups_are_batched_within_budget() {
+        let mut groups = VecDeque::from(vec![group(2,false), group(3,true), group(2,false)]);
+        let mut submit_buf = Vec::new();
+
+        assert!(!fill_batch(&mut groups, &mut submit_buf, 6*R, 127));
+        assert_eq!(submit_buf.iter().map(|(g,_)| g.usage() ).sum::<u64>(), 6*R);
+        assert_eq!(groups.len(), 1);
+    }
+}
@@ -0,0 +1,193 @@
+use super::*;
+use std::{fs::{OpenOptions, remove_file}, io::Write, os::unix::{fs::MetadataExt, io::AsRawFd}, path::Path};
+use ::btrfs::{DedupeRange, DedupeRangeDestInfo, DedupeRangeStatus, deduplicate_range, clone_range, get_filesystem_info};
+use rustc_hash::FxHashMap;
+use fd::FileDescriptor;
+
+/// The mechanism used to share the extents of identical files
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DedupMechanism {
+    /// ioctl_file_dedupe_range, the kernel compares the ranges before sharing them (btrfs, XFS with reflink, ...)
+    Dedupe,
+    /// ioctl_ficlonerange, the ranges are shared without comparision (e.g. NFS 4.2, which has no dedupe).
+    ///
+    /// Never chosen by the probe, as a wrong (cached) hash would overwrite a different file. Only with --dedup clone, which verifies the files first
+    Clone,
+    Unsupported,
+}
+
+impl DedupMechanism {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Dedupe => "ioctl_file_dedupe_range",
+            Self::Clone => "ioctl_ficlonerange",
+            Self::Unsupported => "unsupported",
+        }
+    }
+}
+
+/// Identifies a filesystem. btrfs subvolumes have their own st_dev, but dedup between them is possible
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum FsKey {
+    Btrfs(u128),
+    Dev(u64),
+}
+
+pub struct FsInfo {
+    pub fs_type: &'static str,
+    pub mechanism: DedupMechanism,
+}
+
+impl FsInfo {
+    pub fn is_btrfs(&self) -> bool {
+        self.fs_type == fs_type_name(BTRFS_SUPER_MAGIC)
+    }
+}
+
+pub struct Filesystems {
+    /// use this mechanism regardless of the probe result
+    forced: Option<DedupMechanism>,
+    known: FxHashMap<FsKey,FsInfo>,
+}
+
+impl Filesystems {
+    pub fn new(forced: Option<DedupMechanism>) -> Self {
+        Self {
+            forced,
+            known: FxHashMap::default(),
+        }
+    }
+
+    /// probe the filesystems of the roots upfront, so that the report is printed before the dedup
+    pub fn probe_roots(&mut self, opts: &Opts) {
+        for root in &opts.paths {
+            let fd = match FileDescriptor::open(root, libc::O_RDONLY) {
+                Ok(v) => v,
+                Err(e) => {
+                    dprintln!("\tError opening root for filesystem probe: {} ({})",e,opts.path_disp(root));
+                    continue;
+                }
+            };
+            let dir = if root.is_dir() {&**root} else {root.parent().unwrap_or(root)};
+
+            if let Err(e) = self.info(&fd, dir) {
+                dprintln!("\tError probing filesystem: {} ({})",e,opts.path_disp(root));
+            }
+        }
+    }
+
+    /// `dir` should be a directory on the filesystem of `fd` in which the probe files can be created
+    pub fn info(&mut self, fd: &FileDescriptor, dir: &Path) -> std::io::Result<(FsKey,&FsInfo)> {
+        let (key,magic) = fs_key(fd)?;
+
+        let forced = self.forced;
+
+        let info = self.known.entry(key).or_insert_with(|| {
+            let info = FsInfo {
+                fs_type: fs_type_name(magic),
+                mechanism: forced.unwrap_or_else(|| probe_mechanism(dir, magic) ),
+            };
+            dprintln!(
+                "Filesystem of {}: {}, dedup: {}",
+                dir.to_string_lossy(),
+                info.fs_type,
+                info.mechanism.name(),
+            );
+            info
+        });
+
+        Ok((key,info))
+    }
+}
+
+pub fn fs_key(fd: &FileDescriptor) -> std::io::Result<(FsKey,i64)> {
+    let mut statfs: libc::statfs = unsafe{std::mem::zeroed()};
+
+    if unsafe{libc::fstatfs(fd.get_value(), &mut statfs)} != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let magic = statfs.f_type as i64;
+
+    if magic == BTRFS_SUPER_MAGIC {
+        if let Ok(info) = get_filesystem_info(fd.get_value()) {
+            return Ok((FsKey::Btrfs(u128::from_le_bytes(*info.filesystem_id.as_bytes())),magic));
+        }
+    }
+
+    let meta = super::btrfs::fd_metadata(fd.get_value())?;
+
+    Ok((FsKey::Dev(meta.dev()),magic))
+}
+
+/// Try the dedupe ioctl on two temporary files. If they can't be created, guess from the filesystem type
+fn probe_mechanism(dir: &Path, magic: i64) -> DedupMechanism {
+    const PROBE_SIZE: usize = 4096;
+
+    let probe_a = dir.join(format!(".dupion_probe_{}_a",std::process::id()));
+    let probe_b = dir.join(format!(".dupion_probe_{}_b",std::process::id()));
+
+    let result = (|| -> std::io::Result<DedupMechanism> {
+        let mut files = Vec::with_capacity(2);
+
+        for p in [&probe_a,&probe_b] {
+            let mut f = OpenOptions::new().read(true).write(true).create_new(true).open(p)?;
+            f.write_all(&[0x55;PROBE_SIZE])?;
+            f.sync_all()?;
+            files.push(f);
+        }
+
+        let mut dedup_range = DedupeRange {
+            src_offset: 0,
+            src_length: PROBE_SIZE as u64,
+            dest_infos: vec![DedupeRangeDestInfo {
+                dest_fd: files[1].as_raw_fd() as i64,
+                dest_offset: 0,
+                bytes_deduped: 0,
+                status: DedupeRangeStatus::Same,
+            }],
+        };
+
+        if deduplicate_range(files[0].as_raw_fd(), &mut dedup_range).is_ok() && dedup_range.dest_infos[0].status == DedupeRangeStatus::Same {
+            return Ok(DedupMechanism::Dedupe);
+        }
+
+        if clone_range(files[0].as_raw_fd(), 0, 0, files[1].as_raw_fd(), 0).is_ok() {
+            dprintln!("\tNo dedupe, but clone supported on {}, see --dedup clone",dir.to_string_lossy());
+        }
+
+        Ok(DedupMechanism::Unsupported)
+    })();
+
+    let _ = remove_file(&probe_a);
+    let _ = remove_file(&probe_b);
+
+    result.unwrap_or_else(|e| {
+        dprintln!("\tCan't create filesystem probe files, guessing from filesystem type: {} ({})",e,dir.to_string_lossy());
+        match magic {
+            BTRFS_SUPER_MAGIC | XFS_SUPER_MAGIC => DedupMechanism::Dedupe,
+            _ => DedupMechanism::Unsupported,
+        }
+    })
+}
+
+const BTRFS_SUPER_MAGIC: i64 = 0x9123683E;
+const XFS_SUPER_MAGIC: i64 = 0x58465342;
+
+pub fn fs_type_name(magic: i64) -> &'static str {
+    match magic {
+        BTRFS_SUPER_MAGIC => "btrfs",
+        XFS_SUPER_MAGIC => "xfs",
+        0xEF53 => "ext2/3/4",
+        0x6969 => "nfs",
+        0x7461636F => "ocfs2",
+        0xCA451A4E => "bcachefs",
+        0x2FC12FC1 => "zfs",
+        0xF2F52010 => "f2fs",
+        0x01021994 => "tmpfs",
+        0x794C7630 => "overlayfs",
+        0xFE534D42 | 0xFF534D42 => "cifs",
+        0x65735546 => "fuse",
+        _ => "unknown",
+    }
+}
@@ -5,21 +5,29 @@ use opts::Opts;
 use util::*;
 use vfs::{entry::VfsEntryType, VfsId};
 use std::cmp::Reverse;
+use verify::verify_files;
+use phase::Phase;
 use std::{sync::atomic::Ordering, ops::Range};
+use ::btrfs::get_file_extent_map_for_path_noloop;
+use driver::platterwalker::encoded_bytes;
+use errors::ScanErrorKind;
 
 pub mod btrfs;
 pub mod fd;
+pub mod fs;
 
 pub trait Deduper {
-    fn dedup(&mut self, state: &'static RwLock<State>, opts: &'static Opts) -> AnyhowResult<()> {
-        DISP_PROCESSED_FILES.store(0,Ordering::Relaxed);
-        DISP_PREV.store(0,Ordering::Relaxed);
-        DISP_PROCESSED_BYTES.store(0,Ordering::Relaxed);
-        DISP_RELEVANT_FILES.store(0,Ordering::Relaxed);
-        DISP_RELEVANT_BYTES.store(0,Ordering::Relaxed);
-        DISP_DEDUPED_BYTES.store(0, Ordering::Relaxed);
+    fn dedup(&mut self, state: &RwLock<State>, opts: &Opts) -> AnyhowResult<()> {
+        let progress = state.read().progress.clone();
+        state.read().errors.set_phase(Some(Phase::Dedup));
+        progress.processed_files.store(0,Ordering::Relaxed);
+        progress.processed_bytes.store(0,Ordering::Relaxed);
+        progress.relevant_files.store(0,Ordering::Relaxed);
+        progress.relevant_bytes.store(0,Ordering::Relaxed);
+        progress.deduped_bytes.store(0, Ordering::Relaxed);
         
-        let s = state.write();
+        let mut s = state.write();
+        refresh_layouts(&mut s, opts);
         let mut dest: Vec<DedupGroup> = Vec::with_capacity(s.hashes.len());
         let mut candidates = Vec::with_capacity(1024);
 
@@ -43,6 +51,7 @@ pub trait Deduper {
                         phys_occurrences: 0,
                         file_size: s.tree[id].file_size.unwrap(),
                         n_extends: s.tree[id].n_extends.unwrap(),
+                        encoded_bytes: s.tree[id].encoded_blocks as u64 * ENCODED_BLOCK_SIZE,
                         ctime: s.tree[id].ctime.unwrap()
                     })
             );
@@ -62,13 +71,15 @@ pub trait Deduper {
                     .enumerate()
                     .min_by_key(|(_,c)| (
                         // senpai prioritization of candidate with the:
-                        // 1. least extents
+                        // 1. smallest physical footprint (e.g. btrfs compressed), so dedup won't increase usage
+                        c.phys_footprint(),
+                        // 2. least extents
                         c.n_extends,
-                        // 2. most common phys in group
+                        // 3. most common phys in group
                         Reverse(c.phys_occurrences),
-                        // 3. oldest ctime
+                        // 4. oldest ctime
                         c.ctime,
-                        // 4. smallest distance from avg phys
+                        // 5. smallest distance from avg phys
                         distance(avg_phys, c.phys)
                     ))
                     .unwrap();
@@ -78,16 +89,22 @@ pub trait Deduper {
                 new
             };
 
+            // files in reference snapshots can be deduped against, but are never changed
             candidates.retain(|c|
                 c.id != senpai.id &&
-                (opts.aggressive_dedup || c.phys != senpai.phys)
+                (opts.aggressive_dedup || c.phys != senpai.phys) &&
+                !s.is_reference(&s.tree[c.id].path, opts)
             );
             if candidates.is_empty() {continue;}
 
             let size = senpai.file_size;
 
-            DISP_RELEVANT_BYTES.fetch_add(candidates.len() as u64*size,Ordering::Relaxed);
-            DISP_RELEVANT_FILES.fetch_add(candidates.len() as u64,Ordering::Relaxed);
+            // a fragmented senpai would pass its fragmentation to all dups, so rewrite it first
+            // compressed extents are always small, and the defrag would decompress them
+            let defrag = opts.dedup_defrag.is_some_and(|thres| senpai.n_extends > thres) && senpai.encoded_bytes == 0;
+
+            progress.relevant_bytes.fetch_add((candidates.len() as u64 + defrag as u64)*size,Ordering::Relaxed);
+            progress.relevant_files.fetch_add(candidates.len() as u64,Ordering::Relaxed);
 
             dest.push(DedupGroup{
                 senpai: senpai.id,
@@ -95,12 +112,32 @@ pub trait Deduper {
                 range: 0..size,
                 actual_file_size: size,
                 avg_phys,
+                defrag,
             });
         }
 
         drop(s);
 
         dest.sort_by_key(|g| g.avg_phys );
+
+        if opts.verify {
+            let pairs = dest.iter()
+                .flat_map(|g| g.dups.iter().map(|&id| (g.senpai,id) ) )
+                .collect::<Vec<_>>();
+
+            let verified = verify_files(&pairs, &state.read(), opts);
+            verified.drop_mismatched_hashes(&mut state.write());
+
+            dest.retain_mut(|g| {
+                let before = g.dups.len();
+                g.dups.retain(|&id| verified.passed(id) );
+                let skipped = (before - g.dups.len()) as u64;
+                progress.relevant_bytes.fetch_sub(skipped*g.actual_file_size,Ordering::Relaxed);
+                progress.relevant_files.fetch_sub(skipped,Ordering::Relaxed);
+                !g.dups.is_empty()
+            });
+        }
+
         dest.shrink_to_fit();
 
         self.dedup_groups(dest, state, opts)?;
@@ -108,7 +145,7 @@ pub trait Deduper {
         Ok(())
     }
 
-    fn dedup_groups(&mut self, groups: Vec<DedupGroup>, state: &'static RwLock<State>, opts: &'static Opts) -> AnyhowResult<()>;
+    fn dedup_groups(&mut self, groups: Vec<DedupGroup>, state: &RwLock<State>, opts: &Opts) -> AnyhowResult<()>;
 }
 
 #[derive(Clone, Copy)]
@@ -118,9 +155,46 @@ pub struct DedupCandidate {
     pub phys_occurrences: usize,
     pub file_size: u64,
     pub n_extends: usize,
+    /// bytes in encoded (compressed) extents
+    pub encoded_bytes: u64,
     pub ctime: i64,
 }
 
+impl DedupCandidate {
+    /// FIEMAP doesn't report the on-disk size of encoded extents, but btrfs only keeps compressed extents if they are smaller,
+    /// so the bytes in unencoded extents are the metric to minimize
+    pub fn phys_footprint(&self) -> u64 {
+        self.file_size.saturating_sub(self.encoded_bytes)
+    }
+}
+
+/// The layout of the files from the cache, e.g. in the unchanged directories of the incremental scan, may be outdated by a previous dedup or defrag.
+/// So it's read again for the files which could be deduped
+fn refresh_layouts(s: &mut State, opts: &Opts) {
+    let ids = s.hashes.values()
+        .filter(|e| e.size != 0 && e.entries.len() > 1 )
+        .flat_map(|e| e.entries.iter() )
+        .filter(|&&(typ,id)| typ == VfsEntryType::File && s.tree[id].valid && s.tree[id].phys.is_some() )
+        .map(|&(_,id)| id )
+        .collect::<Vec<_>>();
+
+    for id in ids {
+        let path = s.tree[id].path.clone();
+        match get_file_extent_map_for_path_noloop(&path) {
+            Ok(extents) => {
+                let e = &mut s.tree[id];
+                e.phys = Some(extents.first().map_or(0, |e| e.physical ));
+                e.n_extends = Some(extents.len());
+                e.encoded_blocks = (encoded_bytes(&extents) / ENCODED_BLOCK_SIZE).try_into().unwrap_or(u32::MAX);
+            },
+            Err(e) => {
+                s.errors.push(opts,ScanErrorKind::Metadata,&*path,format!("reading the extents failed: {}",e));
+                s.tree[id].n_extends = None;
+            },
+        }
+    }
+}
+
 fn count_phys_occurrences_sorted(v: &mut [DedupCandidate]) {
     if v.is_empty() {return;}
 
@@ -155,6 +229,8 @@ pub struct DedupGroup {
     pub range: Range<u64>,
     pub avg_phys: u64,
     pub actual_file_size: u64,
+    /// defragment the senpai range before dedup
+    pub defrag: bool,
 }
 
 impl DedupGroup {
@@ -167,11 +243,14 @@ impl DedupGroup {
         self.range.end - self.range.start.min(self.range.end)
     }
 
+    /// the defrag rewrite of the senpai range also goes through the cache
     pub fn usage(&self) -> u64 {
-        self.range_len() * self.sum()
+        self.range_len() * (self.sum() + self.defrag as u64)
     }
 
     /// return the first half and keep last half in &mut self
+    ///
+    /// The senpai range is only defragmented with the first half
     pub fn split_off_start_at_candidate_n(&mut self, at: usize) -> Self {
         let dups_remainder = self.dups.split_off(at);
         
@@ -183,10 +262,13 @@ impl DedupGroup {
             range: self.range.clone(),
             avg_phys: self.avg_phys, //TODO recalculate avg_phys
             actual_file_size: self.actual_file_size,
+            defrag: std::mem::replace(&mut self.defrag, false),
         }
     }
 
     /// return the last half and keep first half in &mut self
+    ///
+    /// The senpai range is only defragmented with the first half
     pub fn split_off_end_at_candidate_n(&mut self, at: usize) -> Self {
         let dups = self.dups.split_off(at);
 
@@ -196,6 +278,7 @@ impl DedupGroup {
             range: self.range.clone(),
             avg_phys: self.avg_phys, //TODO recalculate avg_phys
             actual_file_size: self.actual_file_size,
+            defrag: false,
         }
     }
 
@@ -0,0 +1,231 @@
+use super::*;
+use std::{ffi::OsString, fs::read_dir, os::unix::fs::MetadataExt, path::{Path, PathBuf}, sync::atomic::Ordering};
+use btrfs::{FileDescriptor, get_filesystem_info, get_root_id, get_root_generation, get_child_subvolumes, get_inode_dir_path, find_changed_inodes, get_inode_paths, get_file_extent_map_for_path_noloop};
+use rustc_hash::FxHashSet;
+use serde_derive::{Serialize, Deserialize};
+use vfs::VfsId;
+use platterwalker::{size_file, encoded_bytes};
+use subvol::SUBVOL_ROOT_INODE;
+use errors::ScanErrorKind;
+
+/// The generation of a btrfs subvolume in a scan root, recorded at the time the root was scanned
+#[derive(Serialize,Deserialize,Clone,Debug)]
+pub struct SubvolGeneration {
+    /// the scan root
+    pub root: PathBuf,
+    /// the root directory of the subvolume, the one of the scan root itself may be above the scan root
+    pub path: PathBuf,
+    pub fs_id: String,
+    pub subvol: u64,
+    pub generation: u64,
+}
+
+impl SubvolGeneration {
+    fn same_subvol(&self, other: &Self) -> bool {
+        self.root == other.root && self.path == other.path && self.fs_id == other.fs_id && self.subvol == other.subvol
+    }
+}
+
+/// Find the subvolume of the scan root and the subvolumes nested inside, with their current generations.
+///
+/// Fails if the root isn't on btrfs or without CAP_SYS_ADMIN
+pub fn subvol_generations(root: &Path) -> Result<Vec<SubvolGeneration>,String> {
+    let fd = FileDescriptor::open(root, libc::O_RDONLY | libc::O_DIRECTORY)?;
+    let fd = fd.get_value();
+
+    let fs_id = get_filesystem_info(fd)?.filesystem_id.to_string();
+    let subvol = get_root_id(fd)?;
+
+    let ino = root.metadata().map_err(|e| e.to_string() )?.ino();
+    let rel = get_inode_dir_path(fd, 0, ino)?;
+
+    let mut path = root.to_owned();
+    for _ in Path::new(&rel).components() {
+        path.pop();
+    }
+
+    let mut units = vec![SubvolGeneration{
+        root: root.to_owned(),
+        path,
+        fs_id: fs_id.clone(),
+        subvol,
+        generation: get_root_generation(fd, subvol)?,
+    }];
+
+    let mut i = 0;
+
+    while i < units.len() {
+        for child in get_child_subvolumes(fd, units[i].subvol)? {
+            let mut path = units[i].path.clone();
+            let dir = get_inode_dir_path(fd, units[i].subvol, child.dir_id)?;
+            if !dir.is_empty() {
+                path.push(dir);
+            }
+            path.push(&child.name);
+
+            // skip the ones outside the root, and the deleted ones which aren't cleaned yet
+            let is_subvol_root = path.symlink_metadata().is_ok_and(|m| m.is_dir() && m.ino() == SUBVOL_ROOT_INODE );
+            if !path.starts_with(root) || !is_subvol_root {continue;}
+
+            units.push(SubvolGeneration{
+                root: root.to_owned(),
+                path,
+                fs_id: fs_id.clone(),
+                subvol: child.root_id,
+                generation: get_root_generation(fd, child.root_id)?,
+            });
+        }
+        i += 1;
+    }
+
+    Ok(units)
+}
+
+/// Update the cached files of a scan root from the inodes changed since the generations of the last scan, instead of walking it.
+///
+/// Returns false if the root has to be walked because the cache doesn't match the subvolumes of the root
+pub fn scan_changed(root: &Path, current: &[SubvolGeneration], dest: &mut Vec<(u64,VfsId)>, hash_now: &mut Vec<VfsId>, s: &mut State, opts: &Opts) -> AnyhowResult<bool> {
+    let root_id = match s.tree.cid(root) {
+        Some(v) => v,
+        None => return Ok(false),
+    };
+
+    let errors = s.errors.clone();
+
+    let cached = s.btrfs_generations.iter()
+        .filter(|g| g.root == root )
+        .collect::<Vec<_>>();
+
+    if cached.len() != current.len() || !current.iter().all(|c| cached.iter().any(|g| g.same_subvol(c) ) ) {
+        return Ok(false);
+    }
+
+    // query all changes before touching the tree, so that it stays intact for the walk if this fails
+
+    let mut changed_dirs = Vec::new();
+    let mut changed_files = Vec::new();
+
+    for unit in current {
+        let since = cached.iter().find(|g| g.same_subvol(unit) ).unwrap().generation + 1;
+
+        let fd = FileDescriptor::open(&unit.path, libc::O_RDONLY | libc::O_DIRECTORY).map_err(anyhow::Error::msg)?;
+        let fd = fd.get_value();
+
+        for inode in find_changed_inodes(fd, since).map_err(anyhow::Error::msg)? {
+            if !inode.is_file() && !inode.is_dir() {continue;}
+
+            let paths = if inode.inode == SUBVOL_ROOT_INODE {
+                vec![OsString::new()]
+            }else{
+                // deleted since the search
+                match get_inode_paths(fd, inode.inode) {
+                    Ok(v) => v,
+                    Err(_) => continue,
+                }
+            };
+
+            for p in paths {
+                let path = if p.is_empty() {unit.path.clone()} else {unit.path.join(p)};
+                if !path.starts_with(root) || path.to_str().is_none() {continue;}
+
+                if inode.is_dir() {
+                    changed_dirs.push(path);
+                }else{
+                    changed_files.push(path);
+                }
+            }
+        }
+    }
+
+    opts.log_verbosed("GENERATION", root);
+
+    s.set_valid(root_id);
+
+    // the entries missing in changed directories were removed
+
+    for dir in &changed_dirs {
+        let id = match s.tree.cid(dir) {
+            Some(v) => v,
+            None => continue,
+        };
+
+        let mut names = FxHashSet::<OsString>::default();
+        for dent in try_log!(read_dir(dir),errors,opts,ScanErrorKind::ReadDir,&**dir,continue) {
+            let dent = try_log!(dent,errors,opts,ScanErrorKind::ReadDir,&**dir,continue);
+            names.insert(dent.file_name());
+        }
+
+        for c in s.tree[id].childs.clone() {
+            let e = &s.tree[c];
+            if e.exists() && !e.path.file_name().is_some_and(|n| names.contains(n) ) {
+                opts.log_verbosed("REMOVED", &e.path.clone());
+                s.invalidate(c);
+            }
+        }
+    }
+
+    let mut sized = FxHashSet::<VfsId>::default();
+
+    for path in changed_files {
+        let meta = try_log!(path.symlink_metadata(),errors,opts,ScanErrorKind::Metadata,&*path,continue);
+        if !meta.is_file() {continue;}
+
+        if let Some(id) = s.tree.cid(&path) {
+            let e = &s.tree[id];
+            // e.g. only the atime changed
+            if e.is_file && e.file_size == Some(meta.len()) && e.ctime == Some(meta.ctime()) {continue;}
+            s.invalidate(id);
+        }
+
+        let extents = get_file_extent_map_for_path_noloop(&path).unwrap_or_default();
+
+        let id = size_file(
+            &path,
+            &meta,
+            extents.first().map_or(0, |e| e.physical ),
+            extents.len(),
+            encoded_bytes(&extents),
+            dest,
+            hash_now,
+            s,
+            opts,
+        )?;
+
+        sized.extend(id);
+    }
+
+    // the remaining files are unchanged
+
+    let mut stack = vec![root_id];
+
+    while let Some(id) = stack.pop() {
+        let e = &s.tree[id];
+
+        if e.is_file {
+            if !e.valid || sized.contains(&id) {continue;}
+
+            if e.file_size.is_none() || e.ctime.is_none() {
+                let path = e.path.clone();
+                let meta = try_log!(path.symlink_metadata(),errors,opts,ScanErrorKind::Metadata,&*path,continue);
+                let extents = get_file_extent_map_for_path_noloop(&path).unwrap_or_default();
+                size_file(&path, &meta, extents.first().map_or(0, |e| e.physical ), extents.len(), encoded_bytes(&extents), dest, hash_now, s, opts)?;
+                continue;
+            }
+
+            s.progress.found_bytes.fetch_add(e.file_size.unwrap(),Ordering::Relaxed);
+            s.progress.found_files.fetch_add(1,Ordering::Relaxed);
+            dest.push((e.phys.unwrap_or(0),id));
+        } else if e.is_dir {
+            stack.extend_from_slice(&e.childs);
+        }
+    }
+
+    Ok(true)
+}
+
+impl State {
+    pub fn set_btrfs_generations(&mut self, root: &Path, generations: Vec<SubvolGeneration>) {
+        self.btrfs_generations.retain(|g| g.root != root );
+        self.btrfs_generations.extend(generations);
+    }
+}
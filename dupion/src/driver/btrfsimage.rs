@@ -0,0 +1,208 @@
+use super::*;
+use std::{ffi::OsStr, io::{self, Write}, os::unix::ffi::OsStrExt, path::{Path, PathBuf}, sync::atomic::Ordering};
+use btrfs::diskformat::{BtrfsFilesystem, BtrfsMmapDeviceSet, BTRFS_FS_TREE_OBJECT_ID, BTRFS_FIRST_FREE_OBJECT_ID, BTRFS_ROOT_ITEM_TYPE, BTRFS_FT_DIR, BTRFS_FT_REG_FILE, BTRFS_EXTENT_DATA_NO_COMPRESSION};
+use rustc_hash::FxHashMap;
+use vfs::VfsId;
+use platterwalker::size_entry;
+use subvol::{Subvolume, SnapshotMode};
+use errors::ScanErrorKind;
+
+/// Walks the files of an unmounted btrfs filesystem from its device files or images, without mounting it.
+///
+/// The files appear below the path of the first device
+pub struct BtrfsImageWalker {
+    pub entries: Option<Vec<(u64,VfsId)>>,
+    /// (tree id, inode) of the found files
+    inodes: FxHashMap<VfsId,(u64,u64)>,
+}
+
+impl Driver for BtrfsImageWalker {
+    fn run(&mut self, state: &RwLock<State>, opts: &Opts, phase: Phase) -> AnyhowResult<()> {
+        let errors = state.read().errors.clone();
+        let progress = state.read().progress.clone();
+        errors.set_phase(Some(phase));
+
+        match phase {
+            Phase::Size => {
+                assert!(self.entries.is_none());
+
+                let mut dest = Vec::with_capacity(65536);
+
+                {
+                    let mut s = state.write();
+                    s.add_subvolume(Subvolume{
+                        path: opts.paths[0].clone(),
+                        id: BTRFS_FS_TREE_OBJECT_ID,
+                        readonly: false,
+                    });
+                    with_filesystem(&opts.paths, |filesystem|
+                        self.walk_dir(filesystem, BTRFS_FS_TREE_OBJECT_ID, BTRFS_FIRST_FREE_OBJECT_ID, &opts.paths[0], &mut dest, &mut s, opts)
+                    )?;
+                }
+
+                progress.section_end();
+
+                dprint!("Sort...");
+                io::stdout().flush().unwrap();
+
+                dest.sort_by_key(|(o,_)| *o );
+
+                let mut s = state.write();
+                let progress = s.progress.clone();
+
+                for (_,id) in &dest {
+                    if s.is_file_read_candidate(*id,opts) {
+                        s.tree[*id].disp_add_relevant(&progress);
+                    }
+                }
+
+                dprintln!("Sort... Done");
+
+                self.entries = Some(dest);
+
+                Ok(())
+            },
+            Phase::Hash => {
+                assert!(self.entries.is_some());
+
+                with_filesystem(&opts.paths, |filesystem| {
+                    for &(_,id) in self.entries.as_ref().unwrap() {
+                        progress.check_interrupted()?;
+
+                        let path = {
+                            let mut s = state.write();
+                            if !s.is_file_read_candidate(id,opts) {continue;}
+                            s.tree[id].disp_add_relevant(&progress);
+                            s.tree[id].path.clone()
+                        };
+
+                        opts.log_verbosed("HASH", &path);
+
+                        let (tree_id,inode) = self.inodes[&id];
+
+                        let mut hasher = opts.hash_algorithm.hasher();
+
+                        let read = filesystem.read_file(tree_id, inode, |data| {
+                            hasher.update(data);
+                            progress.processed_bytes.fetch_add(data.len() as u64,Ordering::Relaxed);
+                        });
+                        try_log!(read,errors,opts,ScanErrorKind::Read,&*path,continue);
+
+                        let mut s = state.write();
+
+                        s.tree[id].file_hash = Some(hasher.finalize());
+                        s.push_to_hash_group(id,true,false).unwrap();
+
+                        progress.processed_files.fetch_add(1,Ordering::Relaxed);
+
+                        s.eventually_store_vfs(&opts.cache_path, false);
+                    }
+
+                    Ok(())
+                })
+            },
+            // the extents are read through the filesystem tree, a partial read would be about as expensive
+            Phase::PartialHash => Ok(()),
+            // archives inside images aren't read
+            Phase::PostHash => Ok(()),
+            Phase::Dedup => Ok(()),
+        }
+    }
+    fn new() -> Self {
+        Self{
+            entries: None,
+            inodes: FxHashMap::default(),
+        }
+    }
+}
+
+impl BtrfsImageWalker {
+    #[allow(clippy::too_many_arguments)]
+    fn walk_dir(&mut self, filesystem: &BtrfsFilesystem, tree_id: u64, dir_inode: u64, path: &Path, dest: &mut Vec<(u64,VfsId)>, s: &mut State, opts: &Opts) -> AnyhowResult<()> {
+        let tree = match filesystem.filesystem_tree(tree_id) {
+            Some(v) => v,
+            None => {
+                s.errors.push(opts,ScanErrorKind::ReadDir,path,format!("filesystem tree {} not found",tree_id));
+                return Ok(());
+            },
+        };
+
+        for dir_index in tree.dir_indexes(dir_inode) {
+            let child_path = path.join(OsStr::from_bytes(dir_index.name()));
+            if child_path.to_str().is_none() {continue;}
+
+            let child = dir_index.child_object_id();
+
+            if dir_index.child_key().item_type() == BTRFS_ROOT_ITEM_TYPE {
+                // subvolume or snapshot
+                let readonly = filesystem.root_item(child).is_some_and(|r| r.readonly() );
+                let skip = readonly && opts.snapshots == SnapshotMode::SkipReadonly;
+
+                opts.log_verbosed(if skip {"SKIP SNAPSHOT"} else {"SUBVOL"}, &child_path);
+
+                s.add_subvolume(Subvolume{
+                    path: child_path.clone(),
+                    id: child,
+                    readonly,
+                });
+
+                if skip {continue;}
+
+                self.walk_dir(filesystem, child, BTRFS_FIRST_FREE_OBJECT_ID, &child_path, dest, s, opts)?;
+                continue;
+            }
+
+            match dir_index.child_type() {
+                BTRFS_FT_DIR => self.walk_dir(filesystem, tree_id, child, &child_path, dest, s, opts)?,
+                BTRFS_FT_REG_FILE => {
+                    let inode_item = match tree.inode_item(child) {
+                        Some(v) => v,
+                        None => {
+                            s.errors.push(opts,ScanErrorKind::Metadata,&*child_path,format!("inode item {} not found",child));
+                            continue;
+                        },
+                    };
+
+                    let extents = tree.extent_datas(child);
+
+                    let phy_off = extents.iter()
+                        .find(|e| !e.inline() )
+                        .map_or(0, |e| e.extent_logical_address() );
+
+                    let encoded_bytes = extents.iter()
+                        .filter(|e| e.compression() != BTRFS_EXTENT_DATA_NO_COMPRESSION )
+                        .map(|e| if e.inline() {e.logical_data_size()} else {e.extent_data_size()} )
+                        .sum();
+
+                    let id = size_entry(
+                        &child_path,
+                        inode_item.st_size(),
+                        inode_item.st_ctime().seconds(),
+                        phy_off,
+                        extents.len(),
+                        encoded_bytes,
+                        dest,
+                        &mut Vec::new(),
+                        s,
+                        opts,
+                    )?;
+
+                    self.inodes.insert(id, (tree_id,child));
+                },
+                _ => {},
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Open the filesystem on the given device files for `f`, the mappings are released afterwards
+fn with_filesystem<R>(devices: &[PathBuf], f: impl FnOnce(&BtrfsFilesystem) -> AnyhowResult<R>) -> AnyhowResult<R> {
+    let mmaps = BtrfsMmapDeviceSet::open(devices).map_err(anyhow::Error::msg)?;
+    let devices = mmaps.devices().map_err(anyhow::Error::msg)?;
+
+    let filesystem = BtrfsFilesystem::open(&btrfs_output::open(), &devices).map_err(anyhow::Error::msg)?;
+
+    f(&filesystem)
+}
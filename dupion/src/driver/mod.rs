@@ -5,8 +5,10 @@ use phase::Phase;
 use parking_lot::RwLock;
 
 pub mod platterwalker;
+pub mod btrfsgen;
+pub mod btrfsimage;
 
 pub trait Driver {
-    fn run(&mut self, state: &'static RwLock<State>, opts: &'static Opts, phase: Phase) -> AnyhowResult<()>;
+    fn run(&mut self, state: &RwLock<State>, opts: &Opts, phase: Phase) -> AnyhowResult<()>;
     fn new() -> Self;
 }
\ No newline at end of file
@@ -1,36 +1,158 @@
 use super::*;
 use std::{io::ErrorKind, path::Path};
-use std::os::unix::fs::MetadataExt;
-use btrfs::get_file_extent_map_for_path_noloop;
-use platter_walk::{Order, ToScan};
-use vfs::VfsId;
+use std::os::unix::fs::{MetadataExt, FileExt};
+use btrfs::{get_file_extent_map_for_path_noloop, FileExtent};
+use platter_walk::{DirAction, Order, ToScan};
+use vfs::{VfsId, rootcache::is_root_cache_file};
+use super::btrfsgen::{subvol_generations, scan_changed};
+use subvol::{Subvolume, SnapshotMode, subvolume_dir_filter};
+use storage::split_by_storage;
+use parking_lot::Mutex;
 use reapfrog::MultiFileReadahead;
-use std::{sync::{atomic::Ordering, Arc}, io::{Read, Write, self}, fs::{Metadata, File}};
+use std::{cell::RefCell, collections::VecDeque, fs::{Metadata, File}, io::{Read, Seek, Write, self}, path::PathBuf, rc::Rc, sync::{atomic::Ordering, Arc}, time::SystemTime};
 use util::*;
 use zip::{open_zip, decode_zip};
+use errors::ScanErrorKind;
 use io::{BufReader, Cursor};
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde_derive::{Serialize, Deserialize};
 
 pub struct PlatterWalker {
     pub entries: Option<Vec<(u64,VfsId)>>,
+    /// entries before this were hashed by an interrupted scan
+    resume_position: usize,
+}
+
+/// The progress of the hash pass, stored with the cache so that an interrupted scan can be resumed
+#[derive(Serialize,Deserialize,Clone)]
+pub struct HashCheckpoint {
+    /// the scan roots, a checkpoint is only resumed by a scan of the same paths
+    pub paths: Vec<PathBuf>,
+    /// PlatterWalker::entries of the scan
+    pub entries: Vec<(u64,VfsId)>,
+    /// the hash pass got this far, the entries before this which still have no hash are hashed again
+    pub position: usize,
+    #[serde(skip)]
+    index: FxHashMap<VfsId,usize>,
+}
+
+impl HashCheckpoint {
+    pub fn new(paths: Vec<PathBuf>, entries: Vec<(u64,VfsId)>) -> Self {
+        Self{paths, entries, position: 0, index: FxHashMap::default()}
+    }
+
+    /// Advance the position past a hashed file
+    pub fn hashed(&mut self, id: VfsId) {
+        if self.index.is_empty() {
+            self.index = self.entries.iter().enumerate().map(|(i,&(_,id))| (id,i) ).collect();
+        }
+        if let Some(&i) = self.index.get(&id) {
+            self.position = self.position.max(i+1);
+        }
+    }
+}
+
+impl State {
+    /// Restore the files of the checkpoint of an interrupted scan of the same paths instead of walking again.
+    ///
+    /// Returns the entries and the position of the hash pass
+    pub fn resume_checkpoint(&mut self, opts: &Opts) -> Option<(Vec<(u64,VfsId)>,usize)> {
+        let checkpoint = self.checkpoint.as_ref().filter(|c| c.paths == opts.paths )?;
+
+        // e.g. the cache was written by an older version in between
+        let complete = checkpoint.entries.iter()
+            .all(|&(_,id)| id.evil_inner < self.tree.entries.len() && self.tree[id].was_file() && self.tree[id].file_size.is_some() );
+        if !complete {return None;}
+
+        let entries = checkpoint.entries.clone();
+        let position = checkpoint.position;
+        let progress = self.progress.clone();
+
+        for &(_,id) in &entries {
+            let path = self.tree[id].path.clone();
+            // the parents are dirs again
+            self.tree.cid_and_create(&path);
+            self.set_valid(id);
+
+            progress.found_bytes.fetch_add(self.tree[id].file_size.unwrap(),Ordering::Relaxed);
+            progress.found_files.fetch_add(1,Ordering::Relaxed);
+        }
+
+        for &(_,id) in &entries {
+            if self.is_file_read_candidate(id,opts) {
+                self.tree[id].disp_add_relevant(&progress);
+            }
+        }
+
+        Some((entries,position))
+    }
 }
 
 impl Driver for PlatterWalker {
-    fn run(&mut self, state: &'static RwLock<State>, opts: &'static Opts, phase: Phase) -> AnyhowResult<()> {
+    fn run(&mut self, state: &RwLock<State>, opts: &Opts, phase: Phase) -> AnyhowResult<()> {
+        let errors = state.read().errors.clone();
+        let progress = state.read().progress.clone();
+        errors.set_phase(Some(phase));
+
         match phase {
             Phase::Size => {
                 assert!(self.entries.is_none());
 
+                if opts.resume {
+                    let resumed = state.write().resume_checkpoint(opts);
+                    match resumed {
+                        Some((entries,position)) => {
+                            progress.section_end();
+                            dprintln!("Resume the hash pass at file {}/{}",position,entries.len());
+                            self.entries = Some(entries);
+                            self.resume_position = position;
+                            return Ok(());
+                        },
+                        None => dprintln!("No checkpoint of these paths in the cache, scanning from the start"),
+                    }
+                }
+
                 let mut scan = ToScan::<()>::new();
 
                 scan.prefetch_dirs(opts.dir_prefetch);
-                scan.set_order(Order::Content);
+                scan.set_prefetch_observer(Box::new(|mount, strategy| {
+                    // mount points are mostly outside the roots
+                    if opts.verbose {
+                        dprintln!("\tPREFETCH {} {}",strategy.name(),mount.display());
+                    }
+                }));
+                scan.set_order(opts.walk_order);
                 //scan.set_batchsize(usize::MAX);
 
                 let mut dest = Vec::with_capacity(65536);
                 let mut hash_now: Vec<VfsId> = Vec::new();
+                // the btrfs generations of the roots which have to be walked, recorded before the walk
+                let mut btrfs_walked = Vec::new();
 
                 for root in &opts.paths {
                     if root.is_dir() {
+                        if opts.btrfs_incremental {
+                            match subvol_generations(root) {
+                                Ok(generations) => {
+                                    let mut s = state.write();
+                                    for g in &generations {
+                                        if let Some(subvol) = g.path.metadata().ok().and_then(|m| Subvolume::probe(&g.path, &m) ) {
+                                            s.add_subvolume(subvol);
+                                        }
+                                    }
+                                    match scan_changed(root, &generations, &mut dest, &mut hash_now, &mut s, opts) {
+                                        Ok(true) => {
+                                            s.set_btrfs_generations(root, generations);
+                                            continue;
+                                        },
+                                        Ok(false) => {},
+                                        Err(e) => dprintln!("\tbtrfs incremental scan failed, walking instead: {} ({})",e,opts.path_disp(root)),
+                                    }
+                                    btrfs_walked.push((root,generations));
+                                },
+                                Err(e) => dprintln!("\tbtrfs incremental scan not possible, walking instead: {} ({})",e,opts.path_disp(root)),
+                            }
+                        }
                         try_returnerr!(scan.add_root(root.clone()),"\tError adding root: {} ({})",opts.path_disp(root));
                     } else if root.is_file() {
                         let extends = get_file_extent_map_for_path_noloop(root).unwrap();
@@ -39,6 +161,7 @@ impl Driver for PlatterWalker {
                             &root.metadata()?,
                             extends.get(0).map(|e| e.physical ).unwrap_or(0),
                             extends.len(),
+                            encoded_bytes(&extends),
                             &mut dest,
                             &mut hash_now,
                             &mut state.write(), opts
@@ -50,28 +173,50 @@ impl Driver for PlatterWalker {
                     ft.is_file() && !ft.is_symlink() && path.to_str().is_some()
                 }));
 
+                let incremental = Rc::new(RefCell::new(IncrementalScan::default()));
+
+                {
+                    let incremental = incremental.clone();
+                    scan.set_dir_filter(Box::new(move |dir| {
+                        let meta = match dir.metadata() {
+                            Ok(v) => v,
+                            Err(_) => return DirAction::Read,
+                        };
+                        if !subvolume_dir_filter(dir, &meta, state, opts) {
+                            return DirAction::Descend(vec![]);
+                        }
+                        if opts.incremental {
+                            incremental_dir_filter(dir, &meta, &mut incremental.borrow_mut(), state, opts)
+                        }else{
+                            DirAction::Read
+                        }
+                    }));
+                }
+
                 for entry_set in scan {
+                    progress.check_interrupted()?;
+
                     let mut s = state.write();
 
-                    if let Some(entry_set) = soft_error!(entry_set,"\tError: {}",) {
+                    if let Ok(entry_set) = entry_set.map_err(|e| errors.push(opts,ScanErrorKind::ReadDir,None,e) ) {
                         for (phy_off, mut entry) in entry_set {
 
                             let path = match entry.canon_path.take() {
                                 Some(c) => c,
                                 None => {
                                     dprintln!("METAMISS");
-                                    try_continue!(entry.path().canonicalize(),"\tError: {} ({})",opts.path_disp(entry.path()))
+                                    try_log!(entry.path().canonicalize(),errors,opts,ScanErrorKind::Metadata,entry.path(),continue)
                                 },
                             };
                             let meta = match entry.metadata.take() {
                                 Some(c) => c,
                                 None => {
                                     dprintln!("METAMISS");
-                                    try_continue!(path.metadata(),"\tError: {} ({})",opts.path_disp(&path))
+                                    try_log!(path.metadata(),errors,opts,ScanErrorKind::Metadata,&*path,continue)
                                 },
                             };
 
-                            size_file(&path, &meta, phy_off, entry.extents().count(), &mut dest, &mut hash_now, &mut s, opts)?;
+                            size_file(&path, &meta, phy_off, entry.extents().count(), encoded_bytes(entry.extents()), &mut dest, &mut hash_now, &mut s, opts)?;
                         }
 
                         drop(s);
@@ -93,21 +238,61 @@ impl Driver for PlatterWalker {
 
                 let mut s = state.write();
 
-                stat_section_end();
+                if opts.incremental {
+                    let IncrementalScan{read_dirs,reused} = incremental.take();
+
+                    for (dir,mtime) in read_dirs {
+                        if let Some(id) = s.tree.cid(&dir) {
+                            s.set_dir_mtime(id, mtime);
+                        }
+                    }
+
+                    dest.extend(reused);
+                }
+
+                for (root,generations) in btrfs_walked {
+                    if let Some(id) = s.tree.cid(root) {
+                        s.forget_invalid(id);
+                    }
+                    s.set_btrfs_generations(root, generations);
+                }
+
+                if opts.snapshots == SnapshotMode::SkipReadonly {
+                    // the generation based scan doesn't walk, so it doesn't skip them itself
+                    let skipped = s.subvolumes.iter()
+                        .filter(|v| v.readonly && !opts.paths.iter().any(|p| p.starts_with(&v.path) ) )
+                        .filter_map(|v| s.tree.cid(&v.path) )
+                        .collect::<Vec<_>>();
+                    for id in skipped {
+                        s.invalidate(id);
+                    }
+                    dest.retain(|&(_,id)| s.tree[id].valid );
+                }
+
+                progress.section_end();
 
                 dprint!("Sort...");
                 io::stdout().flush().unwrap();
 
-                dest.sort_by_key(|(o,_)| *o );
+                // the other orders keep the order of the walk
+                if opts.walk_order == Order::Content {
+                    dest.sort_by_key(|(o,_)| *o );
+                }
+
+                let progress = s.progress.clone();
 
                 for (_,id) in &dest {
                     if s.is_file_read_candidate(*id,opts) {
-                        s.tree[*id].disp_add_relevant();
+                        s.tree[*id].disp_add_relevant(&progress);
                     }
                 }
 
                 dest.shrink_to_fit();
 
+                if s.cache_allowed {
+                    s.checkpoint = Some(HashCheckpoint::new(opts.paths.clone(), dest.clone()));
+                }
+
                 dprintln!("Sort... Done");
 
                 // stat_section_start();
@@ -116,48 +301,73 @@ impl Driver for PlatterWalker {
 
                 Ok(())
             },
-            Phase::Hash => {
+            Phase::PartialHash => {
                 assert!(self.entries.is_some());
 
-                hash_files(
+                partial_hash_files(
                     self.entries.as_ref().unwrap().iter().map(|(_,id)| *id ),
                     state,
                     opts,
-                    true,
-                )?;
+                )
+            },
+            Phase::Hash => {
+                assert!(self.entries.is_some());
 
-                
-                Ok(())
+                let entries = self.entries.as_ref().unwrap();
+
+                let (mut sequential,parallel) = split_by_storage(entries.iter().map(|(_,id)| *id ), &state.read(), opts);
+
+                if self.resume_position > 0 {
+                    let done = entries[..self.resume_position.min(entries.len())].iter()
+                        .map(|&(_,id)| id )
+                        .collect::<FxHashSet<_>>();
+                    let s = state.read();
+                    // e.g. archives whose decoding was interrupted
+                    sequential.retain(|&id| !done.contains(&id) || s.tree[id].file_hash.is_none() );
+                }
+
+                if !parallel.is_empty() {
+                    dprintln!(
+                        "Hash {} files sequentially, {} files concurrently on {} devices",
+                        sequential.len(),
+                        parallel.iter().map(Vec::len).sum::<usize>(),
+                        parallel.len(),
+                    );
+                }
+
+                hash_files_split(sequential, parallel, state, opts, true)
             },
             Phase::PostHash => {
                 assert!(self.entries.is_some());
 
-                hash_files(
-                    self.entries.as_ref().unwrap().iter().map(|(_,id)| *id ),
-                    state,
-                    opts,
-                    false,
-                )?;
-                
-                Ok(())
-            }
+                let (sequential,parallel) = split_by_storage(self.entries.as_ref().unwrap().iter().map(|(_,id)| *id ), &state.read(), opts);
+
+                hash_files_split(sequential, parallel, state, opts, false)
+            },
+            Phase::Dedup => Ok(()),
         }
     }
     fn new() -> Self {
         Self{
             entries: None,
+            resume_position: 0,
         }
     }
 }
 
-pub fn size_file(path: &Path, meta: &Metadata, phy_off: u64, n_extends: usize, dest: &mut Vec<(u64,VfsId)>, hash_now: &mut Vec<VfsId>, s: &mut State, opts: &Opts) -> AnyhowResult<()> {
-    let size = meta.len();
-    let ctime = meta.ctime();
+/// Returns the id pushed to `dest`, None if the file is skipped
+pub fn size_file(path: &Path, meta: &Metadata, phy_off: u64, n_extends: usize, encoded_bytes: u64, dest: &mut Vec<(u64,VfsId)>, hash_now: &mut Vec<VfsId>, s: &mut State, opts: &Opts) -> AnyhowResult<Option<VfsId>> {
+    if is_root_cache_file(path, opts) {return Ok(None);}
+    size_entry(path, meta.len(), meta.ctime(), phy_off, n_extends, encoded_bytes, dest, hash_now, s, opts).map(Some)
+}
 
+/// Like size_file, for files which aren't in the local filesystem (e.g. inside a btrfs image)
+#[allow(clippy::too_many_arguments)]
+pub fn size_entry(path: &Path, size: u64, ctime: i64, phy_off: u64, n_extends: usize, encoded_bytes: u64, dest: &mut Vec<(u64,VfsId)>, hash_now: &mut Vec<VfsId>, s: &mut State, opts: &Opts) -> AnyhowResult<VfsId> {
     opts.log_verbosed("SIZE", path);
 
-    DISP_FOUND_BYTES.fetch_add(size,Ordering::Relaxed);
-    DISP_FOUND_FILES.fetch_add(1,Ordering::Relaxed);
+    s.progress.found_bytes.fetch_add(size,Ordering::Relaxed);
+    s.progress.found_files.fetch_add(1,Ordering::Relaxed);
 
     let id = s.tree.cid_and_create(path);
     s.validate(id,ctime,Some(size),None);
@@ -168,6 +378,7 @@ pub fn size_file(path: &Path, meta: &Metadata, phy_off: u64, n_extends: usize, d
     e.file_size = Some(size);
     e.phys = Some(phy_off);
     e.n_extends = Some(n_extends);
+    e.encoded_blocks = (encoded_bytes / ENCODED_BLOCK_SIZE).try_into().unwrap_or(u32::MAX);
     
     s.push_to_size_group(id,true,false).unwrap();
     if s.tree[id].file_hash.is_some() {
@@ -177,15 +388,217 @@ pub fn size_file(path: &Path, meta: &Metadata, phy_off: u64, n_extends: usize, d
     }
 
     if s.is_file_read_candidate(id,opts) {
-        s.tree[id].disp_add_relevant();
+        s.tree[id].disp_add_relevant(&s.progress);
     }
 
     dest.push((phy_off,id));
     hash_now.push(id);
+    Ok(id)
+}
+
+#[derive(Default)]
+struct IncrementalScan {
+    /// the directories read in this scan, with their mtime before the read
+    read_dirs: Vec<(PathBuf,i64)>,
+    /// the files reused from unchanged directories
+    reused: Vec<(u64,VfsId)>,
+}
+
+/// Only read the directories whose mtime changed since the last scan, reuse the files of the others
+fn incremental_dir_filter(dir: &Path, meta: &Metadata, incremental: &mut IncrementalScan, state: &RwLock<State>, opts: &Opts) -> DirAction {
+    let mtime = meta.mtime() * 1_000_000_000 + meta.mtime_nsec();
+
+    let mut s = state.write();
+    let progress = s.progress.clone();
+
+    if let Some(id) = s.tree.cid(dir) {
+        // mark as still existing, so that it isn't dropped from the parent
+        s.tree[id].valid = true;
+
+        if s.tree[id].dir_mtime == Some(mtime) {
+            if let Some((files,subdirs)) = s.reuse_unchanged_dir(id) {
+                opts.log_verbosed("REUSE", dir);
+
+                for id in files {
+                    let e = &s.tree[id];
+                    progress.found_bytes.fetch_add(e.file_size.unwrap(),Ordering::Relaxed);
+                    progress.found_files.fetch_add(1,Ordering::Relaxed);
+                    incremental.reused.push((e.phys.unwrap(),id));
+                }
+
+                return DirAction::Descend(
+                    subdirs.iter()
+                        .map(|&c| s.tree[c].path.to_path_buf() )
+                        .collect()
+                );
+            }
+        }
+    }
+
+    // a change within the timestamp granularity after the read wouldn't be noticed, so don't record recent mtimes
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_nanos() as i64 );
+    if now - mtime > 1_000_000_000 {
+        incremental.read_dirs.push((dir.to_owned(),mtime));
+    }
+
+    DirAction::Read
+}
+
+/// Sum of the logical length of the compressed/encoded extents
+pub fn encoded_bytes<'a>(extents: impl IntoIterator<Item=&'a FileExtent>) -> u64 {
+    extents.into_iter()
+        .filter(|e| e.is_encoded() )
+        .map(|e| e.length )
+        .sum()
+}
+
+/// Hash the first and last `opts.partial_hash_size` bytes of the read candidates which are big enough, in the order of `i`.
+///
+/// The files whose partial hash is unique are no read candidates anymore
+pub fn partial_hash_files(i: impl Iterator<Item=VfsId>, s: &RwLock<State>, opts: &Opts) -> AnyhowResult<()> {
+    if opts.partial_hash_size == 0 {return Ok(());}
+
+    let block = opts.partial_hash_size;
+
+    let mut buf = vec![0;block as usize];
+    let mut hashed = Vec::new();
+    let errors = s.read().errors.clone();
+    let progress = s.read().progress.clone();
+
+    for id in i {
+        progress.check_interrupted()?;
+
+        let path = {
+            let s = s.read();
+            let e = &s.tree[id];
+            // reading twice the block is about as expensive as hashing the whole file
+            if !s.is_file_read_candidate(id,opts) || opts.zip_by_extension(&e.path) || e.file_size.unwrap() <= block * 2 {continue;}
+            if s.any_hashed_of_size(e.file_size.unwrap()) {continue;}
+            e.path.clone()
+        };
+
+        opts.log_verbosed("PARTIAL", &path);
+
+        let file = try_log!(File::open(&path),errors,opts,ScanErrorKind::Open,&*path,continue);
+        let meta = try_log!(file.metadata(),errors,opts,ScanErrorKind::Metadata,&*path,continue);
+
+        {
+            let s = s.read();
+            if s.tree[id].file_size != Some(meta.len()) || s.tree[id].ctime != Some(meta.ctime()) {
+                errors.push(opts,ScanErrorKind::Comodified,&*path,"skipped");
+                continue;
+            }
+        }
+
+        let mut hasher = xxhash_rust::xxh3::Xxh3::new();
+
+        try_log!(file.read_exact_at(&mut buf, 0),errors,opts,ScanErrorKind::Read,&*path,continue);
+        hasher.update(&buf);
+        try_log!(file.read_exact_at(&mut buf, meta.len() - block),errors,opts,ScanErrorKind::Read,&*path,continue);
+        hasher.update(&buf);
+
+        hashed.push(id);
+        s.write().push_to_partial_hash_group(id, hasher.digest128());
+    }
+
+    // the files which won't be fully hashed anymore don't count to the relevant bytes
+    let mut s = s.write();
+    let progress = s.progress.clone();
+    let mut skipped = 0usize;
+
+    for id in hashed {
+        if !s.is_file_read_candidate(id,opts) {
+            let e = &mut s.tree[id];
+            if e.disp_relevated {
+                progress.relevant_bytes.fetch_sub(e.file_size.unwrap(),Ordering::Relaxed);
+                progress.relevant_files.fetch_sub(1,Ordering::Relaxed);
+                e.disp_relevated = false;
+            }
+            skipped += 1;
+        }
+    }
+
+    dprintln!("Partial hash: {} files skipped",skipped);
+
     Ok(())
 }
 
-pub fn hash_files(i: impl Iterator<Item=VfsId>+Send, s: &'static RwLock<State>, opts: &'static Opts, do_zips: bool) -> AnyhowResult<()> {
+/// Hash the files of rotational devices with the sequential reader, and the ones of the other devices concurrently alongside
+pub fn hash_files_split(sequential: Vec<VfsId>, parallel: Vec<Vec<VfsId>>, s: &RwLock<State>, opts: &Opts, do_zips: bool) -> AnyhowResult<()> {
+    std::thread::scope(|scope| {
+        let parallel = scope.spawn(|| hash_files_parallel(&parallel, s, opts, do_zips) );
+
+        hash_files(sequential.into_iter(), s, opts, do_zips)?;
+
+        parallel.join().unwrap()
+    })
+}
+
+/// Hash the files with `opts.threads` concurrent readers in total, for storage without seek penalty (SSD/NVMe).
+///
+/// `devices` are the files grouped by device, the readers are spread over them
+pub fn hash_files_parallel(devices: &[Vec<VfsId>], s: &RwLock<State>, opts: &Opts, do_zips: bool) -> AnyhowResult<()> {
+    if devices.is_empty() {return Ok(());}
+
+    let queues = devices.iter()
+        .map(|ids| Mutex::new(ids.iter().copied()) )
+        .collect::<Vec<_>>();
+
+    std::thread::scope(|scope| {
+        for n in 0..opts.threads {
+            let queues = &queues;
+            scope.spawn(move || hash_worker(queues, n % queues.len(), s, opts, do_zips) );
+        }
+    });
+
+    // the workers stop early
+    s.read().progress.check_interrupted()
+}
+
+/// Hash the files of the queues, starting with `start` and helping with the next ones once it's empty
+fn hash_worker(queues: &[Mutex<impl Iterator<Item=VfsId>>], start: usize, s: &RwLock<State>, opts: &Opts, do_zips: bool) {
+    let mut buf = vec![0;opts.read_buffer];
+    let progress = s.read().progress.clone();
+    let errors = s.read().errors.clone();
+    // the workers don't take turns reading
+    let mut read_lock = ZeroLock::new(false);
+    let mut current = start;
+    let mut emptied = 0;
+
+    loop {
+        if progress.is_interrupted() {break;}
+
+        let id = match queues[current].lock().next() {
+            Some(v) => v,
+            None => {
+                emptied += 1;
+                if emptied == queues.len() {break;}
+                current = (current + 1) % queues.len();
+                continue;
+            },
+        };
+
+        let p = {
+            let mut s = s.write();
+            if !s.is_file_read_candidate(id,opts) {continue;}
+            s.tree[id].disp_add_relevant(&progress);
+            s.tree[id].path.clone()
+        };
+
+        let mut file = try_log!(File::open(&p),errors,opts,ScanErrorKind::Open,&*p,continue);
+        let meta = try_log!(file.metadata(),errors,opts,ScanErrorKind::Metadata,&*p,continue);
+
+        if !hash_one(id, &meta, &mut file, &mut buf, None, &mut read_lock, s, opts) {continue;}
+
+        if do_zips && opts.zip_by_extension(&p) {
+            let reader = try_log!(File::open(&p),errors,opts,ScanErrorKind::Open,&*p,continue);
+            let reader = BufReader::with_capacity(opts.read_buffer.max(1024*1024),reader);
+            hash_archive(&p, meta.len(), reader, s, opts);
+        }
+    }
+}
+
+pub fn hash_files(i: impl Iterator<Item=VfsId>+Send, s: &RwLock<State>, opts: &Opts, do_zips: bool) -> AnyhowResult<()> {
     #[derive(Clone)]
     struct Reapion {
         path: Arc<Path>,
@@ -200,20 +613,29 @@ pub fn hash_files(i: impl Iterator<Item=VfsId>+Send, s: &'static RwLock<State>,
     let read_mutex = ZeroLock::new(true);
     let read_mutex = &read_mutex;
 
+    let progress = s.read().progress.clone();
+    let progress = &*progress;
+    let errors = s.read().errors.clone();
+    let errors = &*errors;
+
     let pool = rayon::ThreadPoolBuilder::new()
         .num_threads(opts.threads)
         .build()
         .unwrap();
 
     pool.scope(move |pool| {
+        // the paths in the order of the reader results, the reader yields exactly one per file
+        let pending = RefCell::new(VecDeque::new());
+
         let filtered = i.filter_map(|id| {
             let mut s = s.write();
             let do_hash = s.is_file_read_candidate(id,opts);
             let e = &mut s.tree[id];
             if do_hash {
-                e.disp_add_relevant();
+                e.disp_add_relevant(progress);
                 assert!(e.valid);
                 let path = e.path.clone();
+                pending.borrow_mut().push_back(path.clone());
                 Some(Reapion{
                     path,
                     id,
@@ -229,9 +651,7 @@ pub fn hash_files(i: impl Iterator<Item=VfsId>+Send, s: &'static RwLock<State>,
 
         let huge_zip_thres = opts.archive_cache_mem as u64 / opts.threads as u64;
 
-        let read_buffer = opts.read_buffer;
-
-        let mut buf = vec![0;read_buffer];
+        let mut buf = vec![0;opts.read_buffer];
 
         let mut local_read_lock = read_mutex.clone();
 
@@ -240,137 +660,148 @@ pub fn hash_files(i: impl Iterator<Item=VfsId>+Send, s: &'static RwLock<State>,
         local_read_lock.lock();
         //local_read_lock = None;
 
-        'big: loop {
+        loop {
+            if let Err(e) = progress.check_interrupted() {
+                local_read_lock.unlock();
+                return Err(e);
+            }
+
             let budget = cache_watcher.get();
             //dprintln!("Budget: {}",budget);
             reaper.budget(budget);
             match reaper.next() {
                 None => break,
                 Some(Err(e)) => {
-                    dprintln!("\tError {}",e);
+                    let path = pending.borrow_mut().pop_front();
+                    errors.push(opts,ScanErrorKind::Open,path.as_deref(),e);
                 }
                 Some(Ok(mut reader)) => {
+                    pending.borrow_mut().pop_front();
                     let Reapion{path: p,id} = reader.data().clone(); 
 
-                    let size = reader.metadata().size();
-                    let ctime = reader.metadata().ctime();
-
-                    {
-                        let s = s.read();
-                        if s.tree[id].file_size != Some(size) || s.tree[id].ctime != Some(ctime) {
-                            dprintln!("\tSkip comodified file: {}",opts.path_disp(&p));
-                            continue;
-                        }
-                    };
-
-                    opts.log_verbosed("HASH", &p);
-
-                    let mut hasher = blake3::Hasher::new();
+                    let meta = reader.metadata().clone();
+                    let size = meta.size();
 
                     let mut reader = &mut reader;
 
                     if do_zips && opts.zip_by_extension(&p) && size <= huge_zip_thres {
-                        let mut buf = AllocMonBuf::new(size as usize, opts.archive_cache_mem);
-
-                        let mut off = 0;
+                        // keep the archive in memory to decode it without reading it again
+                        let mut zip_buf = AllocMonBuf::new(size as usize, opts.archive_cache_mem);
 
-                        loop {
-                            match reader.read(&mut buf[off..(off+read_buffer).min(size as usize)]) {
-                                Ok(0) => break,
-                                Ok(n) => {
-                                    hasher.update(&buf[off..off+n]);
-                                    off+=n;
-                                    DISP_PROCESSED_BYTES.fetch_add(n as u64,Ordering::Relaxed);
-                                },
-                                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
-                                Err(e) if e.kind() == ErrorKind::Interrupted => {}
-                                Err(e) => {
-                                    dprintln!("\tFailed to read {}",e);
-                                    continue 'big;
-                                },
-                            }
-                        }
-                        local_read_lock.unlock();
-                        assert_eq!(off,size as usize);
-
-                        let p = p.clone();
-                        pool.spawn(move |_| {
-                            let buf: AllocMonBuf = buf;
-                            let r = try_return!(open_zip(Cursor::new(&buf[..size as usize]),&p,s,opts),"\tFailed to open ZIP: {} ({})",opts.path_disp(&p));
-                            try_return!(decode_zip(r,&p,s,opts),"\tFailed to read ZIP: {} ({})",opts.path_disp(&p));
-                        });
-                    }else{
-                        if do_zips && opts.zip_by_extension(&p) {
-                            DISP_RELEVANT_BYTES.fetch_add(size,Ordering::Relaxed);
-                            DISP_RELEVANT_FILES.fetch_add(1,Ordering::Relaxed);
-                            let p = p.clone();
+                        if hash_one(id, &meta, &mut reader, &mut buf, Some(&mut zip_buf[..size as usize]), &mut local_read_lock, s, opts) {
                             pool.spawn(move |_| {
-                                let (size,path) = {
-                                    let s = s.read();
-                                    s.eventually_store_vfs(&opts.cache_path, false);
-                                    let e = &s.tree[id];
-                                    ( e.file_size.unwrap(), e.path.clone() )
-                                };
-                                let reader = try_return!(File::open(&path),"\tFailed to open file for big zip read: {} ({})",opts.path_disp(&p));
-                                let reader = MutexedReader{inner: reader,mutex: read_mutex.clone()};
-                                let reader = BufReader::with_capacity(64*1024*1024,reader);
-                        
-                                let r = try_return!(open_zip(reader,&path,s,opts),"\tFailed to open ZIP: {} ({})",opts.path_disp(&p));
-                                try_return!(decode_zip(r,&path,s,opts),"\tFailed to read ZIP: {} ({})",opts.path_disp(&p));
-                        
-                                DISP_PROCESSED_BYTES.fetch_add(size,Ordering::Relaxed);
-                                DISP_PROCESSED_FILES.fetch_add(1,Ordering::Relaxed);
+                                let buf: AllocMonBuf = zip_buf;
+                                let Ok(r) = open_zip(Cursor::new(&buf[..size as usize]),&p,s,opts) else {return};
+                                let _ = decode_zip(r,&p,s,opts);
                             });
                         }
-                        loop {
-                            match reader.read(&mut buf[0..read_buffer]) {
-                                Ok(0) => break,
-                                Ok(n) => {
-                                    hasher.update(&buf[..n]);
-                                    DISP_PROCESSED_BYTES.fetch_add(n as u64,Ordering::Relaxed);
-                                },
-                                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
-                                Err(e) if e.kind() == ErrorKind::Interrupted => {}
-                                Err(e) => {
-                                    dprintln!("\tFailed to read {}",e);
-                                    continue 'big;
-                                },
-                            }
-                        }
-                        local_read_lock.unlock();
+                    }else if hash_one(id, &meta, &mut reader, &mut buf, None, &mut local_read_lock, s, opts) && do_zips && opts.zip_by_extension(&p) {
+                        pool.spawn(move |_| {
+                            let reader = try_log!(File::open(&p),errors,opts,ScanErrorKind::Open,&*p,return);
+                            let reader = MutexedReader{inner: reader,mutex: read_mutex.clone()};
+                            let reader = BufReader::with_capacity(64*1024*1024,reader);
+                            hash_archive(&p, size, reader, s, opts);
+                        });
                     }
-                    local_read_lock.unlock();
 
-                    let hash = Arc::new(hasher.finalize().into());
+                    local_read_lock.lock();
+                }
+            }
+        }
+        local_read_lock.unlock();
+        Ok(())
+    })
+}
 
-                    let mut s = s.write();
+/// Hash the opened file `id` and record the hash, unless it was modified since the size pass.
+///
+/// The data is read into `keep` if given, e.g. to decode an archive from memory afterwards, otherwise into `buf`.
+/// `read_lock` is released after the read. Returns false if the file was skipped, or the read failed or was interrupted
+#[allow(clippy::too_many_arguments)]
+fn hash_one(id: VfsId, meta: &Metadata, reader: &mut impl Read, buf: &mut [u8], mut keep: Option<&mut [u8]>, read_lock: &mut ZeroLock, s: &RwLock<State>, opts: &Opts) -> bool {
+    let (path,progress,errors) = {
+        let s = s.read();
+        let e = &s.tree[id];
+        if e.file_size != Some(meta.len()) || e.ctime != Some(meta.ctime()) {
+            s.errors.push(opts,ScanErrorKind::Comodified,&*e.path,"skipped");
+            return false;
+        }
+        (e.path.clone(),s.progress.clone(),s.errors.clone())
+    };
 
-                    let entry = &mut s.tree[id];
+    opts.log_verbosed("HASH", &path);
 
-                    entry.file_hash = Some(hash);
+    let mut hasher = opts.hash_algorithm.hasher();
+    let mut off = 0;
 
-                    if opts.zip_by_extension(&p) {
-                        entry.is_dir = true;
-                    }
+    loop {
+        let dest = match keep.as_deref_mut() {
+            Some(keep) => {
+                let end = (off+buf.len()).min(keep.len());
+                &mut keep[off..end]
+            },
+            None => &mut *buf,
+        };
+        match reader.read(dest) {
+            Ok(0) => break,
+            Ok(n) => {
+                hasher.update(&dest[..n]);
+                off += n;
+                progress.processed_bytes.fetch_add(n as u64,Ordering::Relaxed);
+                if progress.is_interrupted() {return false;}
+            },
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => {
+                errors.push(opts,ScanErrorKind::Read,&*path,e);
+                return false;
+            },
+        }
+    }
+    read_lock.unlock();
 
-                    //let size = entry.file_size.unwrap() as usize;
+    if let Some(keep) = keep {
+        assert_eq!(off,keep.len());
+    }
 
-                    s.push_to_hash_group(id,true,false).unwrap();
+    let hash = hasher.finalize();
 
-                    //state.disp_pass_2_processed_bytes_capped += size.max(1024*1024);
-                    DISP_PROCESSED_FILES.fetch_add(1,Ordering::Relaxed);
+    let mut s = s.write();
 
-                    s.eventually_store_vfs(&opts.cache_path, false);
+    let entry = &mut s.tree[id];
 
-                    drop(s);
+    entry.file_hash = Some(hash);
 
-                    local_read_lock.lock();
-                }
-            }
-        }
-        local_read_lock.unlock();
-        Ok(())
-    })
+    if opts.zip_by_extension(&path) {
+        entry.is_dir = true;
+    }
+
+    s.push_to_hash_group(id,true,false).unwrap();
+
+    progress.processed_files.fetch_add(1,Ordering::Relaxed);
+
+    if let Some(c) = &mut s.checkpoint {
+        c.hashed(id);
+    }
+
+    s.eventually_store_vfs(&opts.cache_path, false);
+
+    true
+}
+
+/// Decode the archive at `path` from `reader`, counted as another file of the hash pass
+fn hash_archive<R: Read+Seek>(path: &Path, size: u64, reader: R, s: &RwLock<State>, opts: &Opts) {
+    let progress = s.read().progress.clone();
+
+    progress.relevant_bytes.fetch_add(size,Ordering::Relaxed);
+    progress.relevant_files.fetch_add(1,Ordering::Relaxed);
+
+    // the archive errors are logged by open_zip and decode_zip
+    let Ok(r) = open_zip(reader,path,s,opts) else {return};
+    let Ok(()) = decode_zip(r,path,s,opts) else {return};
+
+    progress.processed_bytes.fetch_add(size,Ordering::Relaxed);
+    progress.processed_files.fetch_add(1,Ordering::Relaxed);
 }
 
 #[macro_export]
@@ -432,3 +863,100 @@ macro_rules! try_returnerr {
         }
     };
 }
+
+/// Size and hash the files like a scan, for the tests
+#[cfg(test)]
+pub fn hashed(paths: &[PathBuf], opts: &Opts) -> (State,Vec<VfsId>) {
+    let s = RwLock::new(State::new(false, hasher::HashAlgorithm::Blake3));
+    let ids = paths.iter()
+        .map(|path| {
+            let meta = std::fs::metadata(path).unwrap();
+            size_file(path, &meta, 0, 1, 0, &mut Vec::new(), &mut Vec::new(), &mut s.write(), opts).unwrap().unwrap()
+        })
+        .collect::<Vec<_>>();
+
+    hash_files(ids.iter().copied(), &s, opts, false).unwrap();
+
+    (s.into_inner(),ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hasher::HashAlgorithm;
+
+    const BLOCK: u64 = 4096;
+
+    fn scan(paths: &[PathBuf], s: &RwLock<State>, opts: &Opts) -> Vec<VfsId> {
+        let mut dest = Vec::new();
+        for path in paths {
+            let meta = std::fs::metadata(path).unwrap();
+            size_file(path, &meta, 0, 1, 0, &mut dest, &mut Vec::new(), &mut s.write(), opts).unwrap();
+        }
+        dest.into_iter().map(|(_,id)| id ).collect()
+    }
+
+    fn opts() -> Opts {
+        Opts{read_archives: true, partial_hash_size: BLOCK, ..Default::default()}
+    }
+
+    /// `data` with a different first byte
+    fn other(data: &[u8], b: u8) -> Vec<u8> {
+        let mut data = data.to_owned();
+        data[0] = b;
+        data
+    }
+
+    #[test]
+    fn unique_partial_hashes_are_skipped() {
+        let dir = TestDir::new("partial");
+        let opts = opts();
+        let data = vec![7u8; 4*BLOCK as usize];
+        let paths = [
+            dir.file("a", &data),
+            dir.file("b", &data),
+            dir.file("c", &other(&data,1)),
+        ];
+        let s = RwLock::new(State::new(false, HashAlgorithm::Blake3));
+        let ids = scan(&paths, &s, &opts);
+
+        partial_hash_files(ids.iter().copied(), &s, &opts).unwrap();
+
+        let s = s.read();
+        assert!(s.is_file_read_candidate(ids[0],&opts));
+        assert!(s.is_file_read_candidate(ids[1],&opts));
+        assert!(!s.is_file_read_candidate(ids[2],&opts));
+    }
+
+    #[test]
+    fn files_without_partial_hash_keep_their_size_group() {
+        let dir = TestDir::new("partial");
+        let opts = opts();
+        let data = vec![7u8; 4*BLOCK as usize];
+        // the archive is hashed without partial hash, its renamed copy has a unique partial hash
+        let paths = [
+            dir.file("a.zip", &data),
+            dir.file("a.bin", &data),
+            dir.file("b.bin", &other(&data,1)),
+        ];
+        let s = RwLock::new(State::new(false, HashAlgorithm::Blake3));
+        let ids = scan(&paths, &s, &opts);
+
+        partial_hash_files(ids.iter().copied(), &s, &opts).unwrap();
+
+        assert!(ids.iter().all(|&id| s.read().is_file_read_candidate(id,&opts) ));
+
+        // and a file which fails to read
+        let data = vec![7u8; 5*BLOCK as usize];
+        let paths = [
+            dir.file("d", &data),
+            dir.file("e", &other(&data,1)),
+        ];
+        let ids = scan(&paths, &s, &opts);
+        std::fs::remove_file(&paths[0]).unwrap();
+
+        partial_hash_files(ids.iter().copied(), &s, &opts).unwrap();
+
+        assert!(s.read().is_file_read_candidate(ids[1],&opts));
+    }
+}
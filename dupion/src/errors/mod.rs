@@ -0,0 +1,190 @@
+use super::*;
+use std::{fmt::Display, fs::File, io::{BufWriter, Write}, path::{Path, PathBuf}};
+use parking_lot::Mutex;
+use serde_derive::Serialize;
+use opts::Opts;
+use phase::Phase;
+
+#[derive(Serialize,Clone,Copy,PartialEq,Eq,Hash,Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanErrorKind {
+    Open,
+    Metadata,
+    ReadDir,
+    Read,
+    /// Modified between the size pass and the read
+    Comodified,
+    /// Unreadable or corrupt archive, its contents are missing
+    Archive,
+    Dedup,
+    Verify,
+    Quarantine,
+}
+
+impl ScanErrorKind {
+    pub fn title(self) -> &'static str {
+        match self {
+            Self::Open => "Failed to open",
+            Self::Metadata => "Failed to read metadata",
+            Self::ReadDir => "Failed to read directory",
+            Self::Read => "Failed to read",
+            Self::Comodified => "Modified during the scan",
+            Self::Archive => "Failed to read archive",
+            Self::Dedup => "Failed to dedup",
+            Self::Verify => "Failed to verify",
+            Self::Quarantine => "Failed to quarantine",
+        }
+    }
+}
+
+/// Errors which can report the errno they came from
+pub trait LoggedError: Display {
+    fn os_error(&self) -> Option<i32> {
+        None
+    }
+}
+
+impl LoggedError for std::io::Error {
+    fn os_error(&self) -> Option<i32> {
+        self.raw_os_error()
+    }
+}
+
+impl LoggedError for anyhow::Error {
+    fn os_error(&self) -> Option<i32> {
+        self.chain().find_map(|e| e.downcast_ref::<std::io::Error>()?.raw_os_error() )
+    }
+}
+
+impl LoggedError for String {}
+impl LoggedError for str {}
+
+impl<E> LoggedError for &E where E: LoggedError + ?Sized {
+    fn os_error(&self) -> Option<i32> {
+        (**self).os_error()
+    }
+}
+
+#[derive(Serialize,Clone,Debug)]
+pub struct ScanError {
+    pub kind: ScanErrorKind,
+    pub path: Option<PathBuf>,
+    /// None after the scan, e.g. in the quarantine
+    #[serde(serialize_with = "serialize_phase")]
+    pub phase: Option<Phase>,
+    /// errno
+    pub os_error: Option<i32>,
+    pub message: String,
+}
+
+fn serialize_phase<S>(phase: &Option<Phase>, s: S) -> Result<S::Ok,S::Error> where S: serde::Serializer {
+    match phase {
+        Some(p) => s.serialize_some(p.name()),
+        None => s.serialize_none(),
+    }
+}
+
+/// The errors of a scan, owned by its State
+#[derive(Default)]
+pub struct ErrorLog {
+    errors: Mutex<Vec<ScanError>>,
+    phase: Mutex<Option<Phase>>,
+}
+
+impl ErrorLog {
+    /// The phase of the errors pushed from now on
+    pub fn set_phase(&self, phase: Option<Phase>) {
+        *self.phase.lock() = phase;
+    }
+
+    /// Record an error, printed right away only if verbose
+    pub fn push<'a>(&self, opts: &Opts, kind: ScanErrorKind, path: impl Into<Option<&'a Path>>, error: impl LoggedError) {
+        let path = path.into();
+
+        if opts.verbose {
+            match path {
+                Some(p) => dprintln!("\t{}: {} ({})",kind.title(),error,opts.path_disp(p)),
+                None => dprintln!("\t{}: {}",kind.title(),error),
+            }
+        }
+
+        let error = ScanError {
+            kind,
+            path: path.map(Path::to_owned),
+            phase: *self.phase.lock(),
+            os_error: error.os_error(),
+            message: error.to_string(),
+        };
+
+        self.errors.lock().push(error);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.lock().is_empty()
+    }
+
+    pub fn errors(&self) -> Vec<ScanError> {
+        self.errors.lock().clone()
+    }
+
+    /// Print the number of errors per kind, with the first paths of each unless verbose, where they were already printed
+    pub fn print_summary(&self, opts: &Opts) {
+        let errors = self.errors.lock();
+
+        let mut kinds = Vec::<(ScanErrorKind,Vec<&ScanError>)>::new();
+        for e in errors.iter() {
+            match kinds.iter_mut().find(|(k,_)| *k == e.kind ) {
+                Some((_,v)) => v.push(e),
+                None => kinds.push((e.kind,vec![e])),
+            }
+        }
+
+        for (kind,errors) in kinds {
+            eprintln!("{}: {}",kind.title(),errors.len());
+
+            if opts.verbose {continue;}
+
+            for e in errors.iter().take(SUMMARY_PATHS) {
+                match &e.path {
+                    Some(p) => eprintln!("\t{} ({})",opts.path_disp(p),e.message),
+                    None => eprintln!("\t{}",e.message),
+                }
+            }
+            if errors.len() > SUMMARY_PATHS {
+                eprintln!("\t... {} more",errors.len() - SUMMARY_PATHS);
+            }
+        }
+    }
+
+    /// Write all errors as JSON lines
+    pub fn write_json(&self, path: &Path) -> AnyhowResult<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        for e in self.errors.lock().iter() {
+            serde_json::to_writer(&mut writer, e)?;
+            writer.write_all(b"\n")?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Errors per kind listed in the summary
+const SUMMARY_PATHS: usize = 10;
+
+/// Like try_continue!, but records the error in the ErrorLog. `$or` is e.g. `continue` or `return`
+#[macro_export]
+macro_rules! try_log {
+    ($oof:expr,$errors:expr,$opts:expr,$kind:expr,$path:expr,$or:expr) => {
+        match $oof {
+            Ok(f) => {
+                f
+            },
+            Err(e) => {
+                $errors.push($opts,$kind,$path,e);
+                $or
+            },
+        }
+    };
+}
@@ -1,5 +1,5 @@
 use super::*;
-use util::{Size, Hash};
+use util::{Size, Hash, PartialHash};
 use vfs::{entry::VfsEntryType, VfsId};
 
 #[derive(Clone)]
@@ -8,6 +8,13 @@ pub struct SizeGroup {
     pub size: Size,
 }
 
+#[derive(Clone)]
+pub struct PartialHashGroup {
+    pub entries: Vec<VfsId>,
+    pub size: Size,
+    pub partial_hash: PartialHash,
+}
+
 #[derive(Clone)]
 pub struct HashGroup {
     pub entries: Vec<(VfsEntryType,VfsId)>,
@@ -0,0 +1,85 @@
+use super::*;
+use std::sync::Arc;
+use sha2::Digest;
+use util::{Hash, HASH_SIZE};
+
+/// Algorithm of the file hashes
+#[derive(Clone,Copy,PartialEq,Debug)]
+pub enum HashAlgorithm {
+    Blake3,
+    /// xxh3-128, fast but not cryptographic
+    Xxh3,
+    /// for digests matching the ones of other tools
+    Sha256,
+}
+
+impl HashAlgorithm {
+    /// Id stored in the cache header and the machine outputs
+    pub fn id(self) -> &'static str {
+        match self {
+            Self::Blake3 => "blake3",
+            Self::Xxh3 => "xxh3-128",
+            Self::Sha256 => "sha256",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Option<Self> {
+        [Self::Blake3, Self::Xxh3, Self::Sha256].into_iter()
+            .find(|a| a.id() == id )
+    }
+
+    /// Length of the digest. Shorter digests are zero padded to HASH_SIZE
+    pub fn digest_len(self) -> usize {
+        match self {
+            Self::Blake3 | Self::Sha256 => 32,
+            Self::Xxh3 => 16,
+        }
+    }
+
+    /// The digest without padding
+    pub fn digest(self, h: &Hash) -> &[u8] {
+        &h[..self.digest_len()]
+    }
+
+    /// Lowercase hex of the digest, like sha256sum and b3sum print it
+    pub fn digest_hex(self, h: &Hash) -> String {
+        self.digest(h).iter()
+            .map(|b| format!("{:02x}",b) )
+            .collect()
+    }
+
+    pub fn hasher(self) -> FileHasher {
+        match self {
+            Self::Blake3 => FileHasher::Blake3(Box::new(blake3::Hasher::new())),
+            Self::Xxh3 => FileHasher::Xxh3(Box::new(xxhash_rust::xxh3::Xxh3::new())),
+            Self::Sha256 => FileHasher::Sha256(sha2::Sha256::new()),
+        }
+    }
+}
+
+/// Incremental hasher of the selected algorithm
+pub enum FileHasher {
+    Blake3(Box<blake3::Hasher>),
+    Xxh3(Box<xxhash_rust::xxh3::Xxh3>),
+    Sha256(sha2::Sha256),
+}
+
+impl FileHasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Blake3(h) => {h.update(data);},
+            Self::Xxh3(h) => h.update(data),
+            Self::Sha256(h) => h.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> Hash {
+        let mut hash = [0u8;HASH_SIZE];
+        match self {
+            Self::Blake3(h) => hash = h.finalize().into(),
+            Self::Xxh3(h) => hash[..16].copy_from_slice(&h.digest128().to_be_bytes()),
+            Self::Sha256(h) => hash = h.finalize().into(),
+        }
+        Arc::new(hash)
+    }
+}
@@ -0,0 +1,307 @@
+use super::*;
+use std::{fs::{File, OpenOptions}, io::{BufRead, BufReader, BufWriter, Write}, os::unix::fs::MetadataExt, path::{Path, PathBuf}, time::SystemTime};
+use serde_derive::{Serialize, Deserialize};
+use size::SizeDisp;
+use state::State;
+use vfs::{VfsId, deser::encode_hash_base64};
+
+/// Append-only record of the modifications done to the scanned files, as JSON lines
+pub struct Journal {
+    writer: BufWriter<File>,
+}
+
+#[derive(Serialize,Deserialize,Clone,Copy,PartialEq,Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum JournalAction {
+    /// Extents shared with ioctl_file_dedupe_range
+    Dedup,
+    /// Extents shared with ioctl_ficlonerange
+    Clone,
+    /// File moved into the quarantine dir
+    Quarantine,
+}
+
+#[derive(Serialize,Deserialize)]
+pub struct JournalEntry {
+    /// unix time in seconds
+    pub time: u64,
+    pub action: JournalAction,
+    /// the modified file
+    pub path: PathBuf,
+    /// the file the data was taken from, or the kept copy
+    pub source: Option<PathBuf>,
+    /// ctime of the kept copy, purge checks that it's unmodified
+    #[serde(default)]
+    pub source_ctime: Option<i64>,
+    /// new path of the file, if moved
+    #[serde(default)]
+    pub moved_to: Option<PathBuf>,
+    pub size: u64,
+    pub range_start: u64,
+    pub range_end: u64,
+    /// base64 encoded
+    pub hash: Option<String>,
+    /// HashAlgorithm::id of the hash
+    #[serde(default)]
+    pub hash_algorithm: Option<String>,
+    /// hex encoded hash, without padding
+    #[serde(default)]
+    pub digest: Option<String>,
+    pub bytes: u64,
+    /// extent info of the file before the action
+    pub prior_phys: Option<u64>,
+    pub prior_n_extends: Option<usize>,
+    pub prior_encoded_bytes: u64,
+}
+
+impl JournalEntry {
+    /// Entry with the current attributes of `id`
+    pub fn new(action: JournalAction, id: VfsId, state: &State) -> Self {
+        let e = &state.tree[id];
+        Self {
+            time: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs() ),
+            action,
+            path: e.path.to_path_buf(),
+            source: None,
+            source_ctime: None,
+            moved_to: None,
+            size: e.file_size.unwrap_or(0),
+            range_start: 0,
+            range_end: e.file_size.unwrap_or(0),
+            hash: e.file_hash.as_ref().map(encode_hash_base64),
+            hash_algorithm: e.file_hash.as_ref().map(|_| state.hash_algorithm.id().to_owned() ),
+            digest: e.file_hash.as_ref().map(|h| state.hash_algorithm.digest_hex(h) ),
+            bytes: 0,
+            prior_phys: e.phys,
+            prior_n_extends: e.n_extends,
+            prior_encoded_bytes: e.encoded_blocks as u64 * util::ENCODED_BLOCK_SIZE,
+        }
+    }
+
+    pub fn reversible(&self) -> bool {
+        match self.action {
+            JournalAction::Dedup | JournalAction::Clone => false,
+            JournalAction::Quarantine => true,
+        }
+    }
+
+    pub fn undo(&self) -> AnyhowResult<()> {
+        match self.action {
+            JournalAction::Dedup | JournalAction::Clone => bail!("Shared extents can't be unshared"),
+            JournalAction::Quarantine => {
+                let moved_to = self.moved_to.as_deref().ok_or_else(|| anyhow::anyhow!("No quarantine path") )?;
+                ensure!(self.path.symlink_metadata().is_err(),"Original path exists");
+                if let Some(parent) = self.path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::rename(moved_to, &self.path)?;
+                Ok(())
+            },
+        }
+    }
+
+    /// Finally delete what was moved away, if the kept copy is still there unmodified
+    pub fn purge(&self) -> AnyhowResult<()> {
+        match self.action {
+            JournalAction::Quarantine => {
+                let moved_to = self.moved_to.as_deref().ok_or_else(|| anyhow::anyhow!("No quarantine path") )?;
+                let source = self.source.as_deref().ok_or_else(|| anyhow::anyhow!("No kept copy recorded") )?;
+                let meta = source.symlink_metadata()
+                    .map_err(|e| anyhow::anyhow!("Kept copy {}: {}",source.to_string_lossy(),e) )?;
+                ensure!(meta.is_file() && meta.len() == self.size, "Kept copy {} changed",source.to_string_lossy());
+                ensure!(self.source_ctime.map_or(true, |c| c == meta.ctime() ), "Kept copy {} modified",source.to_string_lossy());
+                std::fs::remove_file(moved_to)?;
+                Ok(())
+            },
+            _ => bail!("Nothing to purge"),
+        }
+    }
+}
+
+impl Journal {
+    pub fn open(path: &Path) -> AnyhowResult<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+
+        Ok(Self {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    pub fn append(&mut self, entry: &JournalEntry) -> AnyhowResult<()> {
+        serde_json::to_writer(&mut self.writer, entry)?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    /// should be called after every batch, so that the journal is complete if interrupted
+    pub fn flush(&mut self) -> AnyhowResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+pub fn read_journal(path: &Path) -> AnyhowResult<Vec<JournalEntry>> {
+    let reader = BufReader::new(File::open(path)?);
+
+    let mut entries = Vec::new();
+
+    for (i,line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {continue;}
+        match serde_json::from_str(&line) {
+            Ok(e) => entries.push(e),
+            Err(e) => dprintln!("\tError reading journal line {}: {}",i+1,e),
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Print the journaled actions, and undo the reversible ones in reverse order if `undo`, or purge the moved files if `purge`
+pub fn report_journal(path: &Path, undo: bool, purge: bool) -> AnyhowResult<()> {
+    let entries = read_journal(path)?;
+
+    let mut count = [0usize;2];
+    let mut bytes = [0u64;2];
+
+    for e in &entries {
+        println!(
+            "{} {:?} {} {}{}",
+            e.time,
+            e.action,
+            SizeDisp(e.bytes),
+            e.path.to_string_lossy(),
+            e.source.as_ref().map_or(String::new(), |s| format!(" <- {}",s.to_string_lossy()) ),
+        );
+        if let Some(moved_to) = &e.moved_to {
+            println!("\tmoved to {}",moved_to.to_string_lossy());
+        }
+        let idx = e.reversible() as usize;
+        count[idx] += 1;
+        bytes[idx] += e.bytes;
+    }
+
+    eprintln!(
+        "\n{} actions, {} reversible ({}), {} irreversible ({})",
+        entries.len(),
+        count[1],
+        SizeDisp(bytes[1]),
+        count[0],
+        SizeDisp(bytes[0]),
+    );
+
+    if undo {
+        let mut undone = 0usize;
+
+        for e in entries.iter().rev().filter(|e| e.reversible() ) {
+            match e.undo() {
+                Ok(()) => undone += 1,
+                Err(err) => dprintln!("\tError undoing {:?}: {} ({})",e.action,err,e.path.to_string_lossy()),
+            }
+        }
+
+        eprintln!("Undone {} actions",undone);
+    } else if purge {
+        let mut purged = 0usize;
+
+        for e in entries.iter().filter(|e| e.moved_to.is_some() ) {
+            match e.purge() {
+                Ok(()) => purged += 1,
+                Err(err) => dprintln!("\tError purging {:?}: {} ({})",e.action,err,e.path.to_string_lossy()),
+            }
+        }
+
+        eprintln!("Purged {} files",purged);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use util::TestDir;
+
+    /// `path` moved to `moved_to`, with `source` as the kept copy
+    fn quarantined(path: &Path, source: &Path, moved_to: &Path) -> JournalEntry {
+        let meta = source.metadata().unwrap();
+        std::fs::rename(path, moved_to).unwrap();
+        JournalEntry {
+            time: 0,
+            action: JournalAction::Quarantine,
+            path: path.to_owned(),
+            source: Some(source.to_owned()),
+            source_ctime: Some(meta.ctime()),
+            moved_to: Some(moved_to.to_owned()),
+            size: meta.len(),
+            range_start: 0,
+            range_end: meta.len(),
+            hash: None,
+            hash_algorithm: None,
+            digest: None,
+            bytes: meta.len(),
+            prior_phys: None,
+            prior_n_extends: None,
+            prior_encoded_bytes: 0,
+        }
+    }
+
+    #[test]
+    fn undo_restores_the_files() {
+        let dir = TestDir::new("journal");
+        let (a,b) = (dir.file("a", b"data"), dir.file("sub/b", b"data"));
+        let e = quarantined(&b, &a, &dir.file("moved", b""));
+
+        let path = dir.0.join("journal.jsonl");
+        let mut journal = Journal::open(&path).unwrap();
+        journal.append(&e).unwrap();
+        journal.flush().unwrap();
+        std::fs::remove_dir(b.parent().unwrap()).unwrap();
+
+        report_journal(&path, true, false).unwrap();
+
+        assert_eq!(std::fs::read(&b).unwrap(), b"data");
+        assert!(e.moved_to.as_ref().unwrap().symlink_metadata().is_err());
+
+        // the original path is taken now
+        assert!(e.undo().is_err());
+    }
+
+    #[test]
+    fn purge_deletes_the_moved_file() {
+        let dir = TestDir::new("journal");
+        let (a,b) = (dir.file("a", b"data"), dir.file("b", b"data"));
+        let e = quarantined(&b, &a, &dir.file("moved", b""));
+
+        e.purge().unwrap();
+
+        assert!(e.moved_to.as_ref().unwrap().symlink_metadata().is_err());
+        assert!(a.exists());
+    }
+
+    #[test]
+    fn purge_refuses_a_changed_kept_copy() {
+        let dir = TestDir::new("journal");
+        let (a,b) = (dir.file("a", b"data"), dir.file("b", b"data"));
+        let mut e = quarantined(&b, &a, &dir.file("moved", b""));
+        let moved_to = e.moved_to.clone().unwrap();
+
+        // another ctime
+        e.source_ctime = e.source_ctime.map(|c| c - 1 );
+        assert!(e.purge().unwrap_err().to_string().contains("modified"));
+
+        // another size
+        std::fs::write(&a, b"other data").unwrap();
+        e.source_ctime = Some(a.metadata().unwrap().ctime());
+        assert!(e.purge().unwrap_err().to_string().contains("changed"));
+
+        // gone
+        std::fs::remove_file(&a).unwrap();
+        assert!(e.purge().is_err());
+
+        assert_eq!(std::fs::read(&moved_to).unwrap(), b"data");
+    }
+}
@@ -1,38 +1,83 @@
-use dupion::{state::State, opts::Opts, driver::{Driver, platterwalker::PlatterWalker}, phase::Phase, process::{export, calculate_dir_hash, find_shadowed}, util::*, vfs::VfsId, zip::setlocale_hack, output::{tree::print_tree, groups::print_groups, treediff::print_treediff}, dedup::{Deduper, btrfs::BtrfsDedup}, print_statw, stat_section_start, stat_section_end};
-use std::{io::{stderr, IsTerminal as _}, path::PathBuf, sync::atomic::Ordering, time::Duration};
+use dupion::{state::State, opts::Opts, driver::{Driver, platterwalker::PlatterWalker, btrfsimage::BtrfsImageWalker}, phase::Phase, process::{export, calculate_dir_hash, find_shadowed}, util::*, vfs::VfsId, zip::setlocale_hack, output::{tree::print_tree, groups::print_groups, treediff::print_treediff}, dedup::{Deduper, btrfs::BtrfsDedup, fs::DedupMechanism}, journal::{Journal, report_journal}, quarantine::quarantine, watch::watch, subvol::SnapshotMode, hasher::HashAlgorithm, storage::StorageMode, progress::{AnsiProgress, JsonProgress}, config::{config_settings, settings_args}, vfs::rootcache::{RootCacheMode, root_caches, default_cache_path}, size::{parse_size_bytes, parse_size_kib, parse_size_mib, round_to_pages}, };
+use std::{ffi::OsString, fs::File, io::{stderr, IsTerminal as _}, os::unix::io::FromRawFd, path::PathBuf, sync::{atomic::Ordering, Arc}, time::Duration};
 use parking_lot::RwLock;
-use clap::{Parser, ValueEnum};
+use platter_walk::Order;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 
 use dupion::dprintln;
 
 fn main() {
     setlocale_hack();
 
-    DISP_ANSI.store(stderr().is_terminal(), Ordering::Relaxed);
+    let (o,matches) = parse_args();
 
-    let o = OptInput::parse();
+    if o.print_config {
+        print_config(&matches);
+        return;
+    }
+
+
+    if let Some(SubCommand::Journal{path,undo,purge}) = &o.command {
+        report_journal(path, *undo, *purge).unwrap();
+        return;
+    }
 
-    let opts = Box::leak(Box::new(Opts{
+    let mut opts = Opts{
         paths: o.dirs.clone(),
-        cache_path: o.cache_path.clone(),
+        cache_path: o.cache_path.clone().unwrap_or_else(default_cache_path),
+        root_cache: match o.root_cache {
+            RootCacheArg::Off => RootCacheMode::Off,
+            RootCacheArg::Root => RootCacheMode::Root,
+            RootCacheArg::Uuid => RootCacheMode::Uuid,
+        },
         verbose: o.verbose,
         shadow_rule: o.shadow_rule,
         force_absolute_paths: o.absolute,
-        read_buffer:       ((o.read_buffer * 1048576.0) as usize +1024)/4096*4096,
-        prefetch_budget: ((o.prefetch_budget * 1048576.0) as u64 +1024)/4096*4096,
-        dedup_budget: ((o.dedup_budget * 1048576.0) as u64 +1024)/4096*4096,
+        read_buffer:       round_to_pages(o.read_buffer) as usize,
+        prefetch_budget: round_to_pages(o.prefetch_budget),
+        dedup_budget: round_to_pages(o.dedup_budget),
         cache_dropbehind: o.cache_dropbehind,
         pass_1_hash: o.pass_1_hash,
-        archive_cache_mem: ((o.archive_cache_mem * 1048576.0) as usize +1024)/4096*4096,
+        archive_cache_mem: round_to_pages(o.archive_cache_mem) as usize,
         dir_prefetch: o.dir_prefetch,
+        walk_order: match o.order {
+            OrderArg::Content => Order::Content,
+            OrderArg::Inode => Order::Inode,
+            OrderArg::DepthFirst => Order::DepthFirst,
+            OrderArg::BreadthFirst => Order::BreadthFirst,
+        },
+        incremental: o.incremental,
+        btrfs_incremental: o.btrfs_incremental,
+        resume: o.resume,
+        snapshots: match o.snapshots {
+            SnapshotsMode::All => SnapshotMode::All,
+            SnapshotsMode::SkipReadonly => SnapshotMode::SkipReadonly,
+            SnapshotsMode::Reference => SnapshotMode::Reference,
+        },
         read_archives: o.read_archives,
+        partial_hash_size: o.partial_hash_size,
+        hash_algorithm: match o.hash {
+            HashMode::Blake3 => HashAlgorithm::Blake3,
+            HashMode::Xxh3 => HashAlgorithm::Xxh3,
+            HashMode::Sha256 => HashAlgorithm::Sha256,
+        },
         //huge_zip_thres: ((o.huge_zip_thres * 1048576.0) as usize +1024)/4096*4096,
         threads: o.threads,
+        storage: match o.storage {
+            StorageArg::Auto => StorageMode::Auto,
+            StorageArg::Hdd => StorageMode::Rotational,
+            StorageArg::Ssd => StorageMode::NonRotational,
+        },
         scan_size_min: o.min_size,
-        scan_size_max: o.max_size,
+        scan_size_max: o.max_size.unwrap_or(u64::MAX),
         aggressive_dedup: o.aggressive_dedup,
         dedup_simulate: o.dedup_simulate,
-    }));
+        dedup_defrag: o.dedup_defrag,
+        // cloning overwrites the dups without comparing them
+        verify: o.verify || matches!(o.dedup, Some(DedupMode::Clone)),
+        journal_path: o.journal.clone(),
+        quarantine_path: o.quarantine.clone(),
+    };
 
     if opts.paths.is_empty() {
         opts.paths = vec![std::env::current_dir().unwrap()];
@@ -43,82 +88,239 @@ fn main() {
 
     opts.validate().unwrap();
 
-    let state = Box::leak(Box::new(RwLock::new(State::new(!o.no_cache))));
+    let opts = &opts;
+    let state = &RwLock::new(State::new(!o.no_cache, opts.hash_algorithm));
+
+    setup_progress(&o, &state.read().progress);
+    spawn_info_thread(state.read().progress.clone());
 
     if !o.bench_pass_1 {
+        state.write().root_caches = root_caches(opts);
         state.write().eventually_load_vfs(&opts.cache_path);
     }
 
-    if !o.no_scan {
-        scan(&o, opts, state);
+    if o.btrfs_image {
+        scan::<BtrfsImageWalker>(&o, opts, state);
+    }else if !o.no_scan {
+        scan::<PlatterWalker>(&o, opts, state);
     }else{
         dirty_load(&o, opts, state);
     }
 
-    if o.bench_pass_1 {return;}
+    if o.bench_pass_1 {
+        std::process::exit(report_errors(&o, opts, &state.read()));
+    }
+
+    let mut journal = opts.journal_path.as_ref()
+        .filter(|_| !opts.dedup_simulate || opts.quarantine_path.is_some() )
+        .map(|p| Journal::open(p).unwrap() );
 
-    if let Some(DedupMode::Btrfs) = o.dedup {
+    if let Some(mode) = &o.dedup {
+        let mechanism = match mode {
+            DedupMode::Btrfs => Some(DedupMechanism::Dedupe),
+            DedupMode::Clone => Some(DedupMechanism::Clone),
+            DedupMode::Auto => None,
+        };
         eprintln!("\n#### Dedup\n");
-        stat_section_start();
-        BtrfsDedup{}.dedup(state,opts).unwrap();
-        stat_section_end();
+        let progress = state.read().progress.clone();
+        progress.section_start(Phase::Dedup);
+        let mut deduper = BtrfsDedup::new(mechanism,journal.take().filter(|_| !opts.dedup_simulate ));
+        catch_interrupts(Some(&progress));
+        let result = deduper.dedup(state,opts);
+        catch_interrupts(None);
+        progress.section_end();
+        journal = deduper.journal.take();
+        if let Err(e) = result {
+            if !progress.is_interrupted() {
+                panic!("{:?}",e);
+            }
+            drop(journal);
+            eprintln!("\nInterrupted, the remaining groups are not deduplicated");
+            std::process::exit(130);
+        }
     }
 
-    let mut state = state.write();
+    results(&o, opts, &mut state.write(), journal.as_mut());
+
+    // keep the hashes dropped by the verification out of the cache
+    if opts.verify {
+        state.read().eventually_store_vfs(&opts.cache_path, true);
+    }
+
+    drop(journal);
+
+    let code = report_errors(&o, opts, &state.read());
+
+    if o.watch {
+        eprintln!("\n#### Watch\n");
+        watch(state, opts).unwrap();
+    }
 
-    if matches!(o.output, OutputMode::Disabled) {return;}
+    std::process::exit(code);
+}
+
+/// Parse the command line in front of the settings of the config files
+fn parse_args() -> (OptInput,ArgMatches) {
+    let cli = OptInput::parse();
+
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+
+    let command = OptInput::command();
+
+    // the options conflict with the subcommands
+    if cli.command.is_none() {
+        let mut settings = config_settings(&command, cli.config.as_deref(), cli.profile.as_deref()).unwrap_or_else(|e| {
+            eprintln!("Error reading config: {}",e);
+            std::process::exit(2);
+        });
+        // the directories given on the command line replace the ones of the config files
+        if !cli.dirs.is_empty() {
+            settings.remove("dirs");
+        }
+        args.splice(1..1, settings_args(&command, &settings));
+    }
+
+    let matches = command.get_matches_from(args);
+    let o = OptInput::from_arg_matches(&matches).unwrap_or_else(|e| e.exit() );
+    (o,matches)
+}
+
+/// Print the effective settings in the config file format
+fn print_config(matches: &ArgMatches) {
+    for arg in OptInput::command().get_arguments() {
+        let key = arg.get_long().unwrap_or(arg.get_id().as_str());
+        if matches!(key, "config" | "profile" | "print-config") {continue;}
+
+        let Some(values) = matches.get_raw(arg.get_id().as_str()) else {continue};
+        let values = values.map(|v| v.to_string_lossy() ).collect::<Vec<_>>();
+
+        let toml_value = |v: &str| {
+            if v.parse::<i64>().is_ok() || v == "true" || v == "false" || (v.contains('.') && v.parse::<f64>().is_ok()) {
+                v.to_owned()
+            }else{
+                format!("{:?}",v)
+            }
+        };
+
+        let value = match &values[..] {
+            [v] => toml_value(v),
+            values => format!("[{}]",values.iter().map(|v| toml_value(v) ).collect::<Vec<_>>().join(", ")),
+        };
+
+        println!("{} = {}",key,value);
+    }
+}
+
+/// Print the summary of the errors and write them to --error-log. Returns the exit code
+fn report_errors(o: &OptInput, opts: &Opts, state: &State) -> i32 {
+    if let Some(path) = &o.error_log {
+        state.errors.write_json(path).unwrap_or_else(|e| dprintln!("\tError writing error log: {}",e) );
+    }
+
+    if state.errors.is_empty() {return 0;}
+
+    eprintln!("\n#### Errors\n");
+    state.errors.print_summary(opts);
+
+    1
+}
+
+fn results(o: &OptInput, opts: &Opts, state: &mut State, journal: Option<&mut Journal>) {
+    if matches!(o.output, OutputMode::Disabled) && opts.quarantine_path.is_none() {return;}
 
     eprintln!("\n#### Calculate");
     
     assert!(!state.tree.entries.is_empty(),"No Duplicates found");
 
-    let _ = calculate_dir_hash(&mut state,VfsId::ROOT);
-    find_shadowed(&mut state,VfsId::ROOT);
+    let _ = calculate_dir_hash(state,VfsId::ROOT);
+    find_shadowed(state,VfsId::ROOT);
 
     eprintln!("#### Sort");
 
-    let sorted = export(&mut state);
+    let sorted = export(state);
 
-    eprintln!("#### Result");
+    if !matches!(o.output, OutputMode::Disabled) {
+        eprintln!("#### Result");
+    }
 
     match o.output {
-        OutputMode::Groups => print_groups(&sorted, &state, opts),
-        OutputMode::Tree => print_tree(&state, opts),
-        OutputMode::Diff => print_treediff(&mut state, opts),
-        OutputMode::Disabled => {}, //TODO exit before calc and sort
+        OutputMode::Groups => print_groups(&sorted, state, opts),
+        OutputMode::Tree => print_tree(state, opts),
+        OutputMode::Diff => print_treediff(state, opts),
+        OutputMode::Disabled => {},
+    }
+
+    if opts.quarantine_path.is_some() {
+        eprintln!("\n#### Quarantine\n");
+        quarantine(&sorted, state, opts, journal).unwrap();
     }
 }
 
-pub fn scan(o: &OptInput, opts: &'static Opts, state: &'static RwLock<State>) {
-    let mut d = PlatterWalker::new();
+pub fn scan<D: Driver>(o: &OptInput, opts: &Opts, state: &RwLock<State>) {
+    let mut d = D::new();
+    let progress = state.read().progress.clone();
+
+    catch_interrupts(Some(&progress));
 
     eprintln!("\n#### Pass 1\n");
 
-    stat_section_start();
-    spawn_info_thread(opts);
-    d.run(state,opts,Phase::Size).unwrap();
-    stat_section_end();
+    progress.section_start(Phase::Size);
+    run_phase(&mut d, opts, state, Phase::Size);
+    progress.section_end();
 
-    if o.bench_pass_1 {return;}
+    if o.bench_pass_1 {
+        catch_interrupts(None);
+        return;
+    }
 
     eprintln!("\n#### Pass 2\n");
 
-    stat_section_start();
-    d.run(state,opts,Phase::Hash).unwrap();
-    stat_section_end();
+    progress.section_start(Phase::PartialHash);
+    run_phase(&mut d, opts, state, Phase::PartialHash);
+    progress.section_phase(Phase::Hash);
+    run_phase(&mut d, opts, state, Phase::Hash);
+    progress.section_end();
 
     eprintln!("\n#### Pass 3\n");
 
-    stat_section_start();
-    d.run(state,opts,Phase::PostHash).unwrap();
-    stat_section_end();
+    progress.section_start(Phase::PostHash);
+    run_phase(&mut d, opts, state, Phase::PostHash);
+    progress.section_end();
 
     let mut state = state.write();
 
+    state.checkpoint = None;
     state.eventually_store_vfs(&opts.cache_path, true);
+
+    catch_interrupts(None);
+    if progress.is_interrupted() {
+        interrupted_exit(state.cache_allowed);
+    }
+}
+
+/// Run a phase of the scan. If interrupted, store the progress to the cache and exit
+fn run_phase<D: Driver>(d: &mut D, opts: &Opts, state: &RwLock<State>, phase: Phase) {
+    if let Err(e) = d.run(state,opts,phase) {
+        let progress = state.read().progress.clone();
+        if !progress.is_interrupted() {
+            panic!("{:?}",e);
+        }
+        progress.section_end();
+        state.read().eventually_store_vfs(&opts.cache_path, true);
+        interrupted_exit(state.read().cache_allowed);
+    }
+}
+
+fn interrupted_exit(cache_allowed: bool) -> ! {
+    if cache_allowed {
+        eprintln!("\nInterrupted, the progress is stored in the cache. Continue with --resume");
+    }else{
+        eprintln!("\nInterrupted");
+    }
+    std::process::exit(130);
 }
 
-pub fn dirty_load(o: &OptInput, opts: &'static Opts, state: &'static RwLock<State>) {
+pub fn dirty_load(o: &OptInput, opts: &Opts, state: &RwLock<State>) {
     let mut state = state.write();
 
     for root in &opts.paths {
@@ -127,26 +329,41 @@ pub fn dirty_load(o: &OptInput, opts: &'static Opts, state: &'static RwLock<Stat
     }
 }
 
-pub fn spawn_info_thread(o: &Opts) {
-    if DISP_ANSI.load(Ordering::Relaxed) && !o.verbose {
-        std::thread::spawn(move || {
-            let mut note = 0usize;
-            loop {
-                std::thread::sleep(Duration::from_millis(500));
-                note+=1;
-                if note >= 1200 {
-                    note = 0;
-                    VFS_STORE_NOTIF.store(true, Ordering::Relaxed);
-                }
-                if DISP_ENABLED.load(Ordering::Relaxed) {
-                    let mut err = std::io::stderr().lock();
-                    print_statw(&mut err, true);
-                }
-            }
-        });
+pub fn setup_progress(o: &OptInput, progress: &Progress) {
+    let terminal = stderr().is_terminal();
+
+    match o.progress {
+        ProgressMode::Auto => {
+            // the status line would be torn apart by the verbose log
+            let live = terminal && !o.verbose;
+            DISP_ANSI.store(live, Ordering::Relaxed);
+            progress.set_sink(Some(Arc::new(AnsiProgress::new(live))));
+        },
+        ProgressMode::Ansi => {
+            DISP_ANSI.store(true, Ordering::Relaxed);
+            progress.set_sink(Some(Arc::new(AnsiProgress::new(true))));
+        },
+        ProgressMode::Json if o.progress_fd == 2 => {
+            progress.set_sink(Some(Arc::new(JsonProgress::new(stderr()))));
+        },
+        ProgressMode::Json => {
+            // the fd is owned by the sink from here on
+            let out = unsafe { File::from_raw_fd(o.progress_fd) };
+            progress.set_sink(Some(Arc::new(JsonProgress::new(out))));
+        },
+        ProgressMode::None => progress.set_sink(None),
     }
 }
 
+pub fn spawn_info_thread(progress: Arc<Progress>) {
+    std::thread::spawn(move || {
+        loop {
+            std::thread::sleep(Duration::from_millis(500));
+            progress.tick();
+        }
+    });
+}
+
 
 pub fn get_threads() -> usize {
     match std::env::var("RAYON_NUM_THREADS")
@@ -169,8 +386,25 @@ pub fn get_threads() -> usize {
 }
 
 #[derive(Parser)]
-#[clap(version, about)]
+#[clap(version, about, args_conflicts_with_subcommands = true, args_override_self = true)]
 pub struct OptInput {
+    #[command(subcommand)]
+    pub command: Option<SubCommand>,
+
+    /// Config file, applied after /etc/dupion/config.toml and $XDG_CONFIG_HOME/dupion/config.toml. The options given here override the files
+    ///
+    /// The keys are the long options, e.g. `read-buffer = 4` or `cache-dropbehind = true`, and `dirs` for the directories to scan if none are given.
+    /// `[profile.<name>]` tables are applied on top with --profile.
+    /// A flag set to true in a file can't be turned off on the command line, only with `false` in a later file or the profile
+    #[arg(long, verbatim_doc_comment)]
+    pub config: Option<PathBuf>,
+    /// Apply the [profile.<name>] table of the config files, e.g. hdd-nightly
+    #[arg(long)]
+    pub profile: Option<String>,
+    /// Print the effective settings in the config file format and exit
+    #[arg(long)]
+    pub print_config: bool,
+
     /// Results output mode (g/t/d/-), what type of result should be printed
     /// groups: duplicate entries in sorted size groups
     /// tree: json as tree
@@ -191,20 +425,32 @@ pub struct OptInput {
     #[arg(long)]
     pub absolute: bool,
 
-    /// File lower size limit for scanning in bytes  
-    #[arg(long, default_value_t = 0)]
+    /// File lower size limit for scanning, e.g. 10K or 1.5MiB. Plain numbers are bytes
+    #[arg(long, value_parser = parse_size_bytes, default_value = "0")]
     pub min_size: u64,
-    /// File upper size limit for scanning in bytes  
-    #[arg(long, default_value_t = u64::MAX)]
-    pub max_size: u64, //TODO parse K/M/G prefixes
+    /// File upper size limit for scanning, e.g. 500MB or 4GiB. Plain numbers are bytes
+    #[arg(long, value_parser = parse_size_bytes)]
+    pub max_size: Option<u64>,
 
     /// Also search inside archives. requires to scan and hash every archive
     #[arg(short='a', long)]
     pub read_archives: bool, //TODO: build mode w/o archive support
-
-    /// Deduplication mode (-/btrfs). Disabled by default
+    /// Hash algorithm for the file contents (blake3/xxh3/sha256). Cached hashes of another algorithm are discarded
+    ///
+    /// blake3: Fast cryptographic hash
+    /// xxh3: xxh3-128, much faster but not cryptographic. For a first triage, better verify before acting on the results
+    /// sha256: Slower, for digests matching other tools
+    #[arg(long, value_enum, default_value = "blake3", verbatim_doc_comment)]
+    pub hash: HashMode,
+    /// Before the full hash, hash this much of the start and end of equally sized files and only fully hash the ones whose partial hash collides. 0 to disable. Plain numbers are KiB
+    #[arg(long, value_parser = parse_size_kib, default_value = "64KiB")]
+    pub partial_hash_size: u64,
+
+    /// Deduplication mode (-/btrfs/auto/clone). Disabled by default
     /// 
     /// btrfs: Use ioctl_file_dedupe_range on supported filesystems
+    /// auto: Probe every filesystem and use ioctl_file_dedupe_range where supported (e.g. btrfs, XFS)
+    /// clone: Use ioctl_ficlonerange (e.g. NFS 4.2), which doesn't compare the files. Implies --verify
     #[arg(long, verbatim_doc_comment)]
     pub dedup: Option<DedupMode>,
     /// EXPERIMENTAL Dedup even if first extent match. Currently this would dedup everything, even if already deduped
@@ -213,10 +459,34 @@ pub struct OptInput {
     /// Simulate if dedup enabled
     #[arg(long)]
     pub dedup_simulate: bool,
+    /// Defragment the file deduped against before dedup, if it has more than N extents
+    #[arg(long)]
+    pub dedup_defrag: Option<usize>,
+    /// Compare the files byte-by-byte against the kept copy before dedup or quarantine, instead of trusting the (cached) hashes.
+    /// Mismatching files are skipped and split off their group
+    #[arg(long)]
+    pub verify: bool,
+    /// Append a record of every action done on the files (e.g. dedup) to this JSON lines file
+    #[arg(long)]
+    pub journal: Option<PathBuf>,
+    /// Write the errors (e.g. unreadable files) as JSON lines to this file. dupion exits with 1 if there were any
+    #[arg(long)]
+    pub error_log: Option<PathBuf>,
+    /// Move duplicate files into this directory, mirroring their original paths. Must be on the same filesystem.
+    /// The first file of a group is kept. The moved files can be restored with the journal subcommand from the manifest in the directory
+    #[arg(long)]
+    pub quarantine: Option<PathBuf>,
 
-    /// Path of dupion cache
-    #[arg(long, default_value = "./dupion_cache")]
-    pub cache_path: PathBuf,
+    /// Path of dupion cache. $XDG_CACHE_HOME/dupion/cache (~/.cache/dupion/cache) by default
+    #[arg(long)]
+    pub cache_path: Option<PathBuf>,
+    /// Also keep a cache per scanned directory, merged into the one at --cache-path (off/root/uuid)
+    ///
+    /// off: Only --cache-path
+    /// root: A .dupion_cache file in each scanned directory, e.g. so that a removable drive carries its own cache
+    /// uuid: A file per filesystem in the cache directory, keyed by its UUID, valid wherever it is mounted
+    #[arg(long, value_enum, default_value = "off", verbatim_doc_comment)]
+    pub root_cache: RootCacheArg,
     /// Don't read or write cache file
     #[arg(long)]
     pub no_cache: bool,
@@ -224,19 +494,26 @@ pub struct OptInput {
     /// Number of threads for zip decoding, 0 = RAYON_NUM_THREADS or num_cpu logical count
     #[arg(short, long, default_value_t = 0)]
     pub threads: usize,
-
-    /// EXPERIMENTAL Read buffer in MiB
-    #[arg(long, default_value_t = 1.0)]
-    pub read_buffer: f64,
-    /// EXPERIMENTAL Prefetch budget in MiB
-    #[arg(long, default_value_t = 32.0)]
-    pub prefetch_budget: f64,
-    /// EXPERIMENTAL Dedup budget in MiB
-    #[arg(long, default_value_t = 512.0)]
-    pub dedup_budget: f64,
-    /// Threaded archive read cache limit in MiB
-    #[arg(long, default_value_t = 1024.0)]
-    pub archive_cache_mem: f64,
+    /// How to read the files in the hash pass (auto/hdd/ssd)
+    ///
+    /// auto: Detect per mount from /sys/block/*/queue/rotational, unknown devices are read like HDDs
+    /// hdd: One sequential reader in physical order
+    /// ssd: Read --threads files concurrently, spread over the devices
+    #[arg(long, value_enum, default_value = "auto", verbatim_doc_comment)]
+    pub storage: StorageArg,
+
+    /// EXPERIMENTAL Read buffer, e.g. 4MiB. Plain numbers are MiB
+    #[arg(long, value_parser = parse_size_mib, default_value = "1MiB")]
+    pub read_buffer: u64,
+    /// EXPERIMENTAL Prefetch budget. Plain numbers are MiB
+    #[arg(long, value_parser = parse_size_mib, default_value = "32MiB")]
+    pub prefetch_budget: u64,
+    /// EXPERIMENTAL Dedup budget. Plain numbers are MiB
+    #[arg(long, value_parser = parse_size_mib, default_value = "512MiB")]
+    pub dedup_budget: u64,
+    /// Threaded archive read cache limit, e.g. 1.5GiB. Plain numbers are MiB
+    #[arg(long, value_parser = parse_size_mib, default_value = "1GiB")]
+    pub archive_cache_mem: u64,
     /// Enable cache dropbehind to reduce cache pressure in hash scan. Can affect performance positively or negatively
     #[arg(long)]
     pub cache_dropbehind: bool,
@@ -246,9 +523,48 @@ pub struct OptInput {
     /// Abort after pass 1
     #[arg(long)]
     pub bench_pass_1: bool,
-    /// EXPERIMENTAL Prefetch directory metadata, eventually fails on non-root
-    #[arg(long)]
+    /// EXPERIMENTAL Prefetch directory metadata, the strategy per mount is shown with -v
+    ///
+    /// ext2/3/4, XFS: readahead of the directory extents on the block device, needs read access to it
+    /// btrfs: tree search of the directory items, needs CAP_SYS_ADMIN
+    /// others, or if the above fail: readahead on the directories
+    #[arg(long, verbatim_doc_comment)]
     pub dir_prefetch: bool,
+    /// Order of the directory walk and the file reads (content/inode/depth-first/breadth-first)
+    ///
+    /// content: By physical offset of the contents, for sequential reads on HDDs
+    /// inode: By inode number, without FIEMAP
+    /// depth-first: Depth-first, sorted by name. Deterministic, e.g. for network filesystems, FUSE or tmpfs
+    /// breadth-first: Breadth-first, sorted by name
+    #[arg(long, value_enum, default_value = "content", verbatim_doc_comment)]
+    pub order: OrderArg,
+    /// After the scan, keep watching the directories with inotify and print the duplicate groups of new or changed files as JSON lines
+    #[arg(long)]
+    pub watch: bool,
+    /// Only read directories whose mtime changed since the last scan and reuse the cached files of the others. Falls back to a full scan without cache.
+    /// The reused files aren't stat'ed, so files rewritten in place inside an unchanged directory keep their old hash.
+    /// Therefore --quarantine and --dedup clone require --verify
+    #[arg(long)]
+    pub incremental: bool,
+    /// On btrfs, only look at the inodes changed since the subvolume generations of the last scan instead of walking the directories.
+    /// Needs root, falls back to a full scan
+    #[arg(long)]
+    pub btrfs_incremental: bool,
+    /// Continue an interrupted scan of the same directories from the checkpoint in the cache, without walking them again.
+    /// On SIGINT/SIGTERM the cache is always written before exiting
+    #[arg(long, conflicts_with_all = ["no_cache", "btrfs_image", "no_scan"])]
+    pub resume: bool,
+    /// Treat the passed paths as the device files or images of one unmounted btrfs filesystem and scan the files inside, without mounting it.
+    /// The files are shown below the first path
+    #[arg(long, conflicts_with_all = ["dedup", "quarantine", "watch", "incremental", "btrfs_incremental", "read_archives", "no_scan"])]
+    pub btrfs_image: bool,
+    /// Handling of read-only btrfs snapshots (all/skip-readonly/reference)
+    ///
+    /// all: Scan them like any other directory
+    /// skip-readonly: Don't scan them, except if passed as path
+    /// reference: Scan them, but only report groups with at least two files outside of them, and never dedup or quarantine files inside
+    #[arg(long, value_enum, default_value = "all", verbatim_doc_comment)]
+    pub snapshots: SnapshotsMode,
     /// EXPERIMENTAL Don't scan for files, use found files from cache instead
     #[arg(long)]
     pub no_scan: bool,
@@ -256,12 +572,38 @@ pub struct OptInput {
     /// Verbose
     #[arg(short, long)]
     pub verbose: bool,
+    /// Progress reporting (auto/ansi/json/none)
+    ///
+    /// auto: Status line if stderr is a terminal and not verbose, otherwise only the totals after each phase
+    /// ansi: Status line, redrawn with escape codes
+    /// json: One JSON object per line, with the phase, counters, throughput, ETA and deduped bytes
+    /// none: Nothing
+    #[arg(long, value_enum, default_value = "auto", verbatim_doc_comment)]
+    pub progress: ProgressMode,
+    /// File descriptor the JSON progress is written to, e.g. 3 with `3>progress.jsonl`
+    #[arg(long, default_value_t = 2)]
+    pub progress_fd: i32,
     
     /// Directories to scan. cwd if none defined
     #[arg()]
     pub dirs: Vec<PathBuf>,
 }
 
+#[derive(Subcommand)]
+pub enum SubCommand {
+    /// Report the actions recorded in a journal, or undo the reversible ones
+    Journal {
+        /// Path of the journal
+        path: PathBuf,
+        /// Undo the reversible actions in reverse order, e.g. restore quarantined files
+        #[arg(long)]
+        undo: bool,
+        /// Delete the quarantined files
+        #[arg(long, conflicts_with = "undo")]
+        purge: bool,
+    },
+}
+
 #[derive(ValueEnum, Clone)]
 pub enum OutputMode {
     #[value(alias="g")]
@@ -274,7 +616,135 @@ pub enum OutputMode {
     Disabled,
 }
 
+#[derive(ValueEnum, Clone)]
+pub enum RootCacheArg {
+    Off,
+    Root,
+    Uuid,
+}
+
+#[derive(ValueEnum, Clone)]
+pub enum OrderArg {
+    Content,
+    Inode,
+    DepthFirst,
+    BreadthFirst,
+}
+
+#[derive(ValueEnum, Clone)]
+pub enum StorageArg {
+    Auto,
+    Hdd,
+    Ssd,
+}
+
+#[derive(ValueEnum, Clone)]
+pub enum HashMode {
+    Blake3,
+    Xxh3,
+    Sha256,
+}
+
+#[derive(ValueEnum, Clone)]
+pub enum SnapshotsMode {
+    All,
+    SkipReadonly,
+    Reference,
+}
+
+#[derive(ValueEnum, Clone)]
+pub enum ProgressMode {
+    Auto,
+    Ansi,
+    Json,
+    None,
+}
+
 #[derive(ValueEnum, Clone)]
 pub enum DedupMode {
-    Btrfs
+    Btrfs,
+    Auto,
+    Clone,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use dupion::config::{ConfigFile, ConfigValue, merge_settings};
+
+    fn file(s: &str) -> ConfigFile {
+        toml::from_str(s).unwrap()
+    }
+
+    fn settings(files: &[ConfigFile], profile: Option<&str>) -> BTreeMap<String,ConfigValue> {
+        for f in files {
+            f.check(&OptInput::command()).unwrap();
+        }
+        merge_settings(files, profile).unwrap()
+    }
+
+    fn args(files: &[ConfigFile], profile: Option<&str>) -> Vec<String> {
+        settings_args(&OptInput::command(), &settings(files, profile))
+            .into_iter()
+            .map(|a| a.into_string().unwrap() )
+            .collect()
+    }
+
+    fn check_err(s: &str) -> String {
+        file(s).check(&OptInput::command()).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn later_files_override() {
+        let files = [
+            file("read-buffer = \"1MiB\"\ndedup-simulate = true\nthreads = 4"),
+            file("read-buffer = \"4MiB\"\ndedup-simulate = false"),
+        ];
+        assert_eq!(args(&files, None), ["--read-buffer=4MiB", "--threads=4"]);
+    }
+
+    #[test]
+    fn profile_overrides_all_files() {
+        let files = [
+            file("verify = true\n[profile.quick]\nverify = false\nhash = \"xxh3\""),
+            file("verify = true\nhash = \"sha256\""),
+        ];
+        assert_eq!(args(&files, None), ["--hash=sha256", "--verify"]);
+        assert_eq!(args(&files, Some("quick")), ["--hash=xxh3"]);
+        assert!(merge_settings(&files, Some("missing")).is_err());
+    }
+
+    #[test]
+    fn arrays_repeat_the_argument() {
+        let files = [file("dirs = [\"/a\", \"/b c\"]\nthreads = 2")];
+        assert_eq!(args(&files, None), ["/a", "/b c", "--threads=2"]);
+
+        let cli = std::iter::once("dupion".to_owned()).chain(args(&files, None)).chain(["--threads=3".to_owned()]);
+        let o = OptInput::try_parse_from(cli).unwrap();
+        assert_eq!(o.dirs, [PathBuf::from("/a"), PathBuf::from("/b c")]);
+        assert_eq!(o.threads, 3);
+    }
+
+    #[test]
+    fn rejected_settings() {
+        assert_eq!(check_err("exclude = [\"*.tmp\"]"), "unknown option exclude");
+        assert_eq!(check_err("[profile.x]\nexclude = \"*.tmp\""), "unknown option exclude");
+        assert!(check_err("config = \"x\"").contains("can't be set"));
+        assert!(check_err("verify = \"yes\"").contains("is a flag"));
+        assert!(check_err("threads = true").contains("needs a value"));
+        assert!(check_err("threads = [1, 2]").contains("multiple times"));
+        assert!(check_err("dirs = [[\"a\"]]").contains("array of values"));
+    }
+
+    #[test]
+    fn unknown_keys_name_the_file() {
+        let path = std::env::temp_dir().join(format!("dupion-config-test-{}.toml",std::process::id()));
+        std::fs::write(&path, "threads = 2\nexclude = \"*.tmp\"").unwrap();
+
+        let e = config_settings(&OptInput::command(), Some(&path), None).map(|_| () ).unwrap_err().to_string();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(e, format!("{}: unknown option exclude",path.display()));
+    }
 }
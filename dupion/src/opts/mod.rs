@@ -1,10 +1,16 @@
 use super::*;
 use std::path::{Path, PathBuf};
-use vfs::is_absolute;
+use vfs::{is_absolute, rootcache::{RootCacheMode, default_cache_path}};
+use subvol::SnapshotMode;
+use hasher::HashAlgorithm;
+use storage::StorageMode;
+use platter_walk::Order;
 
 pub struct Opts {
     pub paths: Vec<PathBuf>,
     pub cache_path: PathBuf,
+    /// additional caches of the scan roots, merged into the one at cache_path
+    pub root_cache: RootCacheMode,
     pub verbose: bool,
     pub shadow_rule: u8,
     pub force_absolute_paths: bool,
@@ -14,14 +20,67 @@ pub struct Opts {
     pub dedup_budget: u64,
     //pub huge_zip_thres: u64,
     pub threads: usize,
+    pub storage: StorageMode,
     pub pass_1_hash: bool,
     pub archive_cache_mem: usize,
     pub dir_prefetch: bool,
+    pub walk_order: Order,
+    pub incremental: bool,
+    pub btrfs_incremental: bool,
+    /// continue the hash pass from the checkpoint in the cache instead of walking again
+    pub resume: bool,
+    pub snapshots: SnapshotMode,
     pub read_archives: bool,
+    pub hash_algorithm: HashAlgorithm,
+    /// bytes hashed at the start and end of the files before the full hash, 0 to disable
+    pub partial_hash_size: u64,
     pub scan_size_min: u64,
     pub scan_size_max: u64,
     pub aggressive_dedup: bool,
     pub dedup_simulate: bool,
+    pub dedup_defrag: Option<usize>,
+    pub verify: bool,
+    pub journal_path: Option<PathBuf>,
+    pub quarantine_path: Option<PathBuf>,
+}
+
+impl Default for Opts {
+    /// The defaults of the CLI, without paths
+    fn default() -> Self {
+        Self {
+            paths: Vec::new(),
+            cache_path: default_cache_path(),
+            root_cache: RootCacheMode::Off,
+            verbose: false,
+            shadow_rule: 2,
+            force_absolute_paths: false,
+            read_buffer: 1024*1024,
+            cache_dropbehind: false,
+            prefetch_budget: 32*1024*1024,
+            dedup_budget: 512*1024*1024,
+            threads: 0,
+            storage: StorageMode::Auto,
+            pass_1_hash: false,
+            archive_cache_mem: 1024*1024*1024,
+            dir_prefetch: false,
+            walk_order: Order::Content,
+            incremental: false,
+            btrfs_incremental: false,
+            resume: false,
+            snapshots: SnapshotMode::All,
+            read_archives: false,
+            hash_algorithm: HashAlgorithm::Blake3,
+            partial_hash_size: 64*1024,
+            scan_size_min: 0,
+            scan_size_max: u64::MAX,
+            aggressive_dedup: false,
+            dedup_simulate: false,
+            dedup_defrag: None,
+            verify: false,
+            journal_path: None,
+            quarantine_path: None,
+        }
+    }
 }
 
 impl Opts {
@@ -33,6 +92,12 @@ impl Opts {
             is_absolute(p);
             assert!(p.is_dir() || p.is_file());
         }
+        // the reused files of unchanged directories may have stale hashes
+        ensure!(!self.incremental || self.quarantine_path.is_none() || self.verify, "--incremental with --quarantine requires --verify");
+        if let Some(q) = &mut self.quarantine_path {
+            std::fs::create_dir_all(&q)?;
+            *q = q.canonicalize()?;
+        }
         Ok(())
     }
     pub fn log_verbosed(&self, prefix: &str, path: &Path) {
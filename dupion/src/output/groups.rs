@@ -1,5 +1,6 @@
 use super::*;
-use size_format::SizeFormatterBinary;
+use size::SizeDisp;
+use subvol::SnapshotMode;
 
 pub fn print_groups(v: &[HashGroup], b: &State, opts: &Opts) {
     for h in v {
@@ -12,6 +13,9 @@ pub fn print_groups(v: &[HashGroup], b: &State, opts: &Opts) {
 
         if entries.len() <= 1 {continue;}
 
+        // copies only found in read-only snapshots aren't duplicates by themselves
+        if entries.iter().filter(|(_,e)| !b.is_reference(&b.tree[*e].path, opts) ).count() <= 1 {continue;}
+
         for (typ,e) in entries.iter() {
             let e = &b.tree[*e];
             if e.exists() {
@@ -37,7 +41,7 @@ pub fn print_groups(v: &[HashGroup], b: &State, opts: &Opts) {
 
         if hide_shadowed && non_shadowed <= 1 {continue;}
 
-        println!("\nGroup {}B", SizeFormatterBinary::new(h.size));
+        println!("\nGroup {}", SizeDisp(h.size));
         for (typ,e) in entries {
             let e = &b.tree[*e];
             let shadowed = e.shadowed(*typ);
@@ -45,10 +49,16 @@ pub fn print_groups(v: &[HashGroup], b: &State, opts: &Opts) {
                 assert_eq!(e.size(*typ).unwrap(),h.size);
                 let tt = typ.icon2(e.is_dir);
                 println!(
-                    "   {}{} {}",
+                    "   {}{}{} {}{}",
                     tt,
                     if shadowed {'S'} else {' '},
-                    opts.path_disp(&e.path)
+                    match (opts.snapshots, b.is_reference(&e.path, opts)) {
+                        (SnapshotMode::Reference,true) => "R",
+                        (SnapshotMode::Reference,false) => " ",
+                        _ => "",
+                    },
+                    opts.path_disp(&e.path),
+                    b.subvolume_annotation(&e.path, opts),
                 );
             }
         }
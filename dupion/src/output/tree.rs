@@ -4,18 +4,21 @@ pub struct TreeRoot<'a> {
     state: &'a State,
     roots: Vec<VfsId>,
     force_absolute_paths: bool,
+    opts: &'a Opts,
 }
 pub struct DirEntry<'a> {
     state: &'a State,
     id: VfsId,
     root_path: &'a Path,
     force_absolute_paths: bool,
+    opts: &'a Opts,
 }
 pub struct Dupes<'a> {
     state: &'a State,
     group: &'a HashGroup,
     root_path: &'a Path,
     force_absolute_paths: bool,
+    opts: &'a Opts,
 }
 
 pub enum DEnum<'a> {
@@ -38,6 +41,7 @@ impl<'a> Serialize for TreeRoot<'a> {
                     id,
                     root_path: path,
                     force_absolute_paths: self.force_absolute_paths,
+                    opts: self.opts,
                 })
             });
         serializer.collect_map(iter)
@@ -80,6 +84,7 @@ impl<'a> Serialize for DirEntry<'a> {
                         group: &self.state.hashes[&hash],
                         root_path: self.root_path,
                         force_absolute_paths: self.force_absolute_paths,
+                        opts: self.opts,
                     }))
                 }else{
                     let ident = format!("UNIQ {} {} {}",icon,name,size);
@@ -88,6 +93,7 @@ impl<'a> Serialize for DirEntry<'a> {
                         id: *id,
                         root_path: self.root_path,
                         force_absolute_paths: self.force_absolute_paths,
+                        opts: self.opts,
                     }))
                 }
             });
@@ -100,15 +106,18 @@ impl<'a> Serialize for Dupes<'a> {
     where
         S: Serializer,
     {
+        // e.g. "blake3:<hex>", the keys of the files start with their icon
+        let digest = format!("{}:{}",self.state.hash_algorithm.id(),self.state.hash_algorithm.digest_hex(&self.group.hash));
+
         let iter = self.group.entries.iter()
             .take(4)
             .map(|(typ,id)| {
                 let e = &self.state.tree[*id];
                 let icon = typ.icon2(e.is_dir);
                 let path = reduce_path(&e.path,self.root_path,self.force_absolute_paths);
-                (format!("{} {}",icon,path),' ')
+                (format!("{} {}{}",icon,path,self.state.subvolume_annotation(&e.path,self.opts))," ".to_owned())
             });
-        serializer.collect_map(iter)
+        serializer.collect_map(std::iter::once(("digest".to_owned(),digest)).chain(iter))
     }
 }
 
@@ -141,6 +150,7 @@ pub fn print_tree(state: &State, opts: &Opts) {
         state,
         roots,
         force_absolute_paths: opts.force_absolute_paths,
+        opts,
     };
 
     let mut stdout = std::io::stdout();
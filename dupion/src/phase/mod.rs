@@ -1,7 +1,24 @@
 use super::*;
 
+#[derive(Clone,Copy,PartialEq,Debug)]
 pub enum Phase {
     Size,
+    /// Hash the start and end of the files, so that only the ones whose partial hash collides are fully hashed
+    PartialHash,
     Hash,
     PostHash,
+    /// Not run by the drivers, only reported to the progress sink
+    Dedup,
+}
+
+impl Phase {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Size => "size",
+            Self::PartialHash => "partial_hash",
+            Self::Hash => "hash",
+            Self::PostHash => "post_hash",
+            Self::Dedup => "dedup",
+        }
+    }
 }
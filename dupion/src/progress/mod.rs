@@ -0,0 +1,239 @@
+use super::*;
+use std::{io::Write, sync::{atomic::Ordering, mpsc, Arc}, time::{Instant, SystemTime, UNIX_EPOCH}};
+use parking_lot::Mutex;
+use serde_derive::Serialize;
+use size::SizeDisp;
+use phase::Phase;
+use util::{Progress, ProgressSnapshot, ALLOC_MON};
+
+/// Receives the progress of a scan
+pub trait ProgressSink: Send + Sync {
+    /// Called periodically while the phase runs
+    fn progress(&self, phase: Phase, progress: &ProgressSnapshot);
+
+    /// Called once at the end of the phase
+    fn phase_end(&self, phase: Phase, progress: &ProgressSnapshot) {
+        self.progress(phase, progress)
+    }
+}
+
+impl<F> ProgressSink for F where F: Fn(Phase,&ProgressSnapshot) + Send + Sync {
+    fn progress(&self, phase: Phase, progress: &ProgressSnapshot) {
+        self(phase, progress)
+    }
+}
+
+impl ProgressSink for mpsc::Sender<(Phase,ProgressSnapshot)> {
+    fn progress(&self, phase: Phase, progress: &ProgressSnapshot) {
+        // the receiver may stop listening
+        let _ = self.send((phase, *progress));
+    }
+}
+
+impl Progress {
+    /// Where the sections report their progress, None to disable
+    pub fn set_sink(&self, sink: Option<Arc<dyn ProgressSink>>) {
+        *self.sink.write() = sink;
+    }
+
+    /// Report the progress of the running section to the sink, called periodically
+    pub fn tick(&self) {
+        let section = *self.section.lock();
+        let sink = self.sink.read().clone();
+
+        if let (Some(phase),Some(sink)) = (section,sink) {
+            sink.progress(phase, &self.snapshot());
+        }
+    }
+
+    /// Report the progress in `phase` until section_end
+    pub fn section_start(&self, phase: Phase) {
+        *self.section.lock() = Some(phase);
+    }
+
+    /// End the phase of the running section and continue it with the next one
+    pub fn section_phase(&self, phase: Phase) {
+        let ended = match &mut *self.section.lock() {
+            Some(prev) if *prev != phase => std::mem::replace(prev, phase),
+            _ => return,
+        };
+        self.phase_end(ended);
+    }
+
+    pub fn section_end(&self) {
+        let section = self.section.lock().take();
+        if let Some(phase) = section {
+            self.phase_end(phase);
+        }
+    }
+
+    fn phase_end(&self, phase: Phase) {
+        let sink = self.sink.read().clone();
+        if let Some(sink) = sink {
+            sink.phase_end(phase, &self.snapshot());
+        }
+    }
+}
+
+/// Throughput of the processed bytes, over the time actually elapsed between the updates
+#[derive(Default)]
+pub struct Throughput {
+    last: Option<(Instant,u64)>,
+    bytes_per_sec: u64,
+}
+
+impl Throughput {
+    /// Returns the bytes per second since the last update
+    pub fn update(&mut self, processed_bytes: u64) -> u64 {
+        let now = Instant::now();
+
+        match self.last {
+            // the counters are reset for the dedup
+            Some((_,prev)) if prev > processed_bytes => {
+                self.last = Some((now,processed_bytes));
+                self.bytes_per_sec = 0;
+            },
+            Some((then,prev)) => {
+                let elapsed = now.duration_since(then).as_secs_f64();
+                // e.g. the section ends right after a periodic update
+                if elapsed >= 0.1 {
+                    self.bytes_per_sec = ((processed_bytes - prev) as f64 / elapsed) as u64;
+                    self.last = Some((now,processed_bytes));
+                }
+            },
+            None => self.last = Some((now,processed_bytes)),
+        }
+
+        self.bytes_per_sec
+    }
+}
+
+/// The status line on stderr
+pub struct AnsiProgress {
+    /// Redraw the line periodically. Otherwise only the final line of a phase is printed, without escape codes
+    live: bool,
+    throughput: Mutex<Throughput>,
+}
+
+impl AnsiProgress {
+    pub fn new(live: bool) -> Self {
+        Self{live, throughput: Default::default()}
+    }
+
+    fn line(&self, p: &ProgressSnapshot) -> String {
+        let bytes_per_sec = self.throughput.lock().update(p.processed_bytes);
+
+        match p.deduped_bytes {
+            None => format!(
+                "Found: {} ({})        Hashed: {}/{} {}/{} ({}/s)        alloc={} ",
+                p.found_files,
+                SizeDisp(p.found_bytes),
+                p.processed_files,
+                p.relevant_files,
+                SizeDisp(p.processed_bytes),
+                SizeDisp(p.relevant_bytes),
+                SizeDisp(bytes_per_sec),
+                SizeDisp(ALLOC_MON.load(Ordering::Relaxed) as u64),
+            ),
+            Some(deduped_bytes) => format!(
+                "Deduplication: Processed: {}/{} {}/{} ({}/s)        Deduped: {} ",
+                p.processed_files,
+                p.relevant_files,
+                SizeDisp(p.processed_bytes),
+                SizeDisp(p.relevant_bytes),
+                SizeDisp(bytes_per_sec),
+                SizeDisp(deduped_bytes),
+            ),
+        }
+    }
+}
+
+impl ProgressSink for AnsiProgress {
+    fn progress(&self, _: Phase, progress: &ProgressSnapshot) {
+        if self.live {
+            let line = self.line(progress);
+            let mut err = std::io::stderr().lock();
+            let _ = write!(err, "\x1B[2K\r{}", line);
+            let _ = err.flush();
+        }
+    }
+
+    fn phase_end(&self, _: Phase, progress: &ProgressSnapshot) {
+        let line = self.line(progress);
+        if self.live {
+            eprintln!("\x1B[2K\r{}", line);
+        }else{
+            eprintln!("{}", line);
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ProgressRecord<'a> {
+    /// unix time in seconds
+    time: u64,
+    phase: &'a str,
+    /// the last record of the phase
+    done: bool,
+    found_files: u64,
+    found_bytes: u64,
+    relevant_files: u64,
+    relevant_bytes: u64,
+    processed_files: u64,
+    processed_bytes: u64,
+    bytes_per_sec: u64,
+    /// seconds until the relevant bytes are processed at the current throughput
+    eta_secs: Option<u64>,
+    deduped_bytes: Option<u64>,
+}
+
+/// One JSON object per line for each update
+pub struct JsonProgress<W> {
+    out: Mutex<(W,Throughput)>,
+}
+
+impl<W> JsonProgress<W> where W: Write {
+    pub fn new(out: W) -> Self {
+        Self{out: Mutex::new((out,Throughput::default()))}
+    }
+
+    fn write(&self, phase: Phase, p: &ProgressSnapshot, done: bool) {
+        let mut out = self.out.lock();
+        let (out,throughput) = &mut *out;
+
+        let bytes_per_sec = throughput.update(p.processed_bytes);
+        let remaining = p.relevant_bytes.saturating_sub(p.processed_bytes);
+
+        let record = ProgressRecord {
+            time: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() ),
+            phase: phase.name(),
+            done,
+            found_files: p.found_files,
+            found_bytes: p.found_bytes,
+            relevant_files: p.relevant_files,
+            relevant_bytes: p.relevant_bytes,
+            processed_files: p.processed_files,
+            processed_bytes: p.processed_bytes,
+            bytes_per_sec,
+            // the relevant bytes are still growing while sizing
+            eta_secs: (phase != Phase::Size && bytes_per_sec > 0).then(|| remaining / bytes_per_sec ),
+            deduped_bytes: p.deduped_bytes,
+        };
+
+        // e.g. the reading end was closed, the scan goes on
+        if serde_json::to_writer(&mut *out, &record).is_ok() {
+            let _ = out.write_all(b"\n");
+            let _ = out.flush();
+        }
+    }
+}
+
+impl<W> ProgressSink for JsonProgress<W> where W: Write + Send {
+    fn progress(&self, phase: Phase, progress: &ProgressSnapshot) {
+        self.write(phase, progress, false)
+    }
+
+    fn phase_end(&self, phase: Phase, progress: &ProgressSnapshot) {
+        self.write(phase, progress, true)
+    }
+}
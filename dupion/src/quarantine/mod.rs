@@ -0,0 +1,227 @@
+use super::*;
+use std::{fs::{create_dir_all, rename}, io::ErrorKind, os::unix::fs::MetadataExt, path::{Path, PathBuf}};
+use size::SizeDisp;
+use state::State;
+use opts::Opts;
+use group::HashGroup;
+use vfs::{VfsId, entry::VfsEntryType};
+use journal::{Journal, JournalAction, JournalEntry};
+use verify::verify_files;
+use errors::ScanErrorKind;
+
+pub const MANIFEST_NAME: &str = "dupion_manifest.jsonl";
+
+/// Path of `path` mirrored into the quarantine dir
+pub fn quarantine_path(quarantine_dir: &Path, path: &Path) -> PathBuf {
+    quarantine_dir.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// Move the duplicate files into the quarantine dir. The first still existing file of every group, in the order of `export`, is kept.
+///
+/// The actions are recorded into the manifest in the quarantine dir, which can be used to restore the files with `dupion journal --undo`
+pub fn quarantine(groups: &[HashGroup], state: &mut State, opts: &Opts, mut journal: Option<&mut Journal>) -> AnyhowResult<()> {
+    let quarantine_dir = opts.quarantine_path.as_deref().unwrap();
+
+    let mut manifest = Journal::open(&quarantine_dir.join(MANIFEST_NAME))?;
+
+    state.errors.set_phase(None);
+
+    let mut moved_files = 0u64;
+    let mut moved_bytes = 0u64;
+
+    for g in groups {
+        let files = g.entries.iter()
+            .filter(|&&(typ,id)|
+                typ == VfsEntryType::File
+                && state.tree[id].is_file
+                && state.tree[id].valid
+                // only the files which were phys-sorted by the scan, i.e. no archive members
+                && state.tree[id].phys.is_some()
+                && !state.tree[id].path.starts_with(quarantine_dir)
+                && !state.is_reference(&state.tree[id].path, opts)
+            )
+            .map(|&(_,id)| id )
+            .collect::<Vec<_>>();
+
+        if files.len() < 2 {continue;}
+
+        // never quarantine the last remaining copy
+        let keep = match files.iter().position(|&id| unmodified(id, state) ) {
+            Some(i) => i,
+            None => {
+                state.errors.push(opts,ScanErrorKind::Comodified,&*state.tree[files[0]].path,"no unmodified copy left, skipped group");
+                continue;
+            }
+        };
+        let keep_id = files[keep];
+
+        let verified = opts.verify.then(|| {
+            let pairs = files.iter()
+                .filter(|&&id| id != keep_id )
+                .map(|&id| (keep_id,id) )
+                .collect::<Vec<_>>();
+            verify_files(&pairs, state, opts)
+        });
+
+        for (i,&id) in files.iter().enumerate() {
+            if i == keep {continue;}
+
+            let path = state.tree[id].path.clone();
+
+            if !unmodified(id, state) {
+                state.errors.push(opts,ScanErrorKind::Comodified,&*path,"skipped");
+                continue;
+            }
+
+            if verified.as_ref().is_some_and(|v| !v.passed(id) ) {continue;}
+
+            let dest = quarantine_path(quarantine_dir, &path);
+
+            opts.log_verbosed("QUARANTINE", &path);
+
+            let result = (|| {
+                if let Some(parent) = dest.parent() {
+                    create_dir_all(parent)?;
+                }
+                if dest.symlink_metadata().is_ok() {
+                    return Err(std::io::Error::new(ErrorKind::AlreadyExists, "Already exists in quarantine"));
+                }
+                rename(&path, &dest)
+            })();
+
+            match result {
+                Ok(()) => {},
+                Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+                    state.errors.push(opts,ScanErrorKind::Quarantine,&*path,"not on the filesystem of the quarantine dir");
+                    continue;
+                },
+                Err(e) => {
+                    state.errors.push(opts,ScanErrorKind::Quarantine,&*path,e);
+                    continue;
+                },
+            }
+
+            let mut entry = JournalEntry::new(JournalAction::Quarantine, id, state);
+            entry.source = Some(state.tree[keep_id].path.to_path_buf());
+            entry.source_ctime = state.tree[keep_id].ctime;
+            entry.moved_to = Some(dest);
+            entry.bytes = g.size;
+
+            manifest.append(&entry).unwrap_or_else(|e| dprintln!("\tError writing manifest: {}",e) );
+            if let Some(journal) = journal.as_mut() {
+                journal.append(&entry).unwrap_or_else(|e| dprintln!("\tError writing journal: {}",e) );
+            }
+
+            state.invalidate(id);
+
+            moved_files += 1;
+            moved_bytes += g.size;
+        }
+
+        manifest.flush()?;
+
+        if let Some(verified) = verified {
+            verified.drop_mismatched_hashes(state);
+        }
+    }
+
+    if let Some(journal) = journal {
+        journal.flush()?;
+    }
+
+    dprintln!(
+        "Quarantined {} files ({}) into {}",
+        moved_files,
+        SizeDisp(moved_bytes),
+        quarantine_dir.to_string_lossy(),
+    );
+
+    Ok(())
+}
+
+/// the file still exists with the scanned size and ctime
+fn unmodified(id: VfsId, state: &State) -> bool {
+    let e = &state.tree[id];
+    e.path.symlink_metadata().is_ok_and(|m|
+        m.is_file()
+        && Some(m.len()) == e.file_size
+        && Some(m.ctime()) == e.ctime
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::read;
+    use process::export;
+    use driver::platterwalker::hashed;
+    use journal::{read_journal, report_journal};
+    use util::TestDir;
+
+    fn opts(quarantine_dir: &Path) -> Opts {
+        let mut opts = Opts{threads: 1, quarantine_path: Some(quarantine_dir.to_owned()), ..Default::default()};
+        opts.validate().unwrap();
+        opts
+    }
+
+    #[test]
+    fn mirrored_path_and_manifest() {
+        let dir = TestDir::new("quarantine");
+        let quarantine_dir = dir.0.join("q");
+        let opts = opts(&quarantine_dir);
+        let data = vec![5u8; 10_000];
+        let paths = [dir.file("data/a", &data), dir.file("data/sub/b", &data), dir.file("c", &data), dir.file("unique", b"x")];
+        let (mut state,ids) = hashed(&paths, &opts);
+        let groups = export(&mut state);
+
+        quarantine(&groups, &mut state, &opts, None).unwrap();
+
+        // c sorts first, so it is kept
+        let moved = [&paths[0], &paths[1]].map(|p| quarantine_path(&quarantine_dir, p) );
+        assert_eq!(moved[0], quarantine_dir.join(paths[0].strip_prefix("/").unwrap()));
+        assert_eq!(read(&paths[2]).unwrap(), data);
+        for (path,moved) in paths.iter().zip(&moved) {
+            assert!(path.symlink_metadata().is_err());
+            assert_eq!(read(moved).unwrap(), data);
+        }
+        assert!(paths[3].exists());
+        assert!(!state.tree[ids[0]].valid && !state.tree[ids[1]].valid && state.tree[ids[2]].valid);
+
+        let manifest_path = quarantine_dir.join(MANIFEST_NAME);
+        let manifest = read_journal(&manifest_path).unwrap();
+        assert_eq!(manifest.len(), 2);
+        for (e,(path,moved)) in manifest.iter().zip(paths.iter().zip(&moved)) {
+            assert_eq!(e.action, JournalAction::Quarantine);
+            assert_eq!(&e.path, path);
+            assert_eq!(e.moved_to.as_ref(), Some(moved));
+            assert_eq!(e.source.as_ref(), Some(&paths[2]));
+            assert_eq!(e.bytes, data.len() as u64);
+        }
+
+        report_journal(&manifest_path, true, false).unwrap();
+
+        for (path,moved) in paths.iter().zip(&moved) {
+            assert_eq!(read(path).unwrap(), data);
+            assert!(moved.symlink_metadata().is_err());
+        }
+    }
+
+    #[test]
+    fn modified_files_stay() {
+        let dir = TestDir::new("quarantine");
+        let quarantine_dir = dir.0.join("q");
+        let opts = opts(&quarantine_dir);
+        let data = vec![5u8; 10_000];
+        let paths = [dir.file("a", &data), dir.file("b", &data)];
+        let (mut state,_) = hashed(&paths, &opts);
+        let groups = export(&mut state);
+
+        std::fs::write(&paths[1], b"changed").unwrap();
+
+        quarantine(&groups, &mut state, &opts, None).unwrap();
+
+        assert!(paths.iter().all(|p| p.exists() ));
+        assert!(read_journal(&quarantine_dir.join(MANIFEST_NAME)).unwrap().is_empty());
+        assert!(state.errors.errors().iter().any(|e| e.kind == ScanErrorKind::Comodified && e.path.as_deref() == Some(&*paths[1]) ));
+    }
+}
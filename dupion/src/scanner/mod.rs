@@ -0,0 +1,196 @@
+use super::*;
+use std::{path::{Path, PathBuf}, sync::{mpsc, Arc}, time::Duration};
+use parking_lot::RwLock;
+use state::State;
+use opts::Opts;
+use phase::Phase;
+use driver::{Driver, platterwalker::PlatterWalker};
+use group::HashGroup;
+use process::{export, calculate_dir_hash, find_shadowed};
+use vfs::{VfsId, rootcache::root_caches};
+
+pub use progress::ProgressSink;
+
+/// Scan for duplicates from another program. The scan owns its state, so independent scans can run side by side.
+///
+/// ```no_run
+/// let result = dupion::scanner::Scanner::new(["/data"])
+///     .cache_path("/var/cache/dupion")
+///     .progress(|phase: dupion::phase::Phase, p: &dupion::util::ProgressSnapshot| eprintln!("{:?} {}/{}", phase, p.processed_files, p.relevant_files))
+///     .run()
+///     .unwrap();
+///
+/// for group in result.duplicates() {
+///     for path in result.paths(group) {
+///         println!("{}", path.display());
+///     }
+/// }
+/// ```
+pub struct Scanner {
+    opts: Opts,
+    cache: bool,
+    sink: Option<Arc<dyn ProgressSink>>,
+    interval: Duration,
+}
+
+impl Scanner {
+    /// With the defaults of the CLI, except that no cache is used
+    pub fn new(paths: impl IntoIterator<Item=impl Into<PathBuf>>) -> Self {
+        Self {
+            opts: Opts {
+                paths: paths.into_iter().map(Into::into).collect(),
+                ..Default::default()
+            },
+            cache: false,
+            sink: None,
+            interval: Duration::from_millis(500),
+        }
+    }
+
+    /// Read the cache from and write it to this path
+    pub fn cache_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.opts.cache_path = path.into();
+        self.cache = true;
+        self
+    }
+
+    pub fn progress(mut self, sink: impl ProgressSink + 'static) -> Self {
+        self.sink = Some(Arc::new(sink));
+        self
+    }
+
+    /// How often the progress sink is called while a phase runs
+    pub fn progress_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn hash_algorithm(mut self, algorithm: hasher::HashAlgorithm) -> Self {
+        self.opts.hash_algorithm = algorithm;
+        self
+    }
+
+    /// 0 for the number of logical CPUs
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.opts.threads = threads;
+        self
+    }
+
+    pub fn size_range(mut self, min: u64, max: u64) -> Self {
+        self.opts.scan_size_min = min;
+        self.opts.scan_size_max = max;
+        self
+    }
+
+    pub fn read_archives(mut self, read_archives: bool) -> Self {
+        self.opts.read_archives = read_archives;
+        self
+    }
+
+    pub fn storage(mut self, storage: storage::StorageMode) -> Self {
+        self.opts.storage = storage;
+        self
+    }
+
+    pub fn walk_order(mut self, order: platter_walk::Order) -> Self {
+        self.opts.walk_order = order;
+        self
+    }
+
+    /// The remaining options
+    pub fn opts_mut(&mut self) -> &mut Opts {
+        &mut self.opts
+    }
+
+    /// Walk and hash the paths, and group the duplicates
+    pub fn run(mut self) -> AnyhowResult<ScanResult> {
+        ensure!(!self.opts.paths.is_empty(), "No paths to scan");
+        if self.opts.threads == 0 {
+            self.opts.threads = num_cpus::get();
+        }
+        self.opts.validate()?;
+
+        let opts = &self.opts;
+        let state = RwLock::new(State::new(self.cache, opts.hash_algorithm));
+        let progress = state.read().progress.clone();
+        progress.set_sink(self.sink.clone());
+
+        state.write().root_caches = root_caches(opts);
+        state.write().eventually_load_vfs(&opts.cache_path);
+
+        let (stop, stopped) = mpsc::channel::<()>();
+
+        std::thread::scope(|scope| {
+            if self.sink.is_some() {
+                let progress = &progress;
+                let interval = self.interval;
+                scope.spawn(move || {
+                    while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                        progress.tick();
+                    }
+                });
+            }
+
+            let result = self.run_phases(&state);
+            drop(stop);
+            result
+        })?;
+
+        let mut state = state.into_inner();
+
+        state.checkpoint = None;
+        state.eventually_store_vfs(&opts.cache_path, true);
+
+        let _ = calculate_dir_hash(&mut state, VfsId::ROOT);
+        find_shadowed(&mut state, VfsId::ROOT);
+
+        let groups = export(&mut state);
+
+        Ok(ScanResult{state, groups, opts: self.opts})
+    }
+
+    fn run_phases(&self, state: &RwLock<State>) -> AnyhowResult<()> {
+        let mut d = PlatterWalker::new();
+        let progress = state.read().progress.clone();
+
+        for p in [Phase::Size, Phase::PartialHash, Phase::Hash, Phase::PostHash] {
+            progress.section_start(p);
+            let result = d.run(state, &self.opts, p);
+            progress.section_end();
+            result?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The outcome of a Scanner
+pub struct ScanResult {
+    pub state: State,
+    /// All hash groups, biggest first
+    pub groups: Vec<HashGroup>,
+    pub opts: Opts,
+}
+
+impl ScanResult {
+    /// The groups with more than one existing entry, not counting copies only found in read-only snapshots with SnapshotMode::Reference
+    pub fn duplicates(&self) -> impl Iterator<Item=&HashGroup> {
+        self.groups.iter()
+            .filter(|g| self.entries(g).filter(|&id| !self.state.is_reference(&self.state.tree[id].path, &self.opts) ).count() > 1 )
+    }
+
+    /// The existing entries of a group
+    pub fn entries<'a>(&'a self, group: &'a HashGroup) -> impl Iterator<Item=VfsId> + 'a {
+        group.entries.iter()
+            .filter(|&&(typ,id)| self.state.tree[id].is2(typ) && self.state.tree[id].exists() )
+            .map(|&(_,id)| id )
+    }
+
+    pub fn paths<'a>(&'a self, group: &'a HashGroup) -> impl Iterator<Item=&'a Path> + 'a {
+        self.entries(group).map(|id| &*self.state.tree[id].path )
+    }
+
+    pub fn into_groups(self) -> Vec<HashGroup> {
+        self.groups
+    }
+}
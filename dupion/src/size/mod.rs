@@ -0,0 +1,127 @@
+use std::fmt::{self, Display};
+use size_format::SizeFormatterBinary;
+
+pub const KIB: u64 = 1024;
+pub const MIB: u64 = 1024*1024;
+
+/// Parse a size like `10K`, `1.5GiB`, `500MB` or `4096`.
+///
+/// K/M/G/T/P/E and KiB/MiB/... are binary, KB/MB/... decimal, case insensitive. Plain numbers are multiplied by `unit`
+pub fn parse_size(s: &str, unit: u64) -> Result<u64,String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit() && c != '.' ).unwrap_or(s.len());
+    let (number,suffix) = s.split_at(split);
+
+    let invalid = || format!("invalid size {:?}, expected e.g. 10K, 1.5GiB or 500MB",s);
+
+    let multiplier = match suffix.trim().to_ascii_lowercase().as_str() {
+        "" => unit,
+        "b" => 1,
+        suffix => {
+            let mut chars = suffix.chars();
+            let prefix = chars.next().unwrap();
+            let exp = "kmgtpe".find(prefix).ok_or_else(invalid)? as u32 + 1;
+            match chars.as_str() {
+                "" | "i" | "ib" => 1024u64.pow(exp),
+                "b" => 1000u64.pow(exp),
+                _ => return Err(invalid()),
+            }
+        },
+    };
+
+    if let Ok(n) = number.parse::<u64>() {
+        return n.checked_mul(multiplier).ok_or_else(|| format!("size {:?} is too large",s) );
+    }
+
+    let n = number.parse::<f64>().map_err(|_| invalid() )?;
+    let bytes = (n * multiplier as f64).round();
+    if bytes >= u64::MAX as f64 {
+        return Err(format!("size {:?} is too large",s));
+    }
+    Ok(bytes as u64)
+}
+
+/// Round a buffer size to whole 4KiB pages. At least one page, a buffer rounded to 0 would read every file as empty
+pub fn round_to_pages(size: u64) -> u64 {
+    (size.saturating_add(1024)/4096*4096).max(4096)
+}
+
+/// For clap, plain numbers are bytes
+pub fn parse_size_bytes(s: &str) -> Result<u64,String> {
+    parse_size(s, 1)
+}
+
+/// For clap, plain numbers are KiB
+pub fn parse_size_kib(s: &str) -> Result<u64,String> {
+    parse_size(s, KIB)
+}
+
+/// For clap, plain numbers are MiB
+pub fn parse_size_mib(s: &str) -> Result<u64,String> {
+    parse_size(s, MIB)
+}
+
+/// Displays a size like `1.5GiB`, rounded to the shown digits
+#[derive(Clone,Copy)]
+pub struct SizeDisp(pub u64);
+
+impl Display for SizeDisp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}B", SizeFormatterBinary::new(self.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_numbers_use_the_unit() {
+        assert_eq!(parse_size("4096", 1), Ok(4096));
+        assert_eq!(parse_size("4", KIB), Ok(4096));
+        assert_eq!(parse_size(" 2 ", MIB), Ok(2*MIB));
+        assert_eq!(parse_size("0", MIB), Ok(0));
+    }
+
+    #[test]
+    fn binary_suffixes() {
+        for s in ["10K", "10k", "10Ki", "10KiB", "10kib", "10 KiB"] {
+            assert_eq!(parse_size(s, MIB), Ok(10*KIB), "{}", s);
+        }
+        assert_eq!(parse_size("3G", 1), Ok(3*1024*MIB));
+        assert_eq!(parse_size("1E", 1), Ok(1 << 60));
+    }
+
+    #[test]
+    fn decimal_suffixes() {
+        assert_eq!(parse_size("500MB", 1), Ok(500_000_000));
+        assert_eq!(parse_size("1kb", 1), Ok(1000));
+        assert_eq!(parse_size("2TB", 1), Ok(2_000_000_000_000));
+        assert_eq!(parse_size("7B", MIB), Ok(7));
+        assert_eq!(parse_size("7b", MIB), Ok(7));
+    }
+
+    #[test]
+    fn fractions() {
+        assert_eq!(parse_size("1.5GiB", 1), Ok(1536*MIB));
+        assert_eq!(parse_size("0.5K", 1), Ok(512));
+        assert_eq!(parse_size("1.5", MIB), Ok(1536*KIB));
+        assert_eq!(parse_size("0.0001K", 1), Ok(0));
+    }
+
+    #[test]
+    fn overflow() {
+        assert!(parse_size("16E", 1).is_err());
+        assert!(parse_size("16.5E", 1).is_err());
+        assert!(parse_size("18446744073709551616", 1).is_err());
+        assert!(parse_size("18446744073709551615", KIB).is_err());
+        assert_eq!(parse_size("18446744073709551615", 1), Ok(u64::MAX));
+    }
+
+    #[test]
+    fn invalid() {
+        for s in ["", " ", "K", "KiB", "MB", "10X", "10KX", "10KBi", "1.2.3", "-1", "1e3"] {
+            assert!(parse_size(s, 1).is_err(), "{}", s);
+        }
+    }
+}
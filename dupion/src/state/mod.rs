@@ -2,15 +2,39 @@ use super::*;
 use rustc_hash::FxHashMap;
 use util::*;
 use vfs::{VfsId, Vfs, entry::VfsEntryType};
-use std::{collections::hash_map::Entry, sync::Arc};
-use group::{HashGroup, SizeGroup};
+use std::{collections::hash_map::Entry, sync::Arc, time::Instant};
+use group::{HashGroup, SizeGroup, PartialHashGroup};
 use opts::Opts;
+use driver::{btrfsgen::SubvolGeneration, platterwalker::HashCheckpoint};
+use vfs::rootcache::RootCache;
+use subvol::Subvolume;
+use hasher::HashAlgorithm;
+use errors::ErrorLog;
 
 pub struct State {
     pub tree: Vfs,
     pub sizes: Sizes,
     pub hashes: Hashes,
+    /// groups of the files hashed in Phase::PartialHash, by size and partial hash
+    pub partial_hashes: PartialHashes,
+    pub file_partial_hashes: FxHashMap<VfsId,PartialHash>,
+    /// amount of partially hashed files by size
+    pub partial_hashed_sizes: FxHashMap<Size,usize>,
     pub cache_allowed: bool,
+    /// btrfs subvolume generations of the scan roots, for the generation based incremental scan
+    pub btrfs_generations: Vec<SubvolGeneration>,
+    /// btrfs subvolumes found by the scan
+    pub subvolumes: Vec<Subvolume>,
+    /// algorithm of the file hashes, cached hashes of another one are discarded
+    pub hash_algorithm: HashAlgorithm,
+    pub progress: Arc<Progress>,
+    pub errors: Arc<ErrorLog>,
+    /// progress of the hash pass, stored with the cache until the scan completes
+    pub checkpoint: Option<HashCheckpoint>,
+    /// merged into the tree on load and written with the forced stores
+    pub root_caches: Vec<RootCache>,
+    /// when the cache was last written, it's written again periodically
+    pub last_store: parking_lot::Mutex<Instant>,
 }
 
 impl State {
@@ -88,10 +112,100 @@ impl State {
         }
         Ok(())
     }
+    pub fn push_to_partial_hash_group(&mut self, id: VfsId, partial_hash: PartialHash) {
+        self.forget_partial_hash(id);
+
+        let size = self.tree[id].file_size.unwrap();
+
+        self.partial_hashes.entry((size,partial_hash))
+            .or_insert_with(|| PartialHashGroup{
+                entries: Vec::new(),
+                size,
+                partial_hash,
+            })
+            .entries.push(id);
+
+        self.file_partial_hashes.insert(id, partial_hash);
+        *self.partial_hashed_sizes.entry(size).or_default() += 1;
+    }
+    /// Remove the entry from its partial hash group, so that it's a read candidate again if its size isn't unique
+    pub fn forget_partial_hash(&mut self, id: VfsId) {
+        if let Some(partial_hash) = self.file_partial_hashes.remove(&id) {
+            let size = self.tree[id].file_size.unwrap();
+            if let Entry::Occupied(mut v) = self.partial_hashes.entry((size,partial_hash)) {
+                v.get_mut().entries.retain(|&e| e != id );
+                if v.get().entries.is_empty() {
+                    v.remove();
+                }
+            }
+            if let Entry::Occupied(mut v) = self.partial_hashed_sizes.entry(size) {
+                *v.get_mut() -= 1;
+                if *v.get() == 0 {
+                    v.remove();
+                }
+            }
+        }
+    }
+    /// Files whose partial hash is unique don't need to be fully hashed. True if the file wasn't partially hashed
+    ///
+    /// Also true if any other file of the size has no partial hash, e.g. an archive or a file which failed to read, as it's fully hashed anyway
+    pub fn more_than_one_partial_hash(&self, id: VfsId) -> bool {
+        match self.file_partial_hashes.get(&id) {
+            Some(&partial_hash) => {
+                let size = self.tree[id].file_size.unwrap();
+                let partial_hashed = self.partial_hashed_sizes.get(&size).copied().unwrap_or(0);
+                self.sizes.get(&size).map_or(0, |e| e.entries.len()) > partial_hashed ||
+                self.partial_hashes.get(&(size,partial_hash))
+                    .map_or(false, |e| e.entries.len() > 1)
+            },
+            None => true,
+        }
+    }
+    /// Remove the entry from its size and hash groups, e.g. because it changed or is gone
+    pub fn remove_from_groups(&mut self, id: VfsId) {
+        self.forget_partial_hash(id);
+
+        let e = &self.tree[id];
+
+        for (typ,size,hash) in [
+            (VfsEntryType::File,e.file_size,&e.file_hash),
+            (VfsEntryType::Dir,e.dir_size,&e.dir_hash),
+        ] {
+            if let Some(size) = size {
+                if let Entry::Occupied(mut v) = self.sizes.entry(size) {
+                    v.get_mut().entries.retain(|e| e != &(typ,id) );
+                    if v.get().entries.is_empty() {
+                        v.remove();
+                    }
+                }
+            }
+            if let Some(hash) = hash {
+                if let Entry::Occupied(mut v) = self.hashes.entry(hash.clone()) {
+                    v.get_mut().entries.retain(|e| e != &(typ,id) );
+                    if v.get().entries.is_empty() {
+                        v.remove();
+                    }
+                }
+            }
+        }
+    }
+    /// Drop the hash of a file from its hash group, e.g. if it failed verification or its archive wasn't decoded completely, so that it's hashed again. The size group is kept
+    pub fn drop_file_hash(&mut self, id: VfsId) {
+        self.remove_from_groups(id);
+        self.tree[id].file_hash = None;
+        if self.tree[id].file_size.is_some() {
+            self.push_to_size_group(id,true,false).unwrap();
+        }
+    }
     pub fn more_than_one_size(&self, size: Size) -> bool {
         self.sizes.get(&size)
             .map_or(false, |e| e.entries.len() > 1)
     }
+    /// A file of the size is already hashed, e.g. from the cache. It has no partial hash, so the partial hashes of the others can't rule it out
+    pub fn any_hashed_of_size(&self, size: Size) -> bool {
+        self.sizes.get(&size)
+            .map_or(false, |e| e.entries.iter().any(|&(t,id)| t == VfsEntryType::File && self.tree[id].file_hash.is_some() ) )
+    }
     pub fn is_file_read_candidate(&self, id: VfsId, opts: &Opts) -> bool {
         let mut do_hash = true;
         //only hash if no hash
@@ -102,6 +216,8 @@ impl State {
         if !opts.zip_by_extension(&self.tree[id].path) {
             //only hash if non-unique size or possible archive
             do_hash &= self.more_than_one_size(self.tree[id].file_size.unwrap());
+            //only hash if non-unique partial hash
+            do_hash &= self.more_than_one_partial_hash(id);
             //only hash if min file size or possible archive
             do_hash &= self.tree[id].file_size.unwrap() >= opts.scan_size_min && self.tree[id].file_size.unwrap() <= opts.scan_size_max;
         }
@@ -128,12 +244,23 @@ impl State {
         None
     }*/
 
-    pub fn new(cache_allowed: bool) -> Self {
+    pub fn new(cache_allowed: bool, hash_algorithm: HashAlgorithm) -> Self {
         Self{
             tree: Vfs::new(),
             sizes: FxHashMap::with_capacity_and_hasher(16384, Default::default()),
             hashes: FxHashMap::with_capacity_and_hasher(16384, Default::default()),
+            partial_hashes: FxHashMap::default(),
+            file_partial_hashes: FxHashMap::default(),
+            partial_hashed_sizes: FxHashMap::default(),
             cache_allowed,
+            btrfs_generations: Vec::new(),
+            subvolumes: Vec::new(),
+            hash_algorithm,
+            progress: Arc::new(Progress::default()),
+            errors: Arc::new(ErrorLog::default()),
+            checkpoint: None,
+            root_caches: Vec::new(),
+            last_store: parking_lot::Mutex::new(Instant::now()),
         }
     }
 }
@@ -0,0 +1,169 @@
+use super::*;
+use std::{fs::read_to_string, os::unix::fs::FileTypeExt, path::{Path, PathBuf}};
+use rustc_hash::FxHashMap;
+use state::State;
+use opts::Opts;
+use vfs::VfsId;
+
+/// How the files are read in the hash pass
+#[derive(Clone,Copy,PartialEq,Debug)]
+pub enum StorageMode {
+    /// Detect per mount from /sys/block/*/queue/rotational
+    Auto,
+    /// One sequential reader in physical order, for HDDs
+    Rotational,
+    /// Many concurrent readers, for SSD/NVMe
+    NonRotational,
+}
+
+/// A mount of /proc/self/mountinfo
+pub struct Mount {
+    pub mount_point: PathBuf,
+    /// the directory of the filesystem mounted, e.g. a btrfs subvolume or the source of a bind mount
+    pub root: String,
+    /// major:minor as in /sys/dev/block
+    pub device: String,
+    pub source: String,
+    /// The sysfs dir of the whole disk backing the mount, None if unknown, e.g. for network filesystems
+    pub disk: Option<PathBuf>,
+    /// None if unknown
+    pub rotational: Option<bool>,
+}
+
+pub struct Mounts {
+    mounts: Vec<Mount>,
+}
+
+impl Mounts {
+    pub fn load() -> std::io::Result<Self> {
+        let mut mounts = Vec::new();
+
+        for line in read_to_string("/proc/self/mountinfo")?.lines() {
+            // id parent major:minor root mount_point options [optional fields] - fstype source super_options
+            let fields = line.split(' ').collect::<Vec<_>>();
+            let sep = match fields.iter().position(|&f| f == "-" ) {
+                Some(v) => v,
+                None => continue,
+            };
+            if fields.len() < 5 || fields.len() < sep + 3 {continue;}
+
+            let device = fields[2].to_owned();
+            let source = unescape(fields[sep + 2]);
+            let disk = backing_disk(&device, &source);
+
+            mounts.push(Mount {
+                mount_point: PathBuf::from(unescape(fields[4])),
+                root: unescape(fields[3]),
+                rotational: disk.as_deref().and_then(read_rotational),
+                disk,
+                device,
+                source,
+            });
+        }
+
+        Ok(Self{mounts})
+    }
+
+    /// The mount containing `path`, the last one mounted if stacked
+    pub fn mount_of(&self, path: &Path) -> Option<&Mount> {
+        self.mounts.iter()
+            .filter(|m| path.starts_with(&m.mount_point) )
+            .max_by_key(|m| m.mount_point.as_os_str().len() )
+    }
+}
+
+impl Mount {
+    /// The filesystem UUID of the source device, from /dev/disk/by-uuid
+    pub fn uuid(&self) -> Option<String> {
+        let source = Path::new(&self.source).canonicalize().ok()?;
+
+        std::fs::read_dir("/dev/disk/by-uuid").ok()?
+            .filter_map(Result::ok)
+            .find(|e| e.path().canonicalize().is_ok_and(|p| p == source ) )
+            .and_then(|e| e.file_name().into_string().ok() )
+    }
+}
+
+/// Undo the octal escapes of whitespace and backslashes in mountinfo
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('\\') {
+        out.push_str(&rest[..i]);
+        match rest.get(i+1..i+4).and_then(|o| u8::from_str_radix(o, 8).ok() ) {
+            Some(c) => {
+                out.push(c as char);
+                rest = &rest[i+4..];
+            },
+            None => {
+                out.push('\\');
+                rest = &rest[i+1..];
+            },
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// `disk` is the disk dir in /sys/devices
+fn read_rotational(disk: &Path) -> Option<bool> {
+    match read_to_string(disk.join("queue/rotational")).ok()?.trim() {
+        "0" => Some(false),
+        "1" => Some(true),
+        _ => None,
+    }
+}
+
+/// The sysfs dir of the disk of a mount. Filesystems like btrfs report an anonymous device per subvolume, then the source device is used
+fn backing_disk(device: &str, source: &str) -> Option<PathBuf> {
+    let sys = if !device.starts_with("0:") {
+        Path::new("/sys/dev/block").join(device)
+    }else{
+        // e.g. /dev/mapper/x -> /dev/dm-0
+        let source = Path::new(source).canonicalize().ok()?;
+        if !source.metadata().ok()?.file_type().is_block_device() {return None;}
+        Path::new("/sys/class/block").join(source.file_name()?)
+    };
+    let sys = sys.canonicalize().ok()?;
+
+    // partitions have no queue, their disk has
+    if sys.join("partition").exists() {
+        sys.parent().map(Path::to_owned)
+    }else{
+        Some(sys)
+    }
+}
+
+/// Split the files into the ones read sequentially and the ones read concurrently, grouped by their disk.
+///
+/// Devices of unknown type are treated as rotational
+pub fn split_by_storage(ids: impl Iterator<Item=VfsId>, state: &State, opts: &Opts) -> (Vec<VfsId>,Vec<Vec<VfsId>>) {
+    if opts.storage == StorageMode::Rotational {
+        return (ids.collect(),Vec::new());
+    }
+
+    let mounts = match Mounts::load() {
+        Ok(v) => v,
+        Err(e) => {
+            dprintln!("\tError reading mounts: {}",e);
+            Mounts{mounts: Vec::new()}
+        },
+    };
+
+    let mut sequential = Vec::new();
+    let mut by_disk = FxHashMap::<&Path,Vec<VfsId>>::default();
+
+    for id in ids {
+        let mount = mounts.mount_of(&state.tree[id].path);
+        let parallel = opts.storage == StorageMode::NonRotational || mount.is_some_and(|m| m.rotational == Some(false) );
+        if parallel {
+            // the subvolumes of a btrfs filesystem share their disk
+            let disk = mount.map_or(Path::new(""), |m| m.disk.as_deref().unwrap_or(Path::new(&m.device)) );
+            by_disk.entry(disk).or_default().push(id);
+        }else{
+            sequential.push(id);
+        }
+    }
+
+    (sequential, by_disk.into_values().collect())
+}
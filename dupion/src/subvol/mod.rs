@@ -0,0 +1,97 @@
+use super::*;
+use std::{fs::Metadata, os::unix::fs::MetadataExt, path::{Path, PathBuf}};
+use btrfs::{FileDescriptor, get_root_id, is_subvolume_readonly};
+use parking_lot::RwLock;
+use state::State;
+use opts::Opts;
+
+/// inode number of the root directory of every btrfs subvolume
+pub const SUBVOL_ROOT_INODE: u64 = 256;
+
+/// How read-only btrfs snapshots are handled
+#[derive(Clone,Copy,PartialEq,Debug)]
+pub enum SnapshotMode {
+    /// Scan and report them like any other directory
+    All,
+    /// Don't scan them
+    SkipReadonly,
+    /// Scan them, but only as references: they don't form duplicate groups by themselves and are never deduped into or quarantined
+    Reference,
+}
+
+#[derive(Clone,Debug)]
+pub struct Subvolume {
+    /// root directory of the subvolume
+    pub path: PathBuf,
+    pub id: u64,
+    pub readonly: bool,
+}
+
+impl Subvolume {
+    /// Check if `dir` is the root of a btrfs subvolume
+    pub fn probe(dir: &Path, meta: &Metadata) -> Option<Self> {
+        // cheap check first, other filesystems may use inode 256 too
+        if meta.ino() != SUBVOL_ROOT_INODE {return None;}
+
+        let fd = FileDescriptor::open(dir, libc::O_RDONLY | libc::O_DIRECTORY).ok()?;
+
+        Some(Self {
+            path: dir.to_owned(),
+            id: get_root_id(fd.get_value()).ok()?,
+            readonly: is_subvolume_readonly(fd.get_value()).ok()?,
+        })
+    }
+}
+
+impl State {
+    pub fn add_subvolume(&mut self, subvol: Subvolume) {
+        if !self.subvolumes.iter().any(|s| s.path == subvol.path ) {
+            self.subvolumes.push(subvol);
+        }
+    }
+
+    /// The innermost found subvolume containing `path`
+    pub fn subvolume_of(&self, path: &Path) -> Option<&Subvolume> {
+        self.subvolumes.iter()
+            .filter(|s| path.starts_with(&s.path) )
+            .max_by_key(|s| s.path.as_os_str().len() )
+    }
+
+    /// Entries in read-only snapshots with SnapshotMode::Reference
+    pub fn is_reference(&self, path: &Path, opts: &Opts) -> bool {
+        opts.snapshots == SnapshotMode::Reference && self.subvolume_of(path).is_some_and(|s| s.readonly )
+    }
+
+    /// Subvolume annotation for the outputs, if the scan found more than one subvolume
+    pub fn subvolume_annotation(&self, path: &Path, opts: &Opts) -> String {
+        if self.subvolumes.len() < 2 {return String::new();}
+
+        match self.subvolume_of(path) {
+            Some(s) => {
+                let path = match opts.path_disp(&s.path) {
+                    "" => s.path.to_str().unwrap(),
+                    p => p,
+                };
+                format!(" [subvol {} {}{}]", s.id, path, if s.readonly {" ro"} else {""})
+            },
+            None => String::new(),
+        }
+    }
+}
+
+/// Detect subvolume boundaries while walking. Returns false if the directory should be skipped
+pub fn subvolume_dir_filter(dir: &Path, meta: &Metadata, state: &RwLock<State>, opts: &Opts) -> bool {
+    let subvol = match Subvolume::probe(dir, meta) {
+        Some(v) => v,
+        None => return true,
+    };
+
+    // the scan roots themselves are always scanned
+    let skip = subvol.readonly && opts.snapshots == SnapshotMode::SkipReadonly && !opts.paths.iter().any(|p| p.starts_with(dir) );
+
+    opts.log_verbosed(if skip {"SKIP SNAPSHOT"} else {"SUBVOL"}, dir);
+
+    state.write().add_subvolume(subvol);
+
+    !skip
+}
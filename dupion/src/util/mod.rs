@@ -1,7 +1,7 @@
 use super::*;
 use std::{sync::Arc, ops::Range};
-use group::{SizeGroup, HashGroup};
-use std::{io::{Seek, Read}, sync::{atomic::{Ordering, AtomicUsize, AtomicBool, AtomicU64}}, time::Duration, ops::{DerefMut, Deref}};
+use group::{SizeGroup, HashGroup, PartialHashGroup};
+use std::{io::{Seek, Read}, sync::{atomic::{Ordering, AtomicUsize, AtomicBool, AtomicU64, AtomicPtr}}, time::Duration, ops::{DerefMut, Deref}};
 use parking_lot::RawMutex;
 use parking_lot::lock_api::RawMutex as _;
 use sysinfo::*;
@@ -9,24 +9,170 @@ use sysinfo::*;
 pub type Size = u64;
 pub type Hash = Arc<[u8;32]>;
 pub const HASH_SIZE: usize = 32;
+pub const ENCODED_BLOCK_SIZE: u64 = 4096;
 
 pub type Sizes = rustc_hash::FxHashMap<Size,SizeGroup>;
 pub type Hashes = rustc_hash::FxHashMap<Hash,HashGroup>;
+/// xxh3-128 of the start and end of a file
+pub type PartialHash = u128;
+pub type PartialHashes = rustc_hash::FxHashMap<(Size,PartialHash),PartialHashGroup>;
 
 pub static DISP_ANSI: AtomicBool = AtomicBool::new(false);
 
-pub static DISP_FOUND_BYTES: AtomicU64 = AtomicU64::new(0);
-pub static DISP_FOUND_FILES: AtomicU64 = AtomicU64::new(0);
-pub static DISP_RELEVANT_BYTES: AtomicU64 = AtomicU64::new(0);
-pub static DISP_RELEVANT_FILES: AtomicU64 = AtomicU64::new(0);
-pub static DISP_PROCESSED_BYTES: AtomicU64 = AtomicU64::new(0);
-pub static DISP_PROCESSED_FILES: AtomicU64 = AtomicU64::new(0);
-pub static DISP_DEDUPED_BYTES: AtomicU64 = AtomicU64::new(u64::MAX);
-pub static DISP_PREV: AtomicU64 = AtomicU64::new(0);
-pub static DISP_ENABLED: AtomicBool = AtomicBool::new(false);
-pub static VFS_STORE_NOTIF: AtomicBool = AtomicBool::new(false);
 pub static ALLOC_MON: AtomicUsize = AtomicUsize::new(0);
 
+/// The scan interrupted by SIGINT/SIGTERM, set by catch_interrupts
+static INTERRUPT_TARGET: AtomicPtr<Progress> = AtomicPtr::new(std::ptr::null_mut());
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    let target = INTERRUPT_TARGET.load(Ordering::Acquire);
+    // the second signal doesn't wait for the scan to stop
+    if target.is_null() || unsafe { (*target).interrupted.swap(true, Ordering::Relaxed) } {
+        unsafe { libc::_exit(130) }
+    }
+}
+
+/// Interrupt the scan or dedup of `progress` on SIGINT/SIGTERM, so that it can stop and store the cache. A second signal exits right away. With None, they terminate the process again
+pub fn catch_interrupts(progress: Option<&Arc<Progress>>) {
+    let set_handler = |handler: libc::sighandler_t| unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    };
+
+    // the target is set before the handler is installed, and released after it's removed
+    let prev = match progress {
+        Some(p) => {
+            let prev = INTERRUPT_TARGET.swap(Arc::into_raw(p.clone()) as *mut Progress, Ordering::AcqRel);
+            set_handler(on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t);
+            prev
+        },
+        None => {
+            set_handler(libc::SIG_DFL);
+            INTERRUPT_TARGET.swap(std::ptr::null_mut(), Ordering::AcqRel)
+        },
+    };
+
+    if !prev.is_null() {
+        drop(unsafe { Arc::from_raw(prev as *const Progress) });
+    }
+}
+
+/// The XDG base directory from `var`, e.g. XDG_CONFIG_HOME, or `fallback` below $HOME, e.g. ".config"
+pub fn xdg_dir(var: &str, fallback: &str) -> Option<std::path::PathBuf> {
+    match std::env::var_os(var) {
+        Some(v) if !v.is_empty() => Some(v.into()),
+        _ => Some(std::path::PathBuf::from(std::env::var_os("HOME")?).join(fallback)),
+    }
+}
+
+/// A directory in the temp dir for the tests, removed on drop
+#[cfg(test)]
+pub struct TestDir(pub std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    pub fn new(name: &str) -> Self {
+        static N: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!("dupion-test-{}-{}-{}", std::process::id(), name, N.fetch_add(1,Ordering::Relaxed)));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path.canonicalize().unwrap())
+    }
+
+    /// Write a file below the dir and return its path
+    pub fn file(&self, name: &str, data: &[u8]) -> std::path::PathBuf {
+        let path = self.0.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Counters of a scan, owned by its State and read by the progress display
+pub struct Progress {
+    /// Stops the scan at the next check_interrupted
+    pub interrupted: AtomicBool,
+    /// Where the sections report their progress
+    pub sink: parking_lot::RwLock<Option<Arc<dyn progress::ProgressSink>>>,
+    /// The phase of the running section, set by section_start
+    pub section: parking_lot::Mutex<Option<phase::Phase>>,
+    pub found_bytes: AtomicU64,
+    pub found_files: AtomicU64,
+    /// files to be hashed
+    pub relevant_bytes: AtomicU64,
+    pub relevant_files: AtomicU64,
+    pub processed_bytes: AtomicU64,
+    pub processed_files: AtomicU64,
+    /// u64::MAX outside of the dedup
+    pub deduped_bytes: AtomicU64,
+}
+
+/// Values of the Progress counters at one point
+#[derive(Clone,Copy,Debug,Default,PartialEq)]
+pub struct ProgressSnapshot {
+    pub found_bytes: u64,
+    pub found_files: u64,
+    pub relevant_bytes: u64,
+    pub relevant_files: u64,
+    pub processed_bytes: u64,
+    pub processed_files: u64,
+    /// None outside of the dedup
+    pub deduped_bytes: Option<u64>,
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self {
+            interrupted: AtomicBool::new(false),
+            sink: Default::default(),
+            section: Default::default(),
+            found_bytes: AtomicU64::new(0),
+            found_files: AtomicU64::new(0),
+            relevant_bytes: AtomicU64::new(0),
+            relevant_files: AtomicU64::new(0),
+            processed_bytes: AtomicU64::new(0),
+            processed_files: AtomicU64::new(0),
+            deduped_bytes: AtomicU64::new(u64::MAX),
+        }
+    }
+}
+
+impl Progress {
+    /// Stop the scan, e.g. from another thread
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::Relaxed)
+    }
+
+    /// Err if interrupted, for the loops of the scan
+    pub fn check_interrupted(&self) -> AnyhowResult<()> {
+        ensure!(!self.is_interrupted(), "Interrupted");
+        Ok(())
+    }
+
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        let deduped_bytes = self.deduped_bytes.load(Ordering::Relaxed);
+        ProgressSnapshot {
+            found_bytes: self.found_bytes.load(Ordering::Relaxed),
+            found_files: self.found_files.load(Ordering::Relaxed),
+            relevant_bytes: self.relevant_bytes.load(Ordering::Relaxed),
+            relevant_files: self.relevant_files.load(Ordering::Relaxed),
+            processed_bytes: self.processed_bytes.load(Ordering::Relaxed),
+            processed_files: self.processed_files.load(Ordering::Relaxed),
+            deduped_bytes: (deduped_bytes != u64::MAX).then_some(deduped_bytes),
+        }
+    }
+}
+
 pub struct MutexedReader<R> {
     pub inner: R,
     pub mutex: ZeroLock,
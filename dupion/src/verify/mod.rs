@@ -0,0 +1,199 @@
+use super::*;
+use std::{fs::File, io::{ErrorKind, Read}, path::Path, sync::Arc};
+use reapfrog::MultiFileReadahead;
+use rustc_hash::FxHashSet;
+use state::State;
+use opts::Opts;
+use vfs::VfsId;
+use errors::ScanErrorKind;
+
+struct VerifyPair {
+    path: Arc<Path>,
+    senpai_path: Arc<Path>,
+}
+
+impl AsRef<Path> for VerifyPair {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[derive(Default)]
+pub struct Verified {
+    /// files whose content differs from their senpai
+    pub mismatched: FxHashSet<VfsId>,
+    /// files which couldn't be compared
+    pub failed: FxHashSet<VfsId>,
+}
+
+impl Verified {
+    pub fn passed(&self, id: VfsId) -> bool {
+        !self.mismatched.contains(&id) && !self.failed.contains(&id)
+    }
+
+    /// Split the mismatched files off their hash groups
+    pub fn drop_mismatched_hashes(&self, state: &mut State) {
+        for &id in &self.mismatched {
+            state.drop_file_hash(id);
+        }
+    }
+}
+
+/// Compare the files byte-by-byte against their senpai, given as (senpai, file).
+///
+/// The files are streamed with readahead in the given order, the senpais are read alongside
+pub fn verify_files(pairs: &[(VfsId,VfsId)], state: &State, opts: &Opts) -> Verified {
+    let mut verified = Verified::default();
+
+    let mut reaper = MultiFileReadahead::new(
+        pairs.iter()
+            .map(|&(senpai,id)| VerifyPair{
+                path: state.tree[id].path.clone(),
+                senpai_path: state.tree[senpai].path.clone(),
+            })
+    );
+
+    reaper.dropbehind(opts.cache_dropbehind);
+    reaper.budget(opts.prefetch_budget);
+
+    let mut buf = vec![0;opts.read_buffer];
+    let mut senpai_buf = vec![0;opts.read_buffer];
+
+    // the reader yields exactly one result per pair, in order
+    let mut i = 0;
+
+    while let Some(reader) = reaper.next() {
+        let (_,id) = pairs[i];
+        i += 1;
+
+        let mut reader = match reader {
+            Ok(v) => v,
+            Err(e) => {
+                state.errors.push(opts,ScanErrorKind::Open,&*state.tree[id].path,e);
+                verified.failed.insert(id);
+                continue;
+            },
+        };
+
+        let VerifyPair{path,senpai_path} = reader.data();
+        let (path,senpai_path) = (path.clone(),senpai_path.clone());
+
+        opts.log_verbosed("VERIFY", &path);
+
+        let equal = compare(&mut &mut reader, &senpai_path, &mut buf, &mut senpai_buf);
+
+        match equal {
+            Ok(true) => {},
+            Ok(false) => {
+                state.errors.push(opts,ScanErrorKind::Verify,&*path,format!("differs from {}, the cached hash is corrupt or stale",senpai_path.display()));
+                verified.mismatched.insert(id);
+            },
+            Err(e) => {
+                state.errors.push(opts,ScanErrorKind::Verify,&*path,e);
+                verified.failed.insert(id);
+            },
+        }
+    }
+
+    verified
+}
+
+fn compare(reader: &mut impl Read, senpai_path: &Path, buf: &mut [u8], senpai_buf: &mut [u8]) -> std::io::Result<bool> {
+    let mut senpai = File::open(senpai_path)?;
+
+    loop {
+        let n = match reader.read(buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if n == 0 {
+            // the senpai must end here too
+            return Ok(senpai.read(&mut senpai_buf[..1])? == 0);
+        }
+        match senpai.read_exact(&mut senpai_buf[..n]) {
+            Ok(()) => {},
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e),
+        }
+        if buf[..n] != senpai_buf[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs::OpenOptions, os::unix::fs::FileExt};
+    use driver::platterwalker::hashed;
+    use util::TestDir;
+
+    fn opts() -> Opts {
+        Opts{threads: 1, ..Default::default()}
+    }
+
+    fn group_len(state: &State, id: VfsId) -> usize {
+        state.hashes[state.tree[id].file_hash.as_ref().unwrap()].entries.len()
+    }
+
+    #[test]
+    fn matching_group() {
+        let dir = TestDir::new("verify");
+        let opts = opts();
+        let data = vec![3u8; 100_000];
+        let paths = [dir.file("a", &data), dir.file("b", &data), dir.file("c", &data)];
+        let (mut state,ids) = hashed(&paths, &opts);
+        assert_eq!(group_len(&state, ids[0]), 3);
+
+        let verified = verify_files(&[(ids[0],ids[1]),(ids[0],ids[2])], &state, &opts);
+
+        assert!(verified.mismatched.is_empty() && verified.failed.is_empty());
+        assert!(ids.iter().all(|&id| verified.passed(id) ));
+        verified.drop_mismatched_hashes(&mut state);
+        assert_eq!(group_len(&state, ids[0]), 3);
+        assert!(state.errors.is_empty());
+    }
+
+    #[test]
+    fn last_byte_differs() {
+        let dir = TestDir::new("verify");
+        let opts = opts();
+        let data = vec![3u8; 100_000];
+        let paths = [dir.file("a", &data), dir.file("b", &data), dir.file("c", &data)];
+        let (mut state,ids) = hashed(&paths, &opts);
+
+        OpenOptions::new().write(true).open(&paths[2]).unwrap().write_all_at(&[4], data.len() as u64 - 1).unwrap();
+
+        let verified = verify_files(&[(ids[0],ids[1]),(ids[0],ids[2])], &state, &opts);
+
+        assert!(verified.passed(ids[1]));
+        assert!(verified.mismatched.contains(&ids[2]) && !verified.failed.contains(&ids[2]));
+
+        verified.drop_mismatched_hashes(&mut state);
+        assert_eq!(group_len(&state, ids[0]), 2);
+        assert!(state.tree[ids[2]].file_hash.is_none());
+        assert!(state.errors.errors().iter().any(|e| e.kind == ScanErrorKind::Verify && e.path.as_deref() == Some(&*paths[2]) ));
+    }
+
+    #[test]
+    fn truncated_after_hashing() {
+        let dir = TestDir::new("verify");
+        let opts = opts();
+        let data = vec![3u8; 100_000];
+        let paths = [dir.file("a", &data), dir.file("b", &data), dir.file("c", &data)];
+        let (mut state,ids) = hashed(&paths, &opts);
+
+        // the file itself, and the senpai of another pair
+        OpenOptions::new().write(true).open(&paths[1]).unwrap().set_len(50_000).unwrap();
+
+        let verified = verify_files(&[(ids[0],ids[1]),(ids[1],ids[2])], &state, &opts);
+
+        assert!(verified.mismatched.contains(&ids[1]));
+        assert!(verified.mismatched.contains(&ids[2]));
+
+        verified.drop_mismatched_hashes(&mut state);
+        assert_eq!(group_len(&state, ids[0]), 1);
+        assert!(state.tree[ids[1]].file_hash.is_none());
+    }
+}
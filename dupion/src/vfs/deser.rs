@@ -11,16 +11,21 @@ use serde_bytes::ByteBuf;
 use serde_derive::*;
 use std::borrow::Cow;
 use std::hash::BuildHasherDefault;
-use std::io::BufRead;
-use std::{io::BufReader, sync::atomic::Ordering};
+use std::io::{BufRead, Write};
+use std::io::BufReader;
 use state::State;
-use util::{VFS_STORE_NOTIF, Hash, Size};
+use util::{Hash, Size};
+use driver::{btrfsgen::SubvolGeneration, platterwalker::HashCheckpoint};
+use hasher::HashAlgorithm;
 use std::fs::File;
+use std::time::{Duration, Instant};
 
 #[derive(Serialize,Deserialize)]
 struct EntryIntermediateMsgPack<'a> {
     path: Cow<'a,str>,
     ctime: Option<i64>,
+    #[serde(default)] 
+    dir_mtime: Option<i64>,
     file_size: Option<Size>,
     file_hash: Option<ByteBuf>,
     childs: Vec<VfsId>,
@@ -33,12 +38,18 @@ struct EntryIntermediateMsgPack<'a> {
     dedup_state: Option<bool>,
     #[serde(default)] 
     phys: Option<u64>,
+    #[serde(default)] 
+    n_extends: Option<usize>,
+    #[serde(default)] 
+    encoded_blocks: u32,
 }
 
 #[derive(Deserialize)]
 struct EntryIntermediateJson<'a> {
     path: Cow<'a,str>,
     ctime: Option<i64>,
+    #[serde(default)] 
+    dir_mtime: Option<i64>,
     file_size: Option<Size>,
     file_hash: Option<Cow<'a,str>>,
     childs: Vec<VfsId>,
@@ -51,6 +62,10 @@ struct EntryIntermediateJson<'a> {
     dedup_state: Option<bool>,
     #[serde(default)] 
     phys: Option<u64>,
+    #[serde(default)] 
+    n_extends: Option<usize>,
+    #[serde(default)] 
+    encoded_blocks: u32,
 }
 
 impl<'a> EntryIntermediateMsgPack<'a> {
@@ -59,6 +74,7 @@ impl<'a> EntryIntermediateMsgPack<'a> {
         Self {
             path: Cow::Borrowed(entry.path.to_str().unwrap()),
             ctime: entry.ctime,
+            dir_mtime: entry.dir_mtime,
             file_size: entry.file_size,
             file_hash,
             childs: entry.childs.clone(),
@@ -67,6 +83,8 @@ impl<'a> EntryIntermediateMsgPack<'a> {
             upgrade: entry.failure,
             dedup_state: entry.dedup_state,
             phys: entry.phys,
+            n_extends: entry.n_extends,
+            encoded_blocks: entry.encoded_blocks,
         }
     }
 
@@ -77,6 +95,7 @@ impl<'a> EntryIntermediateMsgPack<'a> {
             plc: to_plc(&path),
             path,
             ctime: self.ctime,
+            dir_mtime: self.dir_mtime,
             file_size: self.file_size,
             dir_size: None,
             file_hash: self.file_hash.and_then(|h| intern_hash_raw(&h, interner).transpose() ).transpose()?,
@@ -94,8 +113,9 @@ impl<'a> EntryIntermediateMsgPack<'a> {
             failure: self.upgrade,
             treediff_stat: 0,
             dedup_state: self.dedup_state,
-            phys: None,
-            n_extends: None,
+            phys: self.phys,
+            n_extends: self.n_extends,
+            encoded_blocks: self.encoded_blocks,
         })
     }
 }
@@ -108,6 +128,7 @@ impl<'a> EntryIntermediateJson<'a> {
             plc: to_plc(&path),
             path,
             ctime: self.ctime,
+            dir_mtime: self.dir_mtime,
             file_size: self.file_size,
             dir_size: None,
             file_hash: self.file_hash.and_then(|h| decode_and_intern_hash_base64(&h, interner).transpose() ).transpose()?,
@@ -125,13 +146,14 @@ impl<'a> EntryIntermediateJson<'a> {
             failure: self.upgrade,
             treediff_stat: 0,
             dedup_state: self.dedup_state,
-            phys: None,
-            n_extends: None,
+            phys: self.phys,
+            n_extends: self.n_extends,
+            encoded_blocks: self.encoded_blocks,
         })
     }
 }
 
-struct VfsEntriesMsgPack(Vec<VfsEntry>);
+struct VfsEntriesMsgPack(Vec<VfsEntry>,Vec<SubvolGeneration>,Option<HashAlgorithm>,Option<HashCheckpoint>);
 struct VfsEntriesJson(Vec<VfsEntry>);
 
 impl<'de> Deserialize<'de> for VfsEntriesMsgPack {
@@ -152,15 +174,22 @@ impl<'de> Deserialize<'de> for VfsEntriesMsgPack {
                     seq.size_hint().map_or(16384, |s| s - 1 )
                 );
 
-                if !seq.next_element::<CacheHeader>()?.is_some_and(|h| h.version == 4 ) {
-                    return Err(serde::de::Error::custom("Version not 4"));
-                }
+                let header = match seq.next_element::<CacheHeader>()? {
+                    Some(h) if h.version == 4 => h,
+                    _ => return Err(serde::de::Error::custom("Version not 4")),
+                };
 
                 while let Some(value) = seq.next_element::<EntryIntermediateMsgPack>()? {
                     entries.push(value.into_entry(&mut self.interner).map_err(serde::de::Error::custom)?);
                 }
 
-                Ok(VfsEntriesMsgPack(entries))
+                // caches without the field are from before the algorithm was selectable
+                let hash_algorithm = match header.hash_algorithm.as_deref() {
+                    None => Some(HashAlgorithm::Blake3),
+                    Some(id) => HashAlgorithm::from_id(id),
+                };
+
+                Ok(VfsEntriesMsgPack(entries,header.btrfs_generations.into_owned(),hash_algorithm,header.checkpoint.map(Cow::into_owned)))
             }
         }
 
@@ -205,11 +234,19 @@ impl<'de> Deserialize<'de> for VfsEntriesJson {
 }
 
 #[derive(Serialize,Deserialize)]
-struct CacheHeader {
+struct CacheHeader<'a> {
     version: usize,
+    #[serde(default)]
+    btrfs_generations: Cow<'a,[SubvolGeneration]>,
+    /// HashAlgorithm::id of the file hashes
+    #[serde(default)]
+    hash_algorithm: Option<Cow<'a,str>>,
+    /// of an interrupted scan
+    #[serde(default)]
+    checkpoint: Option<Cow<'a,HashCheckpoint>>,
 }
 
-struct VfsEntriesSerialize<'a>(&'a [VfsEntry]);
+struct VfsEntriesSerialize<'a>(&'a [VfsEntry],&'a [SubvolGeneration],HashAlgorithm,Option<&'a HashCheckpoint>);
 
 impl Serialize for VfsEntriesSerialize<'_> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -218,7 +255,12 @@ impl Serialize for VfsEntriesSerialize<'_> {
     {
         let mut serializer = serializer.serialize_seq(Some(self.0.len()+1))?;
 
-        serializer.serialize_element(&CacheHeader { version: 4 })?;
+        serializer.serialize_element(&CacheHeader {
+            version: 4,
+            btrfs_generations: Cow::Borrowed(self.1),
+            hash_algorithm: Some(Cow::Borrowed(self.2.id())),
+            checkpoint: self.3.map(Cow::Borrowed),
+        })?;
 
         for entry in self.0 {
             serializer.serialize_element(&EntryIntermediateMsgPack::from_entry(&entry))?;
@@ -228,52 +270,120 @@ impl Serialize for VfsEntriesSerialize<'_> {
     }
 }
 
+/// The contents of a cache file
+pub(crate) struct CacheContents {
+    pub entries: Vec<VfsEntry>,
+    pub btrfs_generations: Vec<SubvolGeneration>,
+    pub hash_algorithm: Option<HashAlgorithm>,
+    pub checkpoint: Option<HashCheckpoint>,
+}
+
+pub(crate) fn read_cache(path: &Path) -> anyhow::Result<CacheContents> {
+    let reader = File::open(path)?;
+
+    let buf_reader_size = zstd::zstd_safe::DCtx::in_size() * 8;
+
+    let mut buf_reader = BufReader::with_capacity(buf_reader_size, reader);
+
+    if buf_reader.fill_buf()?.starts_with(&ZSTD_MAGIC_NUMBER) {
+        let reader = zstd::stream::read::Decoder::with_buffer(buf_reader)?;
+        let VfsEntriesMsgPack(entries,btrfs_generations,hash_algorithm,checkpoint) = rmp_serde::from_read(reader)?;
+        Ok(CacheContents{entries, btrfs_generations, hash_algorithm, checkpoint})
+    } else {
+        let VfsEntriesJson(entries) = serde_json::from_reader(buf_reader)?;
+        Ok(CacheContents{entries, btrfs_generations: Vec::new(), hash_algorithm: Some(HashAlgorithm::Blake3), checkpoint: None})
+    }
+}
+
+/// Write the cache through a temporary file, so that an interrupted write leaves the old cache intact
+/// Where write_cache writes the cache before renaming it to `path`
+pub fn cache_tmp_path(path: &Path) -> PathBuf {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    tmp_path.into()
+}
+
+pub(crate) fn write_cache(path: &Path, entries: &[VfsEntry], btrfs_generations: &[SubvolGeneration], hash_algorithm: HashAlgorithm, checkpoint: Option<&HashCheckpoint>) -> anyhow::Result<()> {
+    let mut stor = Vec::with_capacity(1024*1024);
+    let mut writer = zstd::stream::write::Encoder::new(&mut stor, 3)?;
+    let mut ser = rmp_serde::Serializer::new(&mut writer).with_struct_map();
+    VfsEntriesSerialize(entries,btrfs_generations,hash_algorithm,checkpoint).serialize(&mut ser)?;
+    writer.finish()?;
+
+    // e.g. the default cache in the XDG cache dir
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty() ) {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let tmp_path = cache_tmp_path(path);
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(&stor)?;
+    tmp.sync_all()?;
+    drop(tmp);
+    std::fs::rename(&tmp_path,path)?;
+    Ok(())
+}
+
+/// How often the cache is written during the scan
+const STORE_INTERVAL: Duration = Duration::from_secs(600);
+
 impl State {
     pub fn eventually_store_vfs(&self, path: &Path, force: bool) {
         self.try_eventually_store_vfs(path, force).unwrap_or_else(|e| dprintln!("Error writing cache: {e}") )
     }
 
+    /// The root caches are only written if forced, e.g. at the end of the scan
     pub fn try_eventually_store_vfs(&self, path: &Path, force: bool) -> anyhow::Result<()> {
-        if self.cache_allowed && (force || VFS_STORE_NOTIF.swap(false,Ordering::Relaxed)) {
-            let mut stor = Vec::with_capacity(1024*1024);
-            let mut writer = zstd::stream::write::Encoder::new(&mut stor, 3)?;
-            let mut ser = rmp_serde::Serializer::new(&mut writer).with_struct_map();
-            VfsEntriesSerialize(&self.tree.entries).serialize(&mut ser)?;
-            writer.finish()?;
-            std::fs::write(path,&stor)?;
+        if self.cache_allowed && (force || self.last_store.lock().elapsed() >= STORE_INTERVAL) {
+            *self.last_store.lock() = Instant::now();
+            write_cache(path,&self.tree.entries,&self.btrfs_generations,self.hash_algorithm,self.checkpoint.as_ref())?;
             //dprintln!("Wrote cache");
+            if force {
+                self.store_root_caches();
+            }
         }
         Ok(())
     }
 
     pub fn eventually_load_vfs(&mut self, path: &Path) {
         self.try_eventually_load_vfs(path).unwrap_or_else(|e| dprintln!("Error reading cache: {e}") );
+        if self.cache_allowed {
+            self.load_root_caches();
+        }
     }
 
     pub fn try_eventually_load_vfs(&mut self, path: &Path) -> anyhow::Result<()> {
         if self.cache_allowed {
             let path_meta = path.metadata()?;
             if path_meta.is_file() {
-                let reader = File::open(&path)?;
-
-                let buf_reader_size = zstd::zstd_safe::DCtx::in_size() * 8;
-
-                let mut buf_reader = BufReader::with_capacity(buf_reader_size, reader);
-
-                if buf_reader.fill_buf()?.starts_with(&ZSTD_MAGIC_NUMBER) {
-                    let reader = zstd::stream::read::Decoder::with_buffer(buf_reader)?;
-                    let VfsEntriesMsgPack(entries) = rmp_serde::from_read(reader)?;
-                    self.tree.entries = entries;
-                } else {
-                    let VfsEntriesJson(entries) = serde_json::from_reader(buf_reader)?;
-                    self.tree.entries = entries;
-                }
+                let CacheContents{entries,btrfs_generations,hash_algorithm,checkpoint} = read_cache(path)?;
+                self.tree.entries = entries;
+                self.btrfs_generations = btrfs_generations;
+                self.checkpoint = checkpoint;
+                self.migrate_hash_algorithm(hash_algorithm);
             }
         }
         Ok(())
     }
 }
 
+impl State {
+    /// Discard the loaded hashes if they were made with another algorithm, so that the files are hashed again
+    fn migrate_hash_algorithm(&mut self, cached: Option<HashAlgorithm>) {
+        if cached == Some(self.hash_algorithm) {return;}
+
+        dprintln!(
+            "Cache was hashed with {}, rehashing with {}",
+            cached.map_or("an unknown algorithm", |a| a.id() ),
+            self.hash_algorithm.id(),
+        );
+
+        for e in &mut self.tree.entries {
+            e.file_hash = None;
+        }
+    }
+}
+
 const ZSTD_MAGIC_NUMBER: [u8;4] = 0xFD2F_B528_u32.to_le_bytes();
 
 const BASE64_ENGINE: base64::engine::GeneralPurpose = base64::engine::GeneralPurpose::new(
@@ -1,6 +1,6 @@
 use super::*;
 use std::{sync::{atomic::Ordering, Arc}, ffi::OsString};
-use util::{DISP_RELEVANT_FILES, Hash, DISP_RELEVANT_BYTES, Size};
+use util::{Hash, Progress, Size};
 
 use state::State;
 
@@ -9,7 +9,10 @@ use state::State;
 pub struct VfsEntry {
     pub path: Arc<Path>,
     pub plc: OsString,
+    /// ctime of files
     pub ctime: Option<i64>,
+    /// mtime in ns of directories read by the incremental scan
+    pub dir_mtime: Option<i64>,
     pub file_size: Option<Size>,
     pub dir_size: Option<Size>,
     pub file_hash: Option<Hash>,
@@ -29,9 +32,11 @@ pub struct VfsEntry {
     pub dedup_state: Option<bool>,
     pub phys: Option<u64>,
     pub n_extends: Option<usize>,
+    /// Amount of ENCODED_BLOCK_SIZE blocks in encoded (e.g. btrfs compressed) extents
+    pub encoded_blocks: u32,
 }
 
-const _: () = assert!(std::mem::size_of::<VfsEntry>() == 192);
+const _: () = assert!(std::mem::size_of::<VfsEntry>() == 208);
 
 impl VfsEntry {
     pub fn new(path: Arc<Path>) -> Self {
@@ -39,6 +44,7 @@ impl VfsEntry {
             plc: to_plc(&path),
             path,
             ctime: None,
+            dir_mtime: None,
             file_size: None,
             dir_size: None,
             file_hash: None,
@@ -58,14 +64,15 @@ impl VfsEntry {
             dedup_state: None,
             phys: Some(0),
             n_extends: None,
+            encoded_blocks: 0,
         }
     }
 
-    pub fn disp_add_relevant(&mut self) {
+    pub fn disp_add_relevant(&mut self, progress: &Progress) {
         if !self.disp_relevated && self.file_hash.is_none() {
             let size = self.file_size.unwrap();
-            DISP_RELEVANT_BYTES.fetch_add(size,Ordering::Relaxed);
-            DISP_RELEVANT_FILES.fetch_add(1,Ordering::Relaxed);
+            progress.relevant_bytes.fetch_add(size,Ordering::Relaxed);
+            progress.relevant_files.fetch_add(1,Ordering::Relaxed);
             self.disp_relevated = true;
         }
     }
@@ -110,6 +117,11 @@ impl VfsEntry {
         self.is_file || self.is_dir
     }
 
+    /// Was a file in the loaded cache
+    pub fn was_file(&self) -> bool {
+        self.was_file
+    }
+
     pub fn icon3(&self) -> char {
         match (self.is_dir,self.is_file) {
             (true,true) => 'A',
@@ -191,6 +203,7 @@ impl State {
             s.dedup_state = None;
             s.phys = Some(0);
             s.n_extends = None;
+            s.encoded_blocks = 0;
             s.ctime = Some(ctime);
             s.valid = true;
             false
@@ -216,6 +229,75 @@ impl State {
         true
     }
 
+    /// The entry and its childs are gone, e.g. deleted while watching
+    pub fn invalidate(&mut self, id: VfsId) {
+        self.for_recursive2(id, true, |s,id| {
+            s.remove_from_groups(id);
+            let e = &mut s.tree[id];
+            e.valid = false;
+            e.is_file = false;
+            e.is_dir = false;
+            e.was_file = false;
+            e.was_dir = false;
+        });
+    }
+
+    /// Reuse the cached childs of a directory which is unchanged since the last incremental scan. Returns the reused files and the subdirectories, which have to be checked separately.
+    ///
+    /// Returns None if the cached childs are incomplete and the directory has to be read
+    pub fn reuse_unchanged_dir(&mut self, id: VfsId) -> Option<(Vec<VfsId>,Vec<VfsId>)> {
+        let mut files = Vec::new();
+        let mut subdirs = Vec::new();
+
+        for &c in &self.tree[id].childs {
+            let e = &self.tree[c];
+            if e.was_file {
+                if e.file_size.is_none() || e.ctime.is_none() {
+                    return None;
+                }
+                files.push(c);
+            } else if e.was_dir {
+                subdirs.push(c);
+            }
+        }
+
+        self.tree[id].valid = true;
+        self.tree[id].is_dir = true;
+
+        for &c in &files {
+            self.set_valid(c);
+            self.tree[c].phys.get_or_insert(0);
+        }
+
+        Some((files,subdirs))
+    }
+
+    /// Record the mtime of a directory read by the incremental scan.
+    ///
+    /// The childs which weren't found again are gone and mustn't be reused by the next scan
+    pub fn set_dir_mtime(&mut self, id: VfsId, mtime: i64) {
+        self.tree[id].dir_mtime = Some(mtime);
+
+        for c in self.tree[id].childs.clone() {
+            if !self.tree[c].valid {
+                self.tree.for_recursive(c, true, |e| {
+                    e.was_file = false;
+                    e.was_dir = false;
+                });
+            }
+        }
+    }
+
+    /// Forget the cached entries below `id` which weren't found again by a full scan, so that they aren't restored by a later `set_valid`
+    pub fn forget_invalid(&mut self, id: VfsId) {
+        self.tree.for_recursive(id, true, |e| {
+            if !e.valid {
+                e.was_file = false;
+                e.was_dir = false;
+            }
+        });
+    }
+
     /*pub fn clean_old_validations(&mut self, id: VfsId) -> bool {
         self.for_recursive2(id, true, |s,id| {
             s.tree[id].was_file = false;
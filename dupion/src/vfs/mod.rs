@@ -4,6 +4,7 @@ use std::{ops::{IndexMut, Index, Deref}, path::{PathBuf, Path, Component}, sync:
 
 pub mod entry;
 pub mod deser;
+pub mod rootcache;
 
 pub struct Vfs {
     pub entries: Vec<VfsEntry>,
@@ -53,6 +54,28 @@ impl Vfs {
 
         id
     }
+    /// Like cid_and_create, but the parents aren't marked as dirs, e.g. to merge a cache
+    pub fn cid_or_insert(&mut self, path: &Path) -> VfsId {
+        is_absolute(path);
+        let mut id = VfsId::ROOT;
+
+        for c in path.components() {
+            id = self.child_or_insert(id, &c);
+        }
+
+        id
+    }
+    pub fn child_or_insert(&mut self, id: VfsId, c: &Component) -> VfsId {
+        match self.child_of(id,c) {
+            Some(i) => i,
+            None => {
+                let e = VfsEntry::new(self[id].path.join(c).into());
+                let new = self._insert_new_entry(e);
+                self[id].childs.push(new);
+                new
+            }
+        }
+    }
     pub fn resolve(&self, path: &Path) -> Option<&VfsEntry> {
         self.cid(path)
             .map(|id| &self[id] )
@@ -107,6 +130,7 @@ impl Vfs {
             path: static_empty_arc_path,
             plc: OsString::with_capacity(0),
             ctime: None,
+            dir_mtime: None,
             file_size: None,
             dir_size: None,
             file_hash: None,
@@ -126,6 +150,7 @@ impl Vfs {
             dedup_state: None,
             phys: Some(0),
             n_extends: None,
+            encoded_blocks: 0,
         });
         senf
     }
@@ -157,7 +182,7 @@ macro_rules! maybe {
     };
 }
 
-#[derive(serde_derive::Deserialize,serde_derive::Serialize,Copy,Clone,PartialEq,PartialOrd)]
+#[derive(serde_derive::Deserialize,serde_derive::Serialize,Copy,Clone,PartialEq,Eq,Hash,PartialOrd)]
 #[serde(transparent)]
 #[repr(transparent)]
 pub struct VfsId {
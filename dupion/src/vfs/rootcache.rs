@@ -0,0 +1,179 @@
+use super::*;
+use super::deser::{read_cache, write_cache, CacheContents};
+use opts::Opts;
+use state::State;
+use storage::Mounts;
+use util::xdg_dir;
+
+/// Name of the cache files at the scan roots
+pub const ROOT_CACHE_NAME: &str = ".dupion_cache";
+/// While write_cache writes it
+const ROOT_CACHE_TMP_NAME: &str = ".dupion_cache.tmp";
+
+/// Where the caches of the scan roots are kept, in addition to the --cache-path
+#[derive(Clone,Copy,PartialEq,Debug)]
+pub enum RootCacheMode {
+    Off,
+    /// In the root directory, so that e.g. a removable drive carries its own cache
+    Root,
+    /// In the cache directory, keyed by the UUID of the filesystem and relative to its mount point
+    Uuid,
+}
+
+/// A cache of the entries below `root`, with the paths relative to it
+#[derive(Clone,PartialEq,Debug)]
+pub struct RootCache {
+    pub root: PathBuf,
+    pub file: PathBuf,
+}
+
+/// $XDG_CACHE_HOME/dupion
+pub fn default_cache_dir() -> PathBuf {
+    xdg_dir("XDG_CACHE_HOME", ".cache").map_or_else(|| PathBuf::from("."), |d| d.join("dupion") )
+}
+
+/// $XDG_CACHE_HOME/dupion/cache
+pub fn default_cache_path() -> PathBuf {
+    default_cache_dir().join("cache")
+}
+
+/// The root caches of the scanned paths
+pub fn root_caches(opts: &Opts) -> Vec<RootCache> {
+    let mut caches = Vec::<RootCache>::new();
+
+    match opts.root_cache {
+        RootCacheMode::Off => {},
+        RootCacheMode::Root => {
+            for p in opts.paths.iter().filter(|p| p.is_dir() ) {
+                caches.push(RootCache{root: p.clone(), file: p.join(ROOT_CACHE_NAME)});
+            }
+        },
+        RootCacheMode::Uuid => {
+            let mounts = match Mounts::load() {
+                Ok(v) => v,
+                Err(e) => {
+                    dprintln!("\tError reading mounts, no root caches: {}",e);
+                    return caches;
+                },
+            };
+
+            for p in &opts.paths {
+                let Some(mount) = mounts.mount_of(p) else {continue};
+                let Some(uuid) = mount.uuid() else {
+                    dprintln!("\tNo filesystem UUID for {}, no root cache",p.display());
+                    continue;
+                };
+
+                // e.g. btrfs subvolumes of the same filesystem
+                let key = match mount.root.as_str() {
+                    "/" => uuid,
+                    root => format!("{}{}",uuid,root.replace('/',"_")),
+                };
+
+                let cache = RootCache{root: mount.mount_point.clone(), file: default_cache_dir().join(format!("fs-{}",key))};
+                if !caches.contains(&cache) {
+                    caches.push(cache);
+                }
+            }
+        },
+    }
+
+    caches
+}
+
+/// The root cache files aren't scanned, so that they don't change the hash of the root directory
+pub fn is_root_cache_file(path: &Path, opts: &Opts) -> bool {
+    opts.root_cache == RootCacheMode::Root
+        && path.file_name().is_some_and(|n| n == ROOT_CACHE_NAME || n == ROOT_CACHE_TMP_NAME )
+        && path.parent().is_some_and(|p| opts.paths.iter().any(|r| r == p ) )
+}
+
+impl State {
+    /// Merge the root caches into the loaded cache. Their entries replace the ones of the same path
+    pub fn load_root_caches(&mut self) {
+        for cache in self.root_caches.clone() {
+            if !cache.file.is_file() {continue;}
+
+            self.merge_root_cache(&cache).unwrap_or_else(|e| dprintln!("Error reading cache {}: {}",cache.file.display(),e) );
+        }
+    }
+
+    fn merge_root_cache(&mut self, cache: &RootCache) -> AnyhowResult<()> {
+        let CacheContents{entries,hash_algorithm,..} = read_cache(&cache.file)?;
+        ensure!(!entries.is_empty(), "Empty cache");
+
+        // the hashes of another algorithm are dropped, like in migrate_hash_algorithm
+        let keep_hashes = hash_algorithm == Some(self.hash_algorithm);
+
+        let root = self.tree.cid_or_insert(&cache.root);
+        let mut stack = vec![(0usize,root)];
+
+        while let Some((cached,id)) = stack.pop() {
+            let c = &entries[cached];
+            let e = &mut self.tree[id];
+
+            e.ctime = c.ctime;
+            e.dir_mtime = c.dir_mtime;
+            e.file_size = c.file_size;
+            e.file_hash = c.file_hash.clone().filter(|_| keep_hashes );
+            e.was_file = c.was_file;
+            e.was_dir = c.was_dir;
+            e.failure = c.failure;
+            e.dedup_state = c.dedup_state;
+            e.phys = c.phys;
+            e.n_extends = c.n_extends;
+            e.encoded_blocks = c.encoded_blocks;
+
+            for &child in &c.childs {
+                let name = &entries[child.evil_inner].plc;
+                let child_id = self.tree.child_or_insert(id, &Component::Normal(name));
+                stack.push((child.evil_inner,child_id));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn store_root_caches(&self) {
+        for cache in &self.root_caches {
+            let Some(root) = self.tree.cid(&cache.root) else {continue};
+
+            let entries = self.root_cache_entries(root);
+
+            write_cache(&cache.file,&entries,&[],self.hash_algorithm,None)
+                .unwrap_or_else(|e| dprintln!("Error writing cache {}: {}",cache.file.display(),e) );
+        }
+    }
+
+    /// The entries below `root`, which is the first, with the paths relative to it
+    fn root_cache_entries(&self, root: VfsId) -> Vec<VfsEntry> {
+        let root_path = self.tree[root].path.clone();
+
+        let relative = |e: &VfsEntry, path: &Path| {
+            let mut e = e.clone();
+            e.path = path.into();
+            e.childs = Vec::new();
+            e
+        };
+
+        let mut entries = vec![relative(&self.tree[root], Path::new(""))];
+        let mut stack = vec![(root,0usize)];
+
+        while let Some((id,index)) = stack.pop() {
+            for &child in &self.tree[id].childs {
+                let e = &self.tree[child];
+                // like the entries the cache file keeps
+                if !(e.is_file || e.is_dir || ((e.was_file || e.was_dir) && !e.valid)) {continue;}
+
+                let path = e.path.strip_prefix(&root_path).unwrap();
+
+                entries.push(relative(e, path));
+                let child_index = entries.len() - 1;
+                entries[index].childs.push(VfsId{evil_inner: child_index});
+                stack.push((child,child_index));
+            }
+        }
+
+        entries
+    }
+}
@@ -0,0 +1,381 @@
+use super::*;
+use std::{ffi::{CString, OsStr}, fs::read_dir, io::Write, os::unix::{ffi::OsStrExt, fs::MetadataExt}, path::{Path, PathBuf}, time::{Duration, Instant, SystemTime}};
+use btrfs::get_file_extent_map_for_path_noloop;
+use parking_lot::RwLock;
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde_derive::Serialize;
+use state::State;
+use opts::Opts;
+use vfs::{entry::VfsEntryType, deser::{encode_hash_base64, cache_tmp_path}, rootcache::is_root_cache_file};
+use driver::platterwalker::{size_file, hash_files, encoded_bytes};
+use util::Hash;
+
+const WATCH_MASK: u32 =
+    libc::IN_CLOSE_WRITE | libc::IN_CREATE | libc::IN_DELETE | libc::IN_MOVED_FROM | libc::IN_MOVED_TO |
+    libc::IN_ONLYDIR | libc::IN_DONT_FOLLOW | libc::IN_EXCL_UNLINK;
+
+/// wait for this long without events before processing the changes
+const SETTLE_TIME: Duration = Duration::from_secs(1);
+/// process the changes at least this often, even if the events don't settle
+const MAX_DELAY: Duration = Duration::from_secs(10);
+
+pub struct Inotify {
+    fd: i32,
+    /// watch descriptor -> watched dir
+    watches: FxHashMap<i32,PathBuf>,
+}
+
+impl Inotify {
+    pub fn new() -> std::io::Result<Self> {
+        let fd = unsafe{libc::inotify_init1(libc::IN_CLOEXEC)};
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Self {
+            fd,
+            watches: FxHashMap::default(),
+        })
+    }
+
+    pub fn add_watch(&mut self, dir: &Path) -> std::io::Result<()> {
+        let cpath = CString::new(dir.as_os_str().as_bytes())?;
+        let wd = unsafe{libc::inotify_add_watch(self.fd, cpath.as_ptr(), WATCH_MASK)};
+        if wd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        // the same dir (e.g. moved) gets the same wd
+        self.watches.insert(wd, dir.to_owned());
+        Ok(())
+    }
+
+    /// Watch `dir` and its subdirectories, the files inside are passed to `found`
+    pub fn add_watch_recursive(&mut self, dir: &Path, found: &mut impl FnMut(PathBuf), opts: &Opts) {
+        if let Err(e) = self.add_watch(dir) {
+            dprintln!("\tError watching dir: {} ({})",e,opts.path_disp(dir));
+            if e.raw_os_error() == Some(libc::ENOSPC) {
+                dprintln!("\tThe inotify watch limit is reached, see /proc/sys/fs/inotify/max_user_watches");
+            }
+            return;
+        }
+
+        let iter = try_return!(read_dir(dir),"\tError reading dir: {} ({})",opts.path_disp(dir));
+
+        for dent in iter {
+            let dent = try_continue!(dent,"\tError reading dir: {} ({})",opts.path_disp(dir));
+            let ft = try_continue!(dent.file_type(),"\tError reading dir: {} ({})",opts.path_disp(dir));
+            if ft.is_dir() {
+                self.add_watch_recursive(&dent.path(), found, opts);
+            } else if ft.is_file() {
+                found(dent.path());
+            }
+        }
+    }
+
+    /// Stop watching `dir` and its subdirectories
+    pub fn remove_watch_recursive(&mut self, dir: &Path) {
+        let fd = self.fd;
+        self.watches.retain(|&wd,p| {
+            if p.starts_with(dir) {
+                unsafe{libc::inotify_rm_watch(fd, wd)};
+                false
+            }else{
+                true
+            }
+        });
+    }
+
+    /// Wait up to `timeout` (forever if None) for events. Returns false on timeout
+    pub fn poll(&self, timeout: Option<Duration>) -> std::io::Result<bool> {
+        let mut pfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let deadline = timeout.map(|t| Instant::now() + t );
+        loop {
+            let timeout = deadline.map_or(-1, |d| d.saturating_duration_since(Instant::now()).as_millis().min(i32::MAX as u128) as i32 );
+            match unsafe{libc::poll(&mut pfd, 1, timeout)} {
+                n if n < 0 => {
+                    let e = std::io::Error::last_os_error();
+                    // a signal, wait for the rest of the timeout
+                    if e.kind() != std::io::ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                },
+                0 => return Ok(false),
+                _ => return Ok(true),
+            }
+        }
+    }
+
+    /// Read the pending events as (mask, path)
+    pub fn read_events(&mut self, buf: &mut [u8], dest: &mut Vec<(u32,PathBuf)>) -> std::io::Result<()> {
+        let n = loop {
+            let n = unsafe{libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len())};
+            if n >= 0 {break n;}
+            let e = std::io::Error::last_os_error();
+            if e.kind() != std::io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        };
+
+        let header_size = std::mem::size_of::<libc::inotify_event>();
+
+        let mut off = 0;
+
+        while off + header_size <= n as usize {
+            let event = unsafe{std::ptr::read_unaligned(buf.as_ptr().add(off) as *const libc::inotify_event)};
+            let name = &buf[off + header_size .. off + header_size + event.len as usize];
+            off += header_size + event.len as usize;
+
+            if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                dprintln!("\tinotify event queue overflowed, some changes were missed");
+                continue;
+            }
+            if event.mask & libc::IN_IGNORED != 0 {
+                self.watches.remove(&event.wd);
+                continue;
+            }
+
+            let dir = match self.watches.get(&event.wd) {
+                Some(v) => v,
+                None => continue,
+            };
+
+            let name = &name[..name.iter().position(|&b| b == 0 ).unwrap_or(name.len())];
+
+            dest.push((event.mask, dir.join(OsStr::from_bytes(name))));
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        unsafe{libc::close(self.fd)};
+    }
+}
+
+#[derive(Serialize)]
+struct DuplicateEvent<'a> {
+    event: &'static str,
+    /// unix time in seconds
+    time: u64,
+    size: u64,
+    /// base64 encoded
+    hash: String,
+    /// HashAlgorithm::id
+    algorithm: &'static str,
+    /// hex encoded, without padding
+    digest: String,
+    files: Vec<&'a str>,
+    /// the files in `files` which were added or changed
+    new: Vec<&'a str>,
+}
+
+/// Keep watching the scanned roots, hash new and changed files and print the duplicate groups they join as NDJSON to stdout
+pub fn watch(state: &RwLock<State>, opts: &Opts) -> AnyhowResult<()> {
+    let mut inotify = Inotify::new()?;
+
+    let mut changed = FxHashSet::<PathBuf>::default();
+    let mut removed = FxHashSet::<PathBuf>::default();
+
+    for root in &opts.paths {
+        if root.is_dir() {
+            // the files were just scanned
+            inotify.add_watch_recursive(root, &mut |_| {}, opts);
+        }
+    }
+
+    dprintln!("Watching {} directories",inotify.watches.len());
+
+    // storing the cache mustn't trigger the next batch
+    let cache_files = cache_files(state, opts);
+
+    let mut buf = vec![0u8;65536];
+    let mut events = Vec::new();
+    let mut batch_start = None;
+
+    loop {
+        let timeout = batch_start.map(|_| SETTLE_TIME );
+
+        if inotify.poll(timeout)? {
+            inotify.read_events(&mut buf, &mut events)?;
+
+            for (mask,path) in events.drain(..) {
+                if path.to_str().is_none() {continue;}
+                if cache_files.contains(&path) || is_root_cache_file(&path, opts) {continue;}
+
+                if mask & (libc::IN_DELETE | libc::IN_MOVED_FROM) != 0 {
+                    if mask & libc::IN_ISDIR != 0 {
+                        inotify.remove_watch_recursive(&path);
+                    }
+                    changed.retain(|p| !p.starts_with(&path) );
+                    removed.insert(path);
+                } else if mask & libc::IN_ISDIR != 0 {
+                    if mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                        removed.remove(&path);
+                        inotify.add_watch_recursive(&path, &mut |p| {changed.insert(p);}, opts);
+                    }
+                } else if mask & (libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO) != 0 {
+                    removed.remove(&path);
+                    changed.insert(path);
+                }
+
+                batch_start.get_or_insert_with(Instant::now);
+            }
+
+            if !batch_start.is_some_and(|t: Instant| t.elapsed() >= MAX_DELAY ) {
+                continue;
+            }
+        }
+
+        if batch_start.take().is_some() {
+            process_changes(&mut changed, &mut removed, state, opts)?;
+        }
+    }
+}
+
+/// The absolute paths of the cache files and their temporary files
+fn cache_files(state: &RwLock<State>, opts: &Opts) -> FxHashSet<PathBuf> {
+    let s = state.read();
+
+    let mut files = FxHashSet::default();
+
+    for path in std::iter::once(&opts.cache_path).chain(s.root_caches.iter().map(|c| &c.file )) {
+        let (Some(dir),Some(name)) = (path.parent(),path.file_name()) else {continue};
+        let dir = if dir.as_os_str().is_empty() {Path::new(".")} else {dir};
+        let Ok(dir) = dir.canonicalize() else {continue};
+
+        files.insert(dir.join(name));
+        files.insert(dir.join(cache_tmp_path(Path::new(name))));
+    }
+
+    files
+}
+
+fn process_changes(changed: &mut FxHashSet<PathBuf>, removed: &mut FxHashSet<PathBuf>, state: &RwLock<State>, opts: &Opts) -> AnyhowResult<()> {
+    let mut dest = Vec::with_capacity(changed.len());
+    let mut hash_now = Vec::new();
+    let mut to_hash = FxHashSet::default();
+    let mut any_removed = false;
+
+    {
+        let mut s = state.write();
+
+        for path in removed.drain() {
+            if let Some(id) = s.tree.cid(&path) {
+                opts.log_verbosed("REMOVED", &path);
+                s.invalidate(id);
+                any_removed = true;
+            }
+        }
+
+        for path in changed.drain() {
+            let meta = try_continue!(path.symlink_metadata(),"\tError: {} ({})",opts.path_disp(&path));
+            if !meta.is_file() {continue;}
+
+            if let Some(id) = s.tree.cid(&path) {
+                let e = &s.tree[id];
+                if e.valid && e.is_file && e.file_size == Some(meta.len()) && e.ctime == Some(meta.ctime()) {
+                    continue;
+                }
+                s.remove_from_groups(id);
+            }
+
+            let extents = get_file_extent_map_for_path_noloop(&path).unwrap_or_default();
+
+            size_file(
+                &path,
+                &meta,
+                extents.first().map_or(0, |e| e.physical ),
+                extents.len(),
+                encoded_bytes(&extents),
+                &mut dest,
+                &mut hash_now,
+                &mut s,
+                opts,
+            )?;
+        }
+
+        // files of the same size which were unique before have to be hashed too
+        for &(_,id) in &dest {
+            if let Some(g) = s.tree[id].file_size.and_then(|size| s.sizes.get(&size) ) {
+                for &(typ,id) in &g.entries {
+                    if typ == VfsEntryType::File {
+                        to_hash.insert(id);
+                    }
+                }
+            }
+        }
+
+        // a new file may collide with a formerly unique partial hash
+        for &id in &to_hash {
+            s.forget_partial_hash(id);
+        }
+    }
+
+    if dest.is_empty() {
+        if any_removed {
+            state.read().eventually_store_vfs(&opts.cache_path, true);
+        }
+        return Ok(());
+    }
+
+    hash_files(to_hash.into_iter(), state, opts, true)?;
+
+    let s = state.read();
+
+    let new_files = dest.iter().map(|&(_,id)| id ).collect::<FxHashSet<_>>();
+    let mut reported = FxHashSet::<Hash>::default();
+
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+
+    for &(_,id) in &dest {
+        let hash = match &s.tree[id].file_hash {
+            Some(v) => v,
+            None => continue,
+        };
+        if reported.contains(hash) {continue;}
+
+        let group = match s.hashes.get(hash) {
+            Some(v) => v,
+            None => continue,
+        };
+
+        let files = group.entries.iter()
+            .filter(|&&(typ,id)| typ == VfsEntryType::File && s.tree[id].valid && s.tree[id].is_file )
+            .map(|&(_,id)| id )
+            .collect::<Vec<_>>();
+
+        if files.len() < 2 {continue;}
+
+        let event = DuplicateEvent {
+            event: "duplicates",
+            time: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs() ),
+            size: group.size,
+            hash: encode_hash_base64(hash),
+            algorithm: opts.hash_algorithm.id(),
+            digest: opts.hash_algorithm.digest_hex(hash),
+            files: files.iter()
+                .map(|&id| s.tree[id].path.to_str().unwrap() )
+                .collect(),
+            new: files.iter()
+                .filter(|id| new_files.contains(id) )
+                .map(|&id| s.tree[id].path.to_str().unwrap() )
+                .collect(),
+        };
+
+        serde_json::to_writer(&mut stdout, &event)?;
+        stdout.write_all(b"\n")?;
+        stdout.flush()?;
+
+        reported.insert(hash.clone());
+    }
+
+    s.eventually_store_vfs(&opts.cache_path, true);
+
+    Ok(())
+}
@@ -1,17 +1,21 @@
 use super::*;
 use opts::Opts;
-use std::{ffi::CString, sync::Arc, path::Path, io::{Read, Write, Seek}};
+use std::{ffi::CString, path::Path, io::{Read, Write, Seek}};
 use parking_lot::RwLock;
 use state::State;
+use errors::ScanErrorKind;
 use libarchive::{entry::OwnedEntry, reader::{StreamReader, Reader as AReader, Builder}, archive::{FileType, Entry, ReadFormat, ReadFilter, ReadCompression}};
 
 pub fn decode_zip<'r,R>(mut ar: R, zip_path: &Path, state: &RwLock<State>, opts: &Opts) -> AnyhowResult<()> where R: AReader<'r> {
+    let progress = state.read().progress.clone();
     let result = (||{
         let mut buf = [0u8;8192];
 
         let mut error_counter = 0usize;
 
         'z: loop {
+            progress.check_interrupted()?;
+
             let mut e = OwnedEntry::new().unwrap();
 
             if !try_counted!(ar.next_header2(&mut e),error_counter,'z,"\tError reading ZIP header: {} ({})",opts.path_disp(zip_path)) {
@@ -27,11 +31,12 @@ pub fn decode_zip<'r,R>(mut ar: R, zip_path: &Path, state: &RwLock<State>, opts:
 
                     opts.log_verbosed("HASH", &zip_path.join(name));
                     
-                    let mut hasher = blake3::Hasher::new();
+                    let mut hasher = opts.hash_algorithm.hasher();
 
                     let mut r2 = 0;
 
                     loop{
+                        progress.check_interrupted()?;
                         let r = try_counted!(ar.read(&mut buf),error_counter,'z,"\tError reading zipped data: {} ({})",opts.path_disp(&zip_path.join(name)));
                         if r == 0 {
                             break;
@@ -45,7 +50,7 @@ pub fn decode_zip<'r,R>(mut ar: R, zip_path: &Path, state: &RwLock<State>, opts:
                         //continue;
                     }
 
-                    let hash = Arc::new(hasher.finalize().into());
+                    let hash = hasher.finalize();
 
                     let path = zip_path.join(name);
 
@@ -80,9 +85,16 @@ pub fn decode_zip<'r,R>(mut ar: R, zip_path: &Path, state: &RwLock<State>, opts:
 
         Ok(())
     })();
-    if result.is_err() {
-        dprintln!("\tUpgrade(1) {}",opts.path_disp(zip_path));
-        if let Some(e) = state.write().tree.resolve_mut(zip_path) {
+    if result.is_err() && progress.is_interrupted() {
+        // the contents are incomplete, the archive is read again by the next scan
+        let mut s = state.write();
+        if let Some(id) = s.tree.cid(zip_path) {
+            s.drop_file_hash(id);
+        }
+    }else if let Err(err) = &result {
+        let mut s = state.write();
+        s.errors.push(opts,ScanErrorKind::Archive,zip_path,err);
+        if let Some(e) = s.tree.resolve_mut(zip_path) {
             e.failure = Some(1);
         }
     }
@@ -99,9 +111,10 @@ pub fn open_zip<'r,R>(r: R,path: &Path,state: &RwLock<State>, opts: &Opts) -> An
 
         Ok(b.open_seekable_stream(r)?)
     })();
-    if result.is_err() {
-        dprintln!("\tUpgrade(1) {}",opts.path_disp(path));
-        if let Some(e) = state.write().tree.resolve_mut(path) {
+    if let Err(err) = &result {
+        let mut s = state.write();
+        s.errors.push(opts,ScanErrorKind::Archive,path,err);
+        if let Some(e) = s.tree.resolve_mut(path) {
             e.failure = Some(1);
         }
     }
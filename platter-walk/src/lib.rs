@@ -13,9 +13,12 @@ use std::path::PathBuf;
 use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::path::Path;
 use std::os::unix::fs::MetadataExt;
-use std::os::unix::io::AsRawFd;
 use std::os::unix::io::FromRawFd;
-use std::cmp::Reverse;
+
+mod prefetch;
+
+use prefetch::Prefetcher;
+pub use prefetch::{PrefetchStrategy, PrefetchObserver};
 
 pub struct Entry<D> where D: Default {
     path: PathBuf,
@@ -73,27 +76,67 @@ impl<D> PartialEq<Path> for Entry<D> where D: Default {
     }
 }
 
-pub struct ToScan<D> where D: Default {
+pub struct ToScan<'a, D> where D: Default {
     phy_sorted : BTreeMap<u64, Entry<D>>,
     phy_sorted_leaves: Vec<(u64, Entry<D>)>,
     unordered : VecDeque<Entry<D>>,
     cursor: u64,
-    current_dir: Option<ReadDir>,
+    current_dir: Option<DirIter>,
     inode_ordered: Vec<Entry<D>>,
-    prefilter: Option<Box<dyn Fn(&Path, &FileType,&mut D) -> bool>>,
+    prefilter: Option<Box<dyn Fn(&Path, &FileType,&mut D) -> bool + 'a>>,
+    dir_filter: Option<Box<dyn FnMut(&Path) -> DirAction + 'a>>,
     phase: Phase,
     order: Order,
     batch_size: usize,
     prefetched: FxHashMap<PathBuf, u64>,
-    mountpoints: Vec<mnt::MountEntry>,
+    prefetcher: Option<Prefetcher>,
+    prefetch_observer: Option<PrefetchObserver<'a>>,
     prefetch_cap: usize
 }
 
-#[derive(PartialEq, Copy, Clone)]
+#[derive(PartialEq, Copy, Clone, Debug)]
 pub enum Order {
     /// Return directory entries sorted by physical offset of the file contents
     /// Can be used to get sequential reads over multiple files
-    Content
+    Content,
+    /// Visit directories and return directory entries in inode order, without looking at the physical offsets.
+    /// For filesystems where inode order roughly follows the disk layout, or FIEMAP isn't available
+    Inode,
+    /// Visit directories depth-first and return their entries sorted by name
+    DepthFirst,
+    /// Visit directories breadth-first and return their entries sorted by name
+    BreadthFirst,
+}
+
+impl Order {
+    /// Whether the directories are read completely and sorted by name
+    fn sorts_by_name(self) -> bool {
+        matches!(self, Order::DepthFirst | Order::BreadthFirst)
+    }
+}
+
+enum DirIter {
+    Unsorted(ReadDir),
+    Sorted(std::vec::IntoIter<DirEntry>),
+}
+
+impl Iterator for DirIter {
+    type Item = std::io::Result<DirEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            DirIter::Unsorted(iter) => iter.next(),
+            DirIter::Sorted(iter) => iter.next().map(Ok),
+        }
+    }
+}
+
+/// Decides what happens with a directory before it is read
+pub enum DirAction {
+    /// Read the directory entries
+    Read,
+    /// Don't read the directory, only descend into these subdirectories (e.g. the directory is unchanged since the last scan)
+    Descend(Vec<PathBuf>),
 }
 
 #[derive(PartialEq)]
@@ -106,7 +149,7 @@ enum Phase {
 
 use Order::*;
 
-impl<D> ToScan<D> where D: Default {
+impl<'a, D> ToScan<'a, D> where D: Default {
 
     pub fn new() -> Self {
         ToScan {
@@ -120,8 +163,10 @@ impl<D> ToScan<D> where D: Default {
             phase: Phase::DirWalk,
             batch_size: 1024,
             prefilter: None,
+            dir_filter: None,
             prefetched: FxHashMap::default(),
-            mountpoints: vec![],
+            prefetcher: None,
+            prefetch_observer: None,
             prefetch_cap: 0
         }
     }
@@ -131,25 +176,25 @@ impl<D> ToScan<D> where D: Default {
         self
     }
 
+    /// Prefetch the metadata of the directories ahead of the walk, with a strategy per mount.
+    /// Privileged strategies fall back to readahead on the directories when they fail
     pub fn prefetch_dirs(&mut self, val: bool) {
-        if !val {
-            self.mountpoints = vec![];
-            return;
-        }
+        self.prefetcher = if val {Prefetcher::load()} else {None};
+    }
 
-        self.mountpoints = match mnt::MountIter::new_from_proc() {
-            Ok(m) => m,
-            Err(_) => {
-                self.mountpoints = vec![];
-                return
-            }
-        }.filter_map(|e| e.ok()).collect();
+    /// Called with the mount point whenever the prefetch strategy of a mount is first used or changes
+    pub fn set_prefetch_observer(&mut self, observer: PrefetchObserver<'a>) {
+        self.prefetch_observer = Some(observer)
     }
 
-    pub fn set_prefilter(&mut self, filter: Box<dyn Fn(&Path, &FileType, &mut D) -> bool>) {
+    pub fn set_prefilter(&mut self, filter: Box<dyn Fn(&Path, &FileType, &mut D) -> bool + 'a>) {
         self.prefilter = Some(filter)
     }
 
+    pub fn set_dir_filter(&mut self, filter: Box<dyn FnMut(&Path) -> DirAction + 'a>) {
+        self.dir_filter = Some(filter)
+    }
+
     pub fn set_batchsize(&mut self, batch: usize) {
         self.batch_size = batch;
     }
@@ -164,11 +209,44 @@ impl<D> ToScan<D> where D: Default {
         Ok(())
     }
 
+    fn add_dir(&mut self, path: PathBuf, ft: FileType, ino: u64) {
+        match self.order {
+            Order::Content => {},
+            Order::Inode => {
+                self.add(Entry::new(path, ft, ino, vec![], D::default()), Some(ino));
+                return;
+            },
+            Order::DepthFirst | Order::BreadthFirst => {
+                self.add(Entry::new(path, ft, ino, vec![], D::default()), None);
+                return;
+            },
+        }
+
+        let extents = get_file_extent_map_for_path_noloop(&path)
+            .unwrap_or_else(|_| Vec::new() );
+
+        let to_add = Entry::new(path, ft, ino, extents, D::default());
+
+        if !to_add.extents.is_empty() {
+            let offset = to_add.extents[0].physical;
+            self.add(to_add, Some(offset));
+        } else {
+            // TODO: fall back to inode-order? depth-first?
+            // skip adding non-directories in content order?
+            self.add(to_add, None);
+        }
+    }
+
     fn get_next(&mut self) -> Option<Entry<D>> {
         self.prefetch();
 
         if !self.unordered.is_empty() {
-            let res = self.unordered.pop_front();
+            // the subdirectories of depth-first are added in reverse
+            let res = if self.order == Order::DepthFirst {
+                self.unordered.pop_back()
+            } else {
+                self.unordered.pop_front()
+            };
             self.remove_prefetch(&res);
             return res;
         }
@@ -197,15 +275,15 @@ impl<D> ToScan<D> where D: Default {
     }
 
     fn prefetch(&mut self) {
-        if self.mountpoints.is_empty() {
-            return;
-        }
+        let prefetcher = match self.prefetcher {
+            Some(ref mut v) => v,
+            None => return,
+        };
 
         const LIMIT : u64 = 8*1024*1024;
 
         let consumed = self.prefetched.iter().map(|ref tuple| tuple.1).sum::<u64>();
         let mut remaining = LIMIT.saturating_sub(consumed);
-        let prev_fetched = self.prefetched.len();
 
         // hysteresis
         if remaining < LIMIT/2 {
@@ -216,86 +294,30 @@ impl<D> ToScan<D> where D: Default {
         let ordered_iter_front = self.phy_sorted.range(self.cursor..).map(|(_,v)| v);
         let ordered_iter_tail = self.phy_sorted.range(..self.cursor).map(|(_,v)| v);
 
-        let mut prune = vec![];
+        let mut batch = vec![];
 
-        {
-            let mut device_groups = FxHashMap::default();
-
-            for e in unordered_iter.chain(ordered_iter_front).chain(ordered_iter_tail) {
-                if remaining == 0 {
-                    break;
-                }
-
-                if self.prefetched.len() > self.prefetch_cap + 1 {
-                    break;
-                }
-
-                if self.prefetched.contains_key(e.path()) {
-                    continue;
-                }
-
-                let size = e.extent_sum();
-                remaining = remaining.saturating_sub(size);
-                self.prefetched.insert(e.path().to_owned(), size);
-
-                let mount = self.mountpoints.iter().rev().find(|mnt| e.path().starts_with(&mnt.file));
-
-                // TODO: only try to open devices once
-                match mount {
-                    Some(&mnt::MountEntry {ref spec, ref vfstype, ..})
-                    if vfstype == "ext4" || vfstype == "ext3"// || vfstype == "btrfs"
-                    => {
-                        let mount_slot = device_groups.entry(spec).or_insert(vec![]);
-                        mount_slot.extend(&e.extents);
-                    }
-                    _ => {}
-                }
+        for e in unordered_iter.chain(ordered_iter_front).chain(ordered_iter_tail) {
+            if remaining == 0 {
+                break;
             }
 
-            for (p, extents) in device_groups {
-                let mut ordered_extents = extents.to_vec();
-                ordered_extents.sort_by_key(|e| e.physical);
-
-                if let Ok(f) = File::open(p) {
-
-                    let mut i = 0;
-
-                    while i < ordered_extents.len() {
-                        let ext1 = ordered_extents[i];
-                        let offset = ext1.physical;
-                        let mut end = offset + ext1.length;
-
-                        for j in i+1..ordered_extents.len() {
-                            let ref ext2 = ordered_extents[j];
-                            if ext2.physical > end {
-                                break;
-                            }
-
-                            i = j;
-
-                            end = ext2.physical+ext2.length;
-                        }
-
-                        i+=1;
-
-                        unsafe {
-                            libc::posix_fadvise(f.as_raw_fd(), offset as i64, (end - offset) as i64, libc::POSIX_FADV_WILLNEED);
-                        }
-                    }
-                } else {
-                    prune.push(p.to_owned());
-                }
+            if self.prefetched.len() > self.prefetch_cap + 1 {
+                break;
             }
 
-        }
+            if self.prefetched.contains_key(e.path()) {
+                continue;
+            }
 
-        //println!("bytes: {} -> {}, f: {}->{}, sc: {}", LIMIT-consumed, remaining, prev_fetched ,self.prefetched.len(), self.prefetch_cap);
+            // btrfs directories have no extents, count them as one block
+            let size = e.extent_sum().max(4096);
+            remaining = remaining.saturating_sub(size);
+            self.prefetched.insert(e.path().to_owned(), size);
 
-        if prune.len() > 0 {
-            self.mountpoints.retain(|e| prune.contains(&e.spec));
+            batch.push(e);
         }
 
-
+        prefetcher.prefetch(batch.iter().map(|e| (e.path(), e.ino, &e.extents[..]) ), &mut self.prefetch_observer);
     }
 
     pub fn add(&mut self, to_add: Entry<D>, pos: Option<u64>) {
@@ -314,7 +336,7 @@ impl<D> ToScan<D> where D: Default {
 
 }
 
-impl<D> Iterator for ToScan<D> where D: Default {
+impl<'a, D> Iterator for ToScan<'a, D> where D: Default {
     type Item = std::io::Result<Vec<(u64,Entry<D>)>>;
 
     //platter-wank is a hack for specialized manual content-sorting
@@ -330,9 +352,55 @@ impl<D> Iterator for ToScan<D> where D: Default {
                     }
                 };
 
+                if let Some(ref mut filter) = self.dir_filter {
+                    if let DirAction::Descend(mut subdirs) = filter(nxt.path()) {
+                        if self.order.sorts_by_name() {
+                            subdirs.sort();
+                        }
+                        if self.order == Order::DepthFirst {
+                            subdirs.reverse();
+                        }
+                        let mut err = None;
+                        for path in subdirs {
+                            match symlink_metadata(&path) {
+                                Ok(meta) if meta.is_dir() => self.add_dir(path, meta.file_type(), meta.ino()),
+                                Ok(_) => {},
+                                Err(e) => err = Some(e),
+                            }
+                        }
+                        if let Some(e) = err {
+                            return Some(Err(e));
+                        }
+                        continue;
+                    }
+                }
+
                 match read_dir(nxt.path()) {
+                    Ok(dir_iter) if self.order.sorts_by_name() => {
+                        let mut dents = match dir_iter.collect::<std::io::Result<Vec<_>>>() {
+                            Ok(v) => v,
+                            Err(e) => return Some(Err(e)),
+                        };
+                        dents.sort_by_key(|d| d.file_name() );
+
+                        // add the subdirectories up front, so that they are visited in name order
+                        let subdirs = dents.iter()
+                            .filter_map(|d| d.file_type().ok().filter(|ft| ft.is_dir() ).map(|ft| (d.path(),ft,d.ino()) ) )
+                            .collect::<Vec<_>>();
+                        if self.order == Order::DepthFirst {
+                            for (path,ft,ino) in subdirs.into_iter().rev() {
+                                self.add_dir(path, ft, ino);
+                            }
+                        } else {
+                            for (path,ft,ino) in subdirs {
+                                self.add_dir(path, ft, ino);
+                            }
+                        }
+
+                        self.current_dir = Some(DirIter::Sorted(dents.into_iter()));
+                    },
                     Ok(dir_iter) => {
-                        self.current_dir = Some(dir_iter);
+                        self.current_dir = Some(DirIter::Unsorted(dir_iter));
                     },
                     Err(open_err) => return Some(Err(open_err))
                 }
@@ -358,21 +426,8 @@ impl<D> Iterator for ToScan<D> where D: Default {
 
                     // TODO: Better phase-switching?
                     // move to inode pass? won't start the next dir before this one is done anyway
-                    if meta.is_dir() {
-
-                        let extents = get_file_extent_map_for_path_noloop(dent.path())
-                            .unwrap_or_else(|_| Vec::new() );
-
-                        let to_add = Entry::new(dent.path(), meta, dent.ino(), extents, D::default());
-
-                        if !to_add.extents.is_empty() {
-                            let offset = to_add.extents[0].physical;
-                            self.add(to_add, Some(offset));
-                        } else {
-                            // TODO: fall back to inode-order? depth-first?
-                            // skip adding non-directories in content order?
-                            self.add(to_add, None);
-                        }
+                    if meta.is_dir() && !self.order.sorts_by_name() {
+                        self.add_dir(dent.path(), meta, dent.ino());
                     }
 
                     let mut userdata = D::default();
@@ -383,18 +438,12 @@ impl<D> Iterator for ToScan<D> where D: Default {
                         }
                     }
 
-                    match self.order {
-                        Order::Content => {
-                            self.inode_ordered.push(Entry::new(dent.path(), meta, dent.ino(), vec![], userdata));
-                        }
-                    }
+                    self.inode_ordered.push(Entry::new(dent.path(), meta, dent.ino(), vec![], userdata));
                 }
             }
 
             if self.inode_ordered.len() >= self.batch_size {
                 self.phase = Phase::InodePass;
-                // reverse sort so we can pop
-                self.inode_ordered.sort_by_key(|dent| Reverse(dent.ino()));
             }
         }
 
@@ -402,34 +451,39 @@ impl<D> Iterator for ToScan<D> where D: Default {
         if self.phase == Phase::InodePass || (self.is_empty() && self.inode_ordered.len() > 0)  {
             assert!(self.inode_ordered.len() > 0);
 
-            match self.order {
-                Order::Content => {
-                    for mut e in self.inode_ordered.drain(..).rev() {
-                        let (meta,extents) = file_meta_and_extents(e.path());
-                        let offset = match extents {
-                            Ok(ref extents) if !extents.is_empty() => extents[0].physical,
-                            _ => 0
-                        };
-                        //The metadata should now be cached by the OS, so file size read shouldn't be slow
-                        if e.ftype.is_file() {
-                            if let Ok(meta) = meta {
-                                e.metadata = Some(meta);
-                            }
-                            /*if let Ok(canon) = std::fs::canonicalize(e.path()) {
-                                assert_eq!(canon,e.path());
-                                e.canon_path = Some(canon);
-                            }*/
-                            assert!(e.path().is_absolute());
-                            e.canon_path = Some(e.path().to_owned());
-                        }
-                        self.phy_sorted_leaves.push((offset, e));
+            // the name sorted orders keep the order of the walk
+            if !self.order.sorts_by_name() {
+                self.inode_ordered.sort_by_key(|dent| dent.ino());
+            }
+
+            for mut e in self.inode_ordered.drain(..) {
+                let (meta,extents) = file_meta_and_extents(e.path());
+                let offset = match extents {
+                    Ok(ref extents) if !extents.is_empty() => extents[0].physical,
+                    _ => 0
+                };
+                if let Ok(extents) = extents {
+                    e.extents = extents;
+                }
+                //The metadata should now be cached by the OS, so file size read shouldn't be slow
+                if e.ftype.is_file() {
+                    if let Ok(meta) = meta {
+                        e.metadata = Some(meta);
                     }
-                    self.phy_sorted_leaves.sort_by_key(|pair| pair.0);
-                    self.phase = Phase::ContentPass;
-                    assert!(self.phy_sorted_leaves.len() > 0);
-                },
-                _ => panic!("illegal state")
+                    /*if let Ok(canon) = std::fs::canonicalize(e.path()) {
+                        assert_eq!(canon,e.path());
+                        e.canon_path = Some(canon);
+                    }*/
+                    assert!(e.path().is_absolute());
+                    e.canon_path = Some(e.path().to_owned());
+                }
+                self.phy_sorted_leaves.push((offset, e));
             }
+            if self.order == Order::Content {
+                self.phy_sorted_leaves.sort_by_key(|pair| pair.0);
+            }
+            self.phase = Phase::ContentPass;
+            assert!(self.phy_sorted_leaves.len() > 0);
 
         }
 
@@ -470,3 +524,131 @@ pub fn file_meta_and_extents(path: impl AsRef<Path>) -> (Result<Metadata,String>
     
     (meta,extents)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A directory tree in the temp dir, removed on drop
+    struct TestTree(PathBuf);
+
+    impl TestTree {
+        /// a/x, a/c/y, b/z and f
+        fn new() -> Self {
+            static COUNTER: AtomicUsize = AtomicUsize::new(0);
+            let root = std::env::temp_dir().join(format!("platter-walk-test-{}-{}", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed)));
+            for dir in ["b", "a/c"] {
+                create_dir_all(root.join(dir)).unwrap();
+            }
+            for file in ["a/x", "a/c/y", "b/z", "f"] {
+                write(root.join(file), file).unwrap();
+            }
+            TestTree(root.canonicalize().unwrap())
+        }
+
+        fn rel(&self, path: &Path) -> String {
+            path.strip_prefix(&self.0).unwrap().to_str().unwrap().to_owned()
+        }
+
+        fn ino(&self, rel: &str) -> u64 {
+            symlink_metadata(self.0.join(rel)).unwrap().ino()
+        }
+    }
+
+    impl Drop for TestTree {
+        fn drop(&mut self) {
+            let _ = remove_dir_all(&self.0);
+        }
+    }
+
+    /// The directories in the order they are read, and the returned entries, relative to the root ("" is the root)
+    fn walk(tree: &TestTree, order: Order, mut dir_filter: impl FnMut(&str) -> DirAction) -> (Vec<String>,Vec<String>) {
+        let visited = RefCell::new(Vec::new());
+
+        let entries = {
+            let mut scan = ToScan::<()>::new();
+            scan.set_order(order);
+            scan.set_dir_filter(Box::new(|path| {
+                let rel = tree.rel(path);
+                let action = dir_filter(&rel);
+                visited.borrow_mut().push(rel);
+                action
+            }));
+            scan.add_root(tree.0.clone()).unwrap();
+
+            scan.flat_map(|batch| batch.unwrap() )
+                .map(|(_,e)| tree.rel(e.path()) )
+                .collect::<Vec<_>>()
+        };
+
+        (visited.into_inner(),entries)
+    }
+
+    #[test]
+    fn depth_first() {
+        let tree = TestTree::new();
+        let (visited,entries) = walk(&tree, DepthFirst, |_| DirAction::Read );
+
+        assert_eq!(visited, ["", "a", "a/c", "b"]);
+        assert_eq!(entries, ["a", "b", "f", "a/c", "a/x", "a/c/y", "b/z"]);
+    }
+
+    #[test]
+    fn breadth_first() {
+        let tree = TestTree::new();
+        let (visited,entries) = walk(&tree, BreadthFirst, |_| DirAction::Read );
+
+        assert_eq!(visited, ["", "a", "b", "a/c"]);
+        assert_eq!(entries, ["a", "b", "f", "a/c", "a/x", "b/z", "a/c/y"]);
+    }
+
+    #[test]
+    fn inode() {
+        let tree = TestTree::new();
+        let (visited,entries) = walk(&tree, Inode, |_| DirAction::Read );
+
+        // the known directories are read in ascending inode order from the last one, wrapping around at the end
+        let subdirs = |rel: &str| match rel {
+            "" => vec!["a", "b"],
+            "a" => vec!["a/c"],
+            _ => vec![],
+        };
+        let mut expected = vec![""];
+        let mut pending = subdirs("");
+        let mut cursor = 0;
+        while !pending.is_empty() {
+            let next = pending.iter().copied()
+                .filter(|d| tree.ino(d) >= cursor )
+                .min_by_key(|d| tree.ino(d) )
+                .or_else(|| pending.iter().copied().min_by_key(|d| tree.ino(d) ) )
+                .unwrap();
+            pending.retain(|d| *d != next );
+            pending.extend(subdirs(next));
+            cursor = tree.ino(next);
+            expected.push(next);
+        }
+        assert_eq!(visited, expected);
+
+        // one batch, sorted by inode
+        let mut expected = vec!["a", "b", "f", "a/c", "a/x", "a/c/y", "b/z"];
+        expected.sort_by_key(|e| tree.ino(e) );
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn dir_filter_skips_and_descends() {
+        let tree = TestTree::new();
+        let root = tree.0.clone();
+        let (visited,entries) = walk(&tree, DepthFirst, |rel| match rel {
+            // a/x is skipped, a/c is read
+            "a" => DirAction::Descend(vec![root.join("a/c")]),
+            "b" => DirAction::Descend(vec![]),
+            _ => DirAction::Read,
+        });
+
+        assert_eq!(visited, ["", "a", "a/c", "b"]);
+        assert_eq!(entries, ["a", "b", "f", "a/c/y"]);
+    }
+}
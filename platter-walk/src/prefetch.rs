@@ -0,0 +1,236 @@
+//   platter-walk
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use btrfs::{linux::{prefetch_dir_items, FileExtent}, FileDescriptor};
+use rustc_hash::FxHashMap;
+use std::fs::{File, read_dir};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+/// How the directory metadata of a mount is prefetched
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum PrefetchStrategy {
+    /// `POSIX_FADV_WILLNEED` on the block device for the extents of the directories, for ext2/3/4 and XFS.
+    /// Needs read access to the device
+    DeviceExtents,
+    /// Search the directory items with the btrfs tree search ioctl in a helper thread, which reads their metadata blocks.
+    /// Needs CAP_SYS_ADMIN
+    BtrfsTreeSearch,
+    /// `readahead` on the directory fds in a helper thread, or reading the entries where the kernel refuses readahead on directories.
+    /// Needs no privileges
+    DirReadahead,
+}
+
+impl PrefetchStrategy {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::DeviceExtents => "device-extents",
+            Self::BtrfsTreeSearch => "btrfs-tree-search",
+            Self::DirReadahead => "dir-readahead",
+        }
+    }
+
+    fn for_fs(vfstype: &str) -> Self {
+        match vfstype {
+            "ext2" | "ext3" | "ext4" | "xfs" => Self::DeviceExtents,
+            "btrfs" => Self::BtrfsTreeSearch,
+            _ => Self::DirReadahead,
+        }
+    }
+}
+
+pub type PrefetchObserver<'a> = Box<dyn FnMut(&Path, PrefetchStrategy) + 'a>;
+
+struct PrefetchMount {
+    entry: mnt::MountEntry,
+    strategy: PrefetchStrategy,
+    /// the strategy was passed to the observer
+    reported: bool,
+    /// opened once for DeviceExtents
+    device: Option<File>,
+}
+
+impl PrefetchMount {
+    fn report(&mut self, observer: &mut Option<PrefetchObserver<'_>>) {
+        if !self.reported {
+            self.reported = true;
+            if let Some(o) = observer {
+                o(&self.entry.file, self.strategy);
+            }
+        }
+    }
+
+    /// Switch to the unprivileged strategy after the current one failed
+    fn fall_back(&mut self, observer: &mut Option<PrefetchObserver<'_>>) {
+        if self.strategy != PrefetchStrategy::DirReadahead {
+            self.strategy = PrefetchStrategy::DirReadahead;
+            self.device = None;
+            self.reported = false;
+            self.report(observer);
+        }
+    }
+}
+
+enum Job {
+    TreeSearch{mount: usize, path: PathBuf, ino: u64},
+    Readahead{path: PathBuf, size: u64},
+}
+
+/// Runs the prefetches which block until the data is read
+struct Worker {
+    jobs: mpsc::Sender<Job>,
+    /// mounts where the tree search failed
+    failed: mpsc::Receiver<usize>,
+}
+
+impl Worker {
+    fn spawn() -> Option<Self> {
+        let (jobs, job_rx) = mpsc::channel();
+        let (failed_tx, failed) = mpsc::channel();
+
+        std::thread::Builder::new().name("platter-walk prefetch".to_owned()).spawn(move || {
+            // ends when the walk is dropped
+            for job in job_rx {
+                match job {
+                    Job::TreeSearch{mount, path, ino} => {
+                        // e.g. removed in between, doesn't say anything about the mount
+                        let fd = match FileDescriptor::open(&path, libc::O_RDONLY | libc::O_DIRECTORY) {
+                            Ok(v) => v,
+                            Err(_) => continue,
+                        };
+                        if prefetch_dir_items(fd.get_value(), ino).is_err() && failed_tx.send(mount).is_err() {
+                            break;
+                        }
+                    },
+                    Job::Readahead{path, size} => {
+                        let dir = match File::open(&path) {
+                            Ok(v) => v,
+                            Err(_) => continue,
+                        };
+                        let ret = unsafe { libc::readahead(dir.as_raw_fd(), 0, size as usize) };
+                        if ret != 0 {
+                            // newer kernels only allow it on regular files, reading the entries caches them too
+                            if let Ok(entries) = read_dir(&path) {
+                                entries.for_each(drop);
+                            }
+                        }
+                    },
+                }
+            }
+        }).ok()?;
+
+        Some(Worker{jobs, failed})
+    }
+}
+
+pub(crate) struct Prefetcher {
+    mounts: Vec<PrefetchMount>,
+    worker: Option<Worker>,
+}
+
+impl Prefetcher {
+    pub fn load() -> Option<Self> {
+        let mounts = mnt::MountIter::new_from_proc().ok()?
+            .filter_map(|e| e.ok())
+            .map(|entry| PrefetchMount {
+                strategy: PrefetchStrategy::for_fs(&entry.vfstype),
+                entry,
+                reported: false,
+                device: None,
+            })
+            .collect();
+
+        Some(Prefetcher{mounts, worker: None})
+    }
+
+    fn mount_of(&self, path: &Path) -> Option<usize> {
+        self.mounts.iter().rposition(|m| path.starts_with(&m.entry.file))
+    }
+
+    fn queue(&mut self, job: Job) {
+        if self.worker.is_none() {
+            self.worker = Worker::spawn();
+        }
+        if let Some(w) = &self.worker {
+            let _ = w.jobs.send(job);
+        }
+    }
+
+    /// Issue the prefetch of the directories given as (path, inode, extents) with the strategy of their mount
+    pub fn prefetch<'a>(&mut self, dirs: impl Iterator<Item=(&'a Path, u64, &'a [FileExtent])>, observer: &mut Option<PrefetchObserver<'_>>) {
+        if let Some(w) = &self.worker {
+            let failed = w.failed.try_iter().collect::<Vec<_>>();
+            for m in failed {
+                self.mounts[m].fall_back(observer);
+            }
+        }
+
+        let mut device_groups = FxHashMap::<usize, Vec<&FileExtent>>::default();
+
+        for (path, ino, extents) in dirs {
+            let m = match self.mount_of(path) {
+                Some(v) => v,
+                None => continue,
+            };
+
+            self.mounts[m].report(observer);
+
+            match self.mounts[m].strategy {
+                PrefetchStrategy::DeviceExtents if !extents.is_empty() => {
+                    device_groups.entry(m).or_default().extend(extents);
+                },
+                PrefetchStrategy::BtrfsTreeSearch => {
+                    self.queue(Job::TreeSearch{mount: m, path: path.to_owned(), ino});
+                },
+                // also directories without extents, e.g. inline ones
+                PrefetchStrategy::DeviceExtents | PrefetchStrategy::DirReadahead => {
+                    let size = extents.iter().map(|e| e.length).sum::<u64>().max(4096);
+                    self.queue(Job::Readahead{path: path.to_owned(), size});
+                },
+            }
+        }
+
+        for (m, mut extents) in device_groups {
+            let mount = &mut self.mounts[m];
+
+            if mount.device.is_none() {
+                match File::open(&mount.entry.spec) {
+                    Ok(f) => mount.device = Some(f),
+                    Err(_) => {
+                        // the extents of this batch are lost, the next ones are read ahead through the directories
+                        mount.fall_back(observer);
+                        continue;
+                    }
+                }
+            }
+
+            let device = mount.device.as_ref().unwrap();
+
+            extents.sort_by_key(|e| e.physical);
+
+            let mut i = 0;
+
+            while i < extents.len() {
+                let offset = extents[i].physical;
+                let mut end = offset + extents[i].length;
+
+                // merge adjacent and overlapping extents
+                while i + 1 < extents.len() && extents[i+1].physical <= end {
+                    i += 1;
+                    end = end.max(extents[i].physical + extents[i].length);
+                }
+
+                i += 1;
+
+                unsafe {
+                    libc::posix_fadvise(device.as_raw_fd(), offset as i64, (end - offset) as i64, libc::POSIX_FADV_WILLNEED);
+                }
+            }
+        }
+    }
+}
@@ -1,2 +1,3 @@
 pub mod lzo;
 pub mod zlib;
+pub mod zstd;
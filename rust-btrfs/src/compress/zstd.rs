@@ -0,0 +1,38 @@
+use std::io::ErrorKind;
+use std::io::Read;
+
+// decompression
+
+/// Decompress the data of a zstd compressed extent. Btrfs stores a single zstd frame, followed by
+/// zero padding up to the sector size, and doesn't record the decompressed size in the frame
+/// header, so the expected output size has to be passed.
+pub fn decompress(input: &[u8], output_size: usize) -> Result<Vec<u8>, String> {
+    let mut decoder = zstd::stream::read::Decoder::with_buffer(input)
+        .map_err(|error| format!("ZSTD initialisation failed: {}", error))?
+        .single_frame();
+
+    let mut output = vec![0u8; output_size];
+
+    let mut position = 0;
+
+    while position < output_size {
+        match decoder.read(&mut output[position..]) {
+            Ok(0) => break,
+
+            Ok(read_size) => position += read_size,
+
+            Err(error) if error.kind() == ErrorKind::Interrupted => (),
+
+            Err(error) => return Err(format!("ZSTD decompression failed: {}", error)),
+        }
+    }
+
+    if position != output_size {
+        return Err(format!(
+            "ZSTD decompressed size {} does not match {}",
+            position, output_size
+        ));
+    }
+
+    Ok(output)
+}
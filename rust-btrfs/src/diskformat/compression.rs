@@ -90,7 +90,15 @@ pub fn btrfs_decompress_pages(
             btrfs_decompress(compression_type, &raw_data[8..], logical_size)
         }
 
-        _ => panic!(),
+        // a single frame, not split into pages
+        BTRFS_EXTENT_DATA_ZSTD_COMPRESSION => {
+            btrfs_decompress(compression_type, raw_data, logical_size)
+        }
+
+        _ => Err(format!(
+            "Unrecognised extent data compression {}",
+            compression_type
+        )),
     }
 }
 
@@ -132,9 +140,121 @@ pub fn btrfs_decompress(
             Ok(Cow::Owned(uncompressed_data))
         }
 
+        BTRFS_EXTENT_DATA_ZSTD_COMPRESSION => {
+            crate::compress::zstd::decompress(raw_data, logical_size as usize).map(Cow::Owned)
+        }
+
         _ => panic!(
             "Unrecognised inline extent data compression {}",
             compression_type
         ),
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::Write;
+
+    use super::*;
+
+    fn test_data() -> Vec<u8> {
+        (0..0x5000_u32)
+            .flat_map(|index| (index / 7).to_le_bytes())
+            .collect()
+    }
+
+    // like the kernel, without the decompressed size in the frame header
+
+    fn zstd_frame(data: &[u8]) -> Vec<u8> {
+        let mut encoder = zstd::stream::Encoder::new(Vec::new(), 3).unwrap();
+
+        encoder.include_contentsize(false).unwrap();
+        encoder.write_all(data).unwrap();
+
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_zstd_regular_extent() {
+        let data = test_data();
+
+        let mut raw_data = zstd_frame(&data);
+
+        // padded to the sector size on disk
+
+        raw_data.resize(raw_data.len().div_ceil(0x1000) * 0x1000, 0);
+
+        let uncompressed_data = btrfs_decompress_pages(
+            BTRFS_EXTENT_DATA_ZSTD_COMPRESSION,
+            &raw_data,
+            data.len() as u64,
+        )
+        .unwrap();
+
+        assert!(uncompressed_data.as_ref() == data.as_slice());
+    }
+
+    #[test]
+    fn test_zstd_inline_extent() {
+        let data = b"inline extent data, inline extent data, inline extent data".to_vec();
+
+        let mut header_bytes: Vec<u8> = Vec::new();
+
+        header_bytes.extend_from_slice(&257_u64.to_le_bytes());
+        header_bytes.push(BTRFS_EXTENT_DATA_TYPE);
+        header_bytes.extend_from_slice(&0_u64.to_le_bytes());
+        header_bytes.extend_from_slice(&0_u32.to_le_bytes());
+        header_bytes.extend_from_slice(&0_u32.to_le_bytes());
+
+        let header = BtrfsLeafItemHeader::from_bytes(&header_bytes).unwrap();
+
+        let mut data_bytes: Vec<u8> = Vec::new();
+
+        data_bytes.extend_from_slice(&7_u64.to_le_bytes());
+        data_bytes.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        data_bytes.push(BTRFS_EXTENT_DATA_ZSTD_COMPRESSION);
+        data_bytes.push(0);
+        data_bytes.extend_from_slice(&0_u16.to_le_bytes());
+        data_bytes.push(BTRFS_EXTENT_DATA_INLINE_TYPE);
+        data_bytes.extend_from_slice(&zstd_frame(&data));
+
+        let extent_data = BtrfsExtentData::from_bytes(header, &data_bytes).unwrap();
+
+        assert!(extent_data.inline());
+
+        assert!(extent_data.inline_data().unwrap().unwrap().as_ref() == data.as_slice());
+    }
+
+    #[test]
+    fn test_zstd_corrupt_extent() {
+        let data = test_data();
+
+        let mut raw_data = zstd_frame(&data);
+
+        // truncated frame
+
+        raw_data.truncate(raw_data.len() / 2);
+
+        assert!(btrfs_decompress_pages(
+            BTRFS_EXTENT_DATA_ZSTD_COMPRESSION,
+            &raw_data,
+            data.len() as u64,
+        )
+        .is_err());
+
+        // garbage
+
+        assert!(btrfs_decompress_pages(
+            BTRFS_EXTENT_DATA_ZSTD_COMPRESSION,
+            &[0x55; 0x1000],
+            data.len() as u64,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_unknown_compression() {
+        assert!(btrfs_decompress_pages(0x7f, &[0; 0x1000], 0x1000).is_err());
+    }
+}
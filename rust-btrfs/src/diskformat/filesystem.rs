@@ -302,6 +302,98 @@ impl<'a> BtrfsFilesystem<'a> {
         self.slice_at_physical_address(physical_address, size)
     }
 
+    /// Read the contents of the regular file `inode` in the filesystem tree `tree_id`, passing them
+    /// to `f` in order. Holes and preallocated extents are passed as zeroes.
+    pub fn read_file(
+        &'a self,
+        tree_id: u64,
+        inode: u64,
+        mut f: impl FnMut(&[u8]),
+    ) -> Result<(), String> {
+        let filesystem_tree = self
+            .filesystem_tree(tree_id)
+            .ok_or(format!("Filesystem tree not found: {}", tree_id))?;
+
+        let file_size = filesystem_tree
+            .inode_item(inode)
+            .ok_or(format!("Inode item not found: {}", inode))?
+            .st_size();
+
+        let zeroes = [0_u8; 0x1000];
+
+        let write_zeroes = |f: &mut dyn FnMut(&[u8]), mut length: u64| {
+            while length > 0 {
+                let chunk = length.min(zeroes.len() as u64);
+                f(&zeroes[..chunk as usize]);
+                length -= chunk;
+            }
+        };
+
+        let mut position: u64 = 0;
+
+        for extent_data in filesystem_tree.extent_datas(inode) {
+            if position >= file_size {
+                break;
+            }
+
+            // implicit hole, with the no-holes feature
+
+            if extent_data.offset() > position {
+                let length = (extent_data.offset() - position).min(file_size - position);
+                write_zeroes(&mut f, length);
+                position += length;
+            }
+
+            if extent_data.inline() {
+                let data = extent_data.inline_data()?.unwrap();
+                let length = (data.len() as u64).min(file_size - position);
+
+                f(&data[..length as usize]);
+                position += length;
+
+                continue;
+            }
+
+            let length = extent_data.extent_data_size().min(file_size - position);
+
+            if extent_data.prealloc() || extent_data.extent_logical_address() == 0 {
+                write_zeroes(&mut f, length);
+            } else if extent_data.compression() == BTRFS_EXTENT_DATA_NO_COMPRESSION {
+                f(self.slice_at_logical_address(
+                    extent_data.extent_logical_address() + extent_data.extent_data_offset(),
+                    length as usize,
+                )?);
+            } else {
+                let raw_data = self.slice_at_logical_address(
+                    extent_data.extent_logical_address(),
+                    extent_data.extent_size() as usize,
+                )?;
+
+                let data = btrfs_decompress_pages(
+                    extent_data.compression(),
+                    raw_data,
+                    extent_data.logical_data_size(),
+                )?;
+
+                let start = extent_data.extent_data_offset() as usize;
+
+                f(data.get(start..start + length as usize).ok_or(format!(
+                    "Decompressed extent of inode {} too short: 0x{:x}",
+                    inode,
+                    data.len()
+                ))?);
+            }
+
+            position += length;
+        }
+
+        if position < file_size {
+            write_zeroes(&mut f, file_size - position);
+        }
+
+        Ok(())
+    }
+
     pub fn subvolume_path(
         &'a self,
         root_backref: &'a BtrfsRootBackref<'a>,
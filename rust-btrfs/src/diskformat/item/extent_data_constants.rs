@@ -5,3 +5,4 @@ pub const BTRFS_EXTENT_DATA_PREALLOC_TYPE: u8 = 2;
 pub const BTRFS_EXTENT_DATA_NO_COMPRESSION: u8 = 0;
 pub const BTRFS_EXTENT_DATA_ZLIB_COMPRESSION: u8 = 1;
 pub const BTRFS_EXTENT_DATA_LZO_COMPRESSION: u8 = 2;
+pub const BTRFS_EXTENT_DATA_ZSTD_COMPRESSION: u8 = 3;
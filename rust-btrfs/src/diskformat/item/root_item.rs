@@ -70,6 +70,10 @@ impl<'a> BtrfsRootItem<'a> {
         self.data().flags
     }
 
+    pub fn readonly(&self) -> bool {
+        self.flags() & BTRFS_ROOT_SUBVOL_RDONLY != 0
+    }
+
     pub fn num_references(&self) -> u32 {
         self.data().num_references
     }
@@ -90,6 +90,12 @@ pub const BTRFS_DEV_TREE_OBJECT_ID: u64 = 4;
 pub const BTRFS_FS_TREE_OBJECT_ID: u64 = 5;
 pub const BTRFS_DEFAULT_TREE_OBJECT_ID: u64 = 6;
 
+/// The root directory inode of every filesystem tree
+pub const BTRFS_FIRST_FREE_OBJECT_ID: u64 = 256;
+
+/// Root item flag of read-only subvolumes, e.g. snapshots
+pub const BTRFS_ROOT_SUBVOL_RDONLY: u64 = 1;
+
 pub const BTRFS_ROOT_TREE_ID: BtrfsTreeId = BtrfsTreeId(1);
 pub const BTRFS_EXTENT_TREE_ID: BtrfsTreeId = BtrfsTreeId(2);
 pub const BTRFS_CHUNK_TREE_ID: BtrfsTreeId = BtrfsTreeId(3);
@@ -37,6 +37,7 @@ extern crate libc;
 extern crate memmap;
 extern crate minilzo;
 extern crate uuid;
+extern crate zstd;
 
 pub mod compress;
 pub mod diskformat;
@@ -39,3 +39,17 @@ pub const DEFRAG_RANGE_START_IO: u64 = 2;
 
 pub const FILE_DEDUPE_RANGE_SAME: i32 = 0;
 pub const FILE_DEDUPE_RANGE_DIFFERS: i32 = 1;
+
+pub const SEARCH_ARGS_BUFSIZE: usize = 4096 - 104;
+pub const INO_LOOKUP_PATH_MAX: usize = 4080;
+
+pub const SUBVOL_RDONLY: u64 = 1 << 1;
+
+pub const ROOT_TREE_OBJECTID: u64 = 1;
+pub const FIRST_FREE_OBJECTID: u64 = 256;
+pub const LAST_FREE_OBJECTID: u64 = -256i64 as u64;
+
+pub const INODE_ITEM_KEY: u32 = 1;
+pub const DIR_INDEX_KEY: u32 = 96;
+pub const ROOT_ITEM_KEY: u32 = 132;
+pub const ROOT_REF_KEY: u32 = 156;
@@ -0,0 +1,8 @@
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct IoctlFileCloneRange {
+    pub src_fd: i64,
+    pub src_offset: u64,
+    pub src_length: u64,
+    pub dest_offset: u64,
+}
@@ -0,0 +1,19 @@
+use crate::linux::ctypes::ioctl_constants::*;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct IoctlInoLookupArgs {
+    pub tree_id: u64,
+    pub objectid: u64,
+    pub name: [u8; INO_LOOKUP_PATH_MAX],
+}
+
+impl IoctlInoLookupArgs {
+    pub fn new() -> IoctlInoLookupArgs {
+        IoctlInoLookupArgs {
+            tree_id: 0,
+            objectid: 0,
+            name: [0u8; INO_LOOKUP_PATH_MAX],
+        }
+    }
+}
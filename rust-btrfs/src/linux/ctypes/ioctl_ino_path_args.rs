@@ -0,0 +1,19 @@
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct IoctlInoPathArgs {
+    pub inum: u64,
+    pub size: u64,
+    pub reserved: [u64; 4],
+    pub fspath: u64,
+}
+
+/// Header of the buffer pointed to by `IoctlInoPathArgs::fspath`, followed
+/// by `elem_cnt` u64 offsets of the paths relative to the end of the header
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct IoctlDataContainer {
+    pub bytes_left: u32,
+    pub bytes_missing: u32,
+    pub elem_cnt: u32,
+    pub elem_missed: u32,
+}
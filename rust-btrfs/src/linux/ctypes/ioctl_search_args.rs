@@ -0,0 +1,63 @@
+use crate::linux::ctypes::ioctl_constants::*;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct IoctlSearchKey {
+    pub tree_id: u64,
+    pub min_objectid: u64,
+    pub max_objectid: u64,
+    pub min_offset: u64,
+    pub max_offset: u64,
+    pub min_transid: u64,
+    pub max_transid: u64,
+    pub min_type: u32,
+    pub max_type: u32,
+    pub nr_items: u32,
+    pub unused: u32,
+    pub unused1: u64,
+    pub unused2: u64,
+    pub unused3: u64,
+    pub unused4: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct IoctlSearchArgs {
+    pub key: IoctlSearchKey,
+    pub buf: [u8; SEARCH_ARGS_BUFSIZE],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct IoctlSearchHeader {
+    pub transid: u64,
+    pub objectid: u64,
+    pub offset: u64,
+    pub item_type: u32,
+    pub len: u32,
+}
+
+impl IoctlSearchArgs {
+    pub fn new() -> IoctlSearchArgs {
+        IoctlSearchArgs {
+            key: IoctlSearchKey {
+                tree_id: 0,
+                min_objectid: 0,
+                max_objectid: 0,
+                min_offset: 0,
+                max_offset: 0,
+                min_transid: 0,
+                max_transid: 0,
+                min_type: 0,
+                max_type: 0,
+                nr_items: 0,
+                unused: 0,
+                unused1: 0,
+                unused2: 0,
+                unused3: 0,
+                unused4: 0,
+            },
+            buf: [0u8; SEARCH_ARGS_BUFSIZE],
+        }
+    }
+}
@@ -4,9 +4,13 @@ mod ioctl_dev_info_args;
 mod ioctl_device_path;
 mod ioctl_fiemap;
 mod ioctl_fiemap_extent;
+mod ioctl_file_clone_range;
 mod ioctl_file_dedupe_range;
 mod ioctl_file_dedupe_range_info;
 mod ioctl_fs_info_args;
+mod ioctl_ino_lookup_args;
+mod ioctl_ino_path_args;
+mod ioctl_search_args;
 mod ioctl_space_args;
 mod ioctl_space_info;
 
@@ -16,8 +20,12 @@ pub use self::ioctl_dev_info_args::*;
 pub use self::ioctl_device_path::*;
 pub use self::ioctl_fiemap::*;
 pub use self::ioctl_fiemap_extent::*;
+pub use self::ioctl_file_clone_range::*;
 pub use self::ioctl_file_dedupe_range::*;
 pub use self::ioctl_file_dedupe_range_info::*;
 pub use self::ioctl_fs_info_args::*;
+pub use self::ioctl_ino_lookup_args::*;
+pub use self::ioctl_ino_path_args::*;
+pub use self::ioctl_search_args::*;
 pub use self::ioctl_space_args::*;
 pub use self::ioctl_space_info::*;
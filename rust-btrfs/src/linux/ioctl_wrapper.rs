@@ -12,6 +12,10 @@ ioctl_readwrite! (
 	ioctl_dev_info, BTRFS_IOCTL_MAGIC, 30, IoctlDevInfoArgs
 );
 
+ioctl_write_ptr! (
+	ioctl_file_clone_range, BTRFS_IOCTL_MAGIC, 13, IoctlFileCloneRange
+);
+
 ioctl_readwrite! (
 	ioctl_file_dedupe_range, BTRFS_IOCTL_MAGIC, 54, IoctlFileDedupeRange
 );
@@ -20,6 +24,22 @@ ioctl_read! (
 	ioctl_fs_info, BTRFS_IOCTL_MAGIC, 31, IoctlFsInfoArgs
 );
 
+ioctl_readwrite! (
+	ioctl_tree_search, BTRFS_IOCTL_MAGIC, 17, IoctlSearchArgs
+);
+
+ioctl_readwrite! (
+	ioctl_ino_lookup, BTRFS_IOCTL_MAGIC, 18, IoctlInoLookupArgs
+);
+
+ioctl_readwrite! (
+	ioctl_ino_paths, BTRFS_IOCTL_MAGIC, 35, IoctlInoPathArgs
+);
+
+ioctl_read! (
+	ioctl_subvol_getflags, BTRFS_IOCTL_MAGIC, 25, u64
+);
+
 ioctl_readwrite! (
 	ioctl_space_info, BTRFS_IOCTL_MAGIC, 20, IoctlSpaceArgs
 );
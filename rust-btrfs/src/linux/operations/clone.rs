@@ -0,0 +1,39 @@
+//! This module contains an interface to the kernel's reflink functionality.
+//!
+//! Unlike `deduplicate_range`, the kernel does not compare the contents of the
+//! ranges before sharing them, so the caller has to make sure that they are
+//! identical. It is supported by some filesystems which don't implement the
+//! deduplicate ioctl, e.g. NFS 4.2.
+
+use crate::linux::imports::*;
+
+/// This function maps directly onto the kernel's clone range functionality.
+/// A `src_length` of zero clones until the end of the source file.
+pub fn clone_range(
+    src_file_descriptor: libc::c_int,
+    src_offset: u64,
+    src_length: u64,
+    dest_file_descriptor: libc::c_int,
+    dest_offset: u64,
+) -> Result<(), String> {
+    let c_clone_range = IoctlFileCloneRange {
+        src_fd: src_file_descriptor as i64,
+        src_offset,
+        src_length,
+        dest_offset,
+    };
+
+    // perform ioctl
+
+    unsafe {
+        ioctl_file_clone_range(
+            dest_file_descriptor,
+            &c_clone_range as *const IoctlFileCloneRange,
+        )
+    }
+    .map_err(|error| format!("Clone ioctl returned {}", error))?;
+
+    // return
+
+    Ok(())
+}
@@ -7,6 +7,14 @@ pub struct FileExtent {
     pub logical: u64,
     pub physical: u64,
     pub length: u64,
+    pub flags: u32,
+}
+
+impl FileExtent {
+    /// The extent data is compressed or otherwise encoded, so its size on disk may differ from `length`
+    pub fn is_encoded(&self) -> bool {
+        self.flags & FIEMAP_EXTENT_ENCODED != 0
+    }
 }
 
 pub fn get_file_extent_map_for_path<PathRef: AsRef<Path>>(
@@ -65,6 +73,7 @@ pub fn get_file_extent_map(file_descriptor: libc::c_int) -> Result<Vec<FileExten
                 logical: c_file_extent.logical,
                 physical: c_file_extent.physical,
                 length: c_file_extent.length,
+                flags: c_file_extent.flags,
             })
             .collect(),
     )
@@ -88,6 +97,7 @@ pub fn get_file_extent_map_noloop(file_descriptor: libc::c_int) -> Result<Vec<Fi
                 logical: c_file_extent.logical,
                 physical: c_file_extent.physical,
                 length: c_file_extent.length,
+                flags: c_file_extent.flags,
             })
             .collect(),
     )
@@ -158,4 +168,5 @@ fn get_c_file_extent_map(
 
 //const FIEMAP_FLAG_SYNC: u32 = 0x00000001;
 
-const FIEMAP_EXTENT_LAST: u32 = 0x00000001;
+pub const FIEMAP_EXTENT_LAST: u32 = 0x00000001;
+pub const FIEMAP_EXTENT_ENCODED: u32 = 0x00000008;
@@ -1,11 +1,17 @@
+mod clone;
 mod deduplicate;
 mod defragment;
 mod fiemap;
 mod filesystem_info;
 mod space_info;
+mod subvolume;
+mod tree_search;
 
+pub use self::clone::*;
 pub use self::deduplicate::*;
 pub use self::defragment::*;
 pub use self::fiemap::*;
 pub use self::filesystem_info::*;
 pub use self::space_info::*;
+pub use self::subvolume::*;
+pub use self::tree_search::*;
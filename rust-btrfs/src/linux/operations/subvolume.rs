@@ -0,0 +1,22 @@
+//! This module contains an interface to the kernel's subvolume flags
+//! functionality.
+//!
+//! Unlike the tree search, this doesn't need any privileges, so it can be used
+//! to tell read-only snapshots apart while walking a filesystem.
+
+use crate::linux::imports::*;
+
+pub fn get_subvolume_flags(file_descriptor: libc::c_int) -> Result<u64, String> {
+    let mut flags: u64 = 0;
+
+    unsafe { ioctl_subvol_getflags(file_descriptor, &mut flags as *mut u64) }
+        .map_err(|error| format!("Error getting subvolume flags: {}", error))?;
+
+    Ok(flags)
+}
+
+/// The subvolume of the file descriptor is read-only, e.g. a snapshot taken
+/// with `btrfs subvolume snapshot -r`
+pub fn is_subvolume_readonly(file_descriptor: libc::c_int) -> Result<bool, String> {
+    Ok(get_subvolume_flags(file_descriptor)? & SUBVOL_RDONLY != 0)
+}
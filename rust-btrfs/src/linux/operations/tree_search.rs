@@ -0,0 +1,357 @@
+//! This module contains an interface to the kernel's tree search and inode
+//! lookup functionality.
+//!
+//! Every item in the btrfs trees carries the transaction id of the tree block
+//! it was last written in, so searching with a minimum transaction id is a
+//! cheap way to find the inodes changed since a previous generation, like
+//! `btrfs subvolume find-new` does. All of these ioctls need CAP_SYS_ADMIN.
+
+use crate::linux::imports::*;
+use std::os::unix::ffi::OsStrExt;
+
+// --------- high level wrapper
+
+/// An item found by `tree_search`, in the order of its key
+#[derive(Debug, Eq, PartialEq)]
+pub struct TreeSearchItem<'a> {
+    pub transid: u64,
+    pub objectid: u64,
+    pub item_type: u32,
+    pub offset: u64,
+    pub data: &'a [u8],
+}
+
+/// An inode whose inode item was modified since the searched generation
+#[derive(Debug, Eq, PartialEq)]
+pub struct ChangedInode {
+    pub inode: u64,
+    pub transid: u64,
+    pub mode: u32,
+    pub size: u64,
+}
+
+impl ChangedInode {
+    pub fn is_file(&self) -> bool {
+        self.mode & libc::S_IFMT == libc::S_IFREG
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & libc::S_IFMT == libc::S_IFDIR
+    }
+}
+
+/// A subvolume placed inside another one
+#[derive(Debug, Eq, PartialEq)]
+pub struct SubvolumeRef {
+    pub root_id: u64,
+    /// inode of the directory in the parent subvolume containing it
+    pub dir_id: u64,
+    pub name: OsString,
+}
+
+/// Call `f` for every item of the tree with a key between `min_key` and
+/// `max_key` (objectid, type, offset) in tree blocks written since
+/// `min_transid`. A `tree_id` of zero searches the subvolume of the file
+/// descriptor.
+pub fn tree_search(
+    file_descriptor: libc::c_int,
+    tree_id: u64,
+    min_key: (u64, u32, u64),
+    max_key: (u64, u32, u64),
+    min_transid: u64,
+    mut f: impl FnMut(TreeSearchItem),
+) -> Result<(), String> {
+    let mut c_search_args = Box::new(IoctlSearchArgs::new());
+
+    c_search_args.key.tree_id = tree_id;
+    (
+        c_search_args.key.min_objectid,
+        c_search_args.key.min_type,
+        c_search_args.key.min_offset,
+    ) = min_key;
+    (
+        c_search_args.key.max_objectid,
+        c_search_args.key.max_type,
+        c_search_args.key.max_offset,
+    ) = max_key;
+    c_search_args.key.min_transid = min_transid;
+    c_search_args.key.max_transid = u64::MAX;
+
+    loop {
+        c_search_args.key.nr_items = u32::MAX;
+
+        unsafe { ioctl_tree_search(file_descriptor, &mut *c_search_args as *mut IoctlSearchArgs) }
+            .map_err(|error| format!("Error searching btrfs tree: {}", error))?;
+
+        let nr_items = c_search_args.key.nr_items;
+
+        if nr_items == 0 {
+            break;
+        }
+
+        let mut off = 0;
+        let mut last_key = min_key;
+
+        for _ in 0..nr_items {
+            let header: IoctlSearchHeader = unsafe {
+                std::ptr::read_unaligned(
+                    c_search_args.buf[off..].as_ptr() as *const IoctlSearchHeader
+                )
+            };
+            off += mem::size_of::<IoctlSearchHeader>();
+
+            let data = &c_search_args.buf[off..off + header.len as usize];
+            off += header.len as usize;
+
+            f(TreeSearchItem {
+                transid: header.transid,
+                objectid: header.objectid,
+                item_type: header.item_type,
+                offset: header.offset,
+                data,
+            });
+
+            last_key = (header.objectid, header.item_type, header.offset);
+        }
+
+        // continue after the last found key
+
+        let next_key = match last_key {
+            (o, t, off) if off < u64::MAX => (o, t, off + 1),
+            (o, t, _) if t < u8::MAX as u32 => (o, t + 1, 0),
+            (o, _, _) if o < u64::MAX => (o + 1, 0, 0),
+            _ => break,
+        };
+
+        if next_key > max_key {
+            break;
+        }
+
+        (
+            c_search_args.key.min_objectid,
+            c_search_args.key.min_type,
+            c_search_args.key.min_offset,
+        ) = next_key;
+    }
+
+    Ok(())
+}
+
+/// Get the id of the subvolume containing the file descriptor
+pub fn get_root_id(file_descriptor: libc::c_int) -> Result<u64, String> {
+    let mut c_ino_lookup_args = Box::new(IoctlInoLookupArgs::new());
+
+    c_ino_lookup_args.objectid = FIRST_FREE_OBJECTID;
+
+    unsafe {
+        ioctl_ino_lookup(
+            file_descriptor,
+            &mut *c_ino_lookup_args as *mut IoctlInoLookupArgs,
+        )
+    }
+    .map_err(|error| format!("Error looking up btrfs subvolume: {}", error))?;
+
+    Ok(c_ino_lookup_args.tree_id)
+}
+
+/// Get the path of a directory inode relative to the root of its
+/// subvolume. A `tree_id` of zero means the subvolume of the file descriptor.
+pub fn get_inode_dir_path(
+    file_descriptor: libc::c_int,
+    tree_id: u64,
+    inode: u64,
+) -> Result<OsString, String> {
+    let mut c_ino_lookup_args = Box::new(IoctlInoLookupArgs::new());
+
+    c_ino_lookup_args.tree_id = tree_id;
+    c_ino_lookup_args.objectid = inode;
+
+    unsafe {
+        ioctl_ino_lookup(
+            file_descriptor,
+            &mut *c_ino_lookup_args as *mut IoctlInoLookupArgs,
+        )
+    }
+    .map_err(|error| format!("Error looking up btrfs inode path: {}", error))?;
+
+    let name = &c_ino_lookup_args.name;
+    let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+
+    // the path ends with a slash, except for the root of the subvolume
+
+    let path = &name[..len];
+    let path = path.strip_suffix(b"/").unwrap_or(path);
+
+    Ok(OsString::from_vec(path.to_vec()))
+}
+
+/// Get all paths (hard links) of an inode in the subvolume of the file
+/// descriptor, relative to the root of the subvolume
+pub fn get_inode_paths(file_descriptor: libc::c_int, inode: u64) -> Result<Vec<OsString>, String> {
+    const BUF_SIZE: usize = 65536;
+
+    let mut c_data_container: Vec<u64> = vec![0u64; BUF_SIZE / 8];
+
+    let mut c_ino_path_args = IoctlInoPathArgs {
+        inum: inode,
+        size: BUF_SIZE as u64,
+        reserved: [0; 4],
+        fspath: c_data_container.as_mut_ptr() as u64,
+    };
+
+    unsafe {
+        ioctl_ino_paths(
+            file_descriptor,
+            &mut c_ino_path_args as *mut IoctlInoPathArgs,
+        )
+    }
+    .map_err(|error| format!("Error getting btrfs inode paths: {}", error))?;
+
+    let header: IoctlDataContainer =
+        unsafe { std::ptr::read(c_data_container.as_ptr() as *const IoctlDataContainer) };
+
+    let values = &c_data_container[mem::size_of::<IoctlDataContainer>() / 8..];
+
+    let bytes: &[u8] =
+        unsafe { slice::from_raw_parts(values.as_ptr() as *const u8, values.len() * 8) };
+
+    let mut paths = Vec::with_capacity(header.elem_cnt as usize);
+
+    for &offset in &values[..header.elem_cnt as usize] {
+        let path = &bytes[offset as usize..];
+        let len = path.iter().position(|&b| b == 0).unwrap_or(path.len());
+        paths.push(std::ffi::OsStr::from_bytes(&path[..len]).to_owned());
+    }
+
+    Ok(paths)
+}
+
+/// Get the generation (last committed transaction id) of a subvolume
+pub fn get_root_generation(file_descriptor: libc::c_int, root_id: u64) -> Result<u64, String> {
+    // the generation follows the inode item in the root item
+
+    const GENERATION_OFFSET: usize = 160;
+
+    let mut generation = None;
+
+    tree_search(
+        file_descriptor,
+        ROOT_TREE_OBJECTID,
+        (root_id, ROOT_ITEM_KEY, 0),
+        (root_id, ROOT_ITEM_KEY, u64::MAX),
+        0,
+        |item| {
+            if item.item_type == ROOT_ITEM_KEY && item.data.len() >= GENERATION_OFFSET + 8 {
+                generation = Some(read_u64(item.data, GENERATION_OFFSET));
+            }
+        },
+    )?;
+
+    generation.ok_or_else(|| format!("Root item of btrfs subvolume {} not found", root_id))
+}
+
+/// Find the inodes of the subvolume of the file descriptor which were
+/// modified in or after the transaction `min_transid`
+pub fn find_changed_inodes(
+    file_descriptor: libc::c_int,
+    min_transid: u64,
+) -> Result<Vec<ChangedInode>, String> {
+    // offsets in the inode item
+
+    const TRANSID_OFFSET: usize = 8;
+    const SIZE_OFFSET: usize = 16;
+    const MODE_OFFSET: usize = 52;
+
+    let mut changed = Vec::new();
+
+    tree_search(
+        file_descriptor,
+        0,
+        (FIRST_FREE_OBJECTID, INODE_ITEM_KEY, 0),
+        (LAST_FREE_OBJECTID, u8::MAX as u32, u64::MAX),
+        min_transid,
+        |item| {
+            if item.item_type == INODE_ITEM_KEY && item.data.len() >= MODE_OFFSET + 4 {
+                let transid = read_u64(item.data, TRANSID_OFFSET);
+
+                // the tree block may be newer than the inode item
+
+                if transid >= min_transid {
+                    changed.push(ChangedInode {
+                        inode: item.objectid,
+                        transid,
+                        mode: read_u32(item.data, MODE_OFFSET),
+                        size: read_u64(item.data, SIZE_OFFSET),
+                    });
+                }
+            }
+        },
+    )?;
+
+    Ok(changed)
+}
+
+/// Search the directory index items of a directory inode in the subvolume
+/// of the file descriptor, which reads the tree blocks holding them into the
+/// page cache. Returns the number of entries.
+pub fn prefetch_dir_items(file_descriptor: libc::c_int, dir_inode: u64) -> Result<usize, String> {
+    let mut entries = 0;
+
+    tree_search(
+        file_descriptor,
+        0,
+        (dir_inode, DIR_INDEX_KEY, 0),
+        (dir_inode, DIR_INDEX_KEY, u64::MAX),
+        0,
+        |item| {
+            if item.item_type == DIR_INDEX_KEY {
+                entries += 1;
+            }
+        },
+    )?;
+
+    Ok(entries)
+}
+
+/// List the subvolumes placed directly inside the subvolume `root_id`
+pub fn get_child_subvolumes(
+    file_descriptor: libc::c_int,
+    root_id: u64,
+) -> Result<Vec<SubvolumeRef>, String> {
+    // dirid: u64, sequence: u64, name_len: u16, name
+
+    const NAME_OFFSET: usize = 18;
+
+    let mut children = Vec::new();
+
+    tree_search(
+        file_descriptor,
+        ROOT_TREE_OBJECTID,
+        (root_id, ROOT_REF_KEY, 0),
+        (root_id, ROOT_REF_KEY, u64::MAX),
+        0,
+        |item| {
+            if item.item_type == ROOT_REF_KEY && item.data.len() >= NAME_OFFSET {
+                let name_len = u16::from_le_bytes([item.data[16], item.data[17]]) as usize;
+
+                if let Some(name) = item.data.get(NAME_OFFSET..NAME_OFFSET + name_len) {
+                    children.push(SubvolumeRef {
+                        root_id: item.offset,
+                        dir_id: read_u64(item.data, 0),
+                        name: OsString::from_vec(name.to_vec()),
+                    });
+                }
+            }
+        },
+    )?;
+
+    Ok(children)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}